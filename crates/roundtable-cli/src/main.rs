@@ -0,0 +1,437 @@
+//! Headless Roundtable client for scripts and build servers.
+//!
+//! Speaks the same UDP protocol as the desktop app, so peers see it as a regular user.
+
+use clap::{Parser, Subcommand};
+use roundtable_core::net;
+use roundtable_core::{
+    DeliveryStatus, EngineConfig, EngineEvent, OfferTarget, OutgoingFile, OutgoingMessage, RoundtableEngine, User,
+    DEFAULT_TRANSFER_STREAMS, DISCOVERY_PORT, MSG_PORT,
+};
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast::error::RecvError;
+
+const PRESENCE_INTERVAL_SECS: u64 = 30;
+
+#[derive(Parser)]
+#[command(name = "roundtable-cli", version, about = "Send Roundtable LAN messages and files from the command line")]
+struct Cli {
+    /// Display name shown to peers (defaults to the hostname)
+    #[arg(long, global = true)]
+    name: Option<String>,
+
+    /// User ID to announce (defaults to one derived from the name and hostname)
+    #[arg(long, global = true)]
+    id: Option<u64>,
+
+    #[arg(long, global = true, default_value_t = DISCOVERY_PORT)]
+    discovery_port: u16,
+
+    #[arg(long, global = true, default_value_t = MSG_PORT)]
+    message_port: u16,
+
+    /// Refuse to exchange unencrypted messages and files with older peers
+    #[arg(long, global = true)]
+    require_encryption: bool,
+
+    /// Directory holding the identity key and pinned peer keys
+    #[arg(long, global = true)]
+    data_dir: Option<PathBuf>,
+
+    /// Most parallel connections a large file is sent or received over
+    #[arg(long, global = true, default_value_t = DEFAULT_TRANSFER_STREAMS)]
+    streams: u8,
+
+    /// Cap on transfer bandwidth, in KiB per second
+    #[arg(long, global = true, value_name = "KIB")]
+    rate_limit: Option<u64>,
+
+    /// Compress file streams to peers that support it
+    #[arg(long, global = true)]
+    compress: bool,
+
+    /// Routers discovery multicast may cross, for peers on other VLANs
+    #[arg(long, global = true, default_value_t = 1, value_name = "HOPS")]
+    multicast_ttl: u32,
+
+    /// Only discover and be discovered on this interface; repeat for more
+    #[arg(long = "interface", global = true, value_name = "NAME")]
+    interfaces: Vec<String>,
+
+    /// Seconds to wait for peers to answer a discovery query
+    #[arg(long, global = true, default_value_t = 3)]
+    wait: u64,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List peers currently online
+    Peers {
+        /// Print one JSON object per peer instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+    /// Send a text message; reads stdin when TEXT is omitted
+    Send {
+        /// Peer ID, name, username or hostname
+        user: String,
+        text: Option<String>,
+    },
+    /// Broadcast a notice to everyone on the subnet; reads stdin when TEXT is omitted
+    Announce {
+        text: Option<String>,
+        /// Ask receivers to show it prominently
+        #[arg(long)]
+        urgent: bool,
+    },
+    /// Offer a file or folder and serve it once the peer accepts
+    SendFile {
+        /// Peer ID, name, username or hostname
+        user: String,
+        path: PathBuf,
+        /// Also offer a file to this peer; repeat for more. One transfer serves them all
+        #[arg(long, value_name = "USER")]
+        also: Vec<String>,
+        /// Seconds to wait for the peer to accept and finish downloading
+        #[arg(long, default_value_t = 300)]
+        timeout: u64,
+    },
+    /// Stay online and print every incoming event as a JSON line
+    Listen,
+    /// List network interfaces and their addresses, marking those discovery uses
+    Interfaces,
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+
+    let cli = Cli::parse();
+    match run(cli).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("roundtable-cli: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(cli: Cli) -> Result<(), String> {
+    let defaults = EngineConfig::default();
+    let config = EngineConfig {
+        discovery_port: cli.discovery_port,
+        message_port: cli.message_port,
+        require_encryption: cli.require_encryption,
+        data_dir: cli.data_dir.unwrap_or(defaults.data_dir),
+        transfer_streams: cli.streams,
+        max_active_transfers: defaults.max_active_transfers,
+        rate_limit: cli.rate_limit.map(|kib| kib * 1024),
+        compress_transfers: cli.compress,
+        multicast_ttl: cli.multicast_ttl,
+        interfaces: cli.interfaces,
+    };
+    let engine = RoundtableEngine::start(config).await.map_err(|e| e.to_string())?;
+
+    let host = hostname::get().ok().and_then(|s| s.into_string().ok()).unwrap_or_else(|| "roundtable-cli".to_string());
+    let name = cli.name.unwrap_or_else(|| host.clone());
+    let id = cli.id.unwrap_or_else(|| default_user_id(&name, &host));
+    let me = engine.local_user(id, name.clone(), name, None);
+    let wait = Duration::from_secs(cli.wait);
+
+    match cli.command {
+        Command::Peers { json } => {
+            let peers = discover_peers(&engine, &me, wait).await?;
+            for peer in peers.values() {
+                if json {
+                    println!("{}", serde_json::to_string(peer).map_err(|e| e.to_string())?);
+                } else {
+                    // Brackets IPv6 addresses, as in [fe80::1%3]:2427
+                    let address = net::peer_addr(&peer.ip, peer.port)
+                        .map_or_else(|_| format!("{}:{}", peer.ip, peer.port), |addr| addr.to_string());
+                    println!(
+                        "{:<20} {:<24} {:<20} {}",
+                        peer.id,
+                        peer.name,
+                        peer.hostname.as_deref().unwrap_or("-"),
+                        address
+                    );
+                }
+            }
+            Ok(())
+        }
+        Command::Interfaces => {
+            for interface in engine.network_interfaces() {
+                for (i, address) in interface.addresses.iter().enumerate() {
+                    // Name and selection only on an interface's first line
+                    let (marker, name) = match i {
+                        0 if interface.selected => ("*", interface.name.as_str()),
+                        0 => (" ", interface.name.as_str()),
+                        _ => (" ", ""),
+                    };
+                    let line = format!(
+                        "{} {:<16} {:<40} {}",
+                        marker,
+                        name,
+                        format!("{}/{}", address.ip, address.prefix_len),
+                        address.broadcast.as_deref().map_or_else(String::new, |broadcast| format!("broadcast {}", broadcast))
+                    );
+                    println!("{}", line.trim_end());
+                }
+            }
+            Ok(())
+        }
+        Command::Send { user, text } => {
+            let content = text_or_stdin(text)?;
+            let peer = resolve_peer(&discover_peers(&engine, &me, wait).await?, &user)?;
+
+            let mut events = engine.subscribe();
+            let message_id = engine
+                .send_message(OutgoingMessage {
+                    content,
+                    target_ip: peer.ip.clone(),
+                    target_port: peer.port,
+                    sender_name: me.name.clone(),
+                    sender_id: me.id,
+                    target_id: peer.id,
+                    sender_port: me.port,
+                })
+                .await
+                .map_err(|e| e.to_string())?;
+
+            loop {
+                match events.recv().await {
+                    Ok(EngineEvent::MessageDeliveryStatus { message_id: id, status, .. }) if id == message_id => {
+                        match status {
+                            DeliveryStatus::Pending => {}
+                            DeliveryStatus::Delivered => {
+                                eprintln!("Delivered to {} ({})", peer.name, peer.ip);
+                                return Ok(());
+                            }
+                            DeliveryStatus::Failed => {
+                                return Err(format!("{} ({}) did not acknowledge the message", peer.name, peer.ip));
+                            }
+                        }
+                    }
+                    Ok(_) | Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => return Err("engine stopped".to_string()),
+                }
+            }
+        }
+        Command::Announce { text, urgent } => {
+            let content = text_or_stdin(text)?;
+            engine
+                .send_announcement(me.id, me.name.clone(), content, urgent)
+                .await
+                .map_err(|e| e.to_string())?;
+            eprintln!("Announced to the subnet");
+            Ok(())
+        }
+        Command::SendFile { user, path, also, timeout } => {
+            let metadata = std::fs::metadata(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            let file_name = path
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .ok_or_else(|| format!("{} has no file name", path.display()))?;
+            // Discovered once for every recipient, as each round waits the full time
+            let online = discover_peers(&engine, &me, wait).await?;
+            let peer = resolve_peer(&online, &user)?;
+
+            let transfer_id = format!("{}-{}", me.id, unix_millis());
+            let mut events = engine.subscribe();
+            let mut peers = BTreeMap::new();
+            peers.insert(peer.id, peer.name.clone());
+            let offered = if !also.is_empty() {
+                if metadata.is_dir() {
+                    return Err("a folder can only be sent to one peer at a time".to_string());
+                }
+                let mut targets = vec![OfferTarget { id: peer.id, ip: peer.ip.clone(), port: peer.port }];
+                for user in &also {
+                    let other = resolve_peer(&online, user)?;
+                    peers.insert(other.id, other.name.clone());
+                    targets.push(OfferTarget { id: other.id, ip: other.ip, port: other.port });
+                }
+                let file = OutgoingFile {
+                    transfer_id: transfer_id.clone(),
+                    file_name: file_name.clone(),
+                    file_size: metadata.len(),
+                    path,
+                };
+                engine.offer_file_to_many(me.clone(), targets, file).await
+            } else if metadata.is_dir() {
+                engine
+                    .offer_files(me.clone(), peer.id, &peer.ip, peer.port, transfer_id.clone(), file_name.clone(), vec![path])
+                    .await
+            } else {
+                let file = OutgoingFile {
+                    transfer_id: transfer_id.clone(),
+                    file_name: file_name.clone(),
+                    file_size: metadata.len(),
+                    path,
+                };
+                engine.offer_file(me.clone(), peer.id, &peer.ip, peer.port, file).await
+            };
+            offered.map_err(|e| e.to_string())?;
+            let names: Vec<&str> = peers.values().map(String::as_str).collect();
+            eprintln!("Offered {} to {}, waiting for them to accept...", file_name, names.join(", "));
+
+            let outcome = tokio::time::timeout(Duration::from_secs(timeout), async {
+                loop {
+                    match events.recv().await {
+                        Ok(EngineEvent::FileTransferAccepted { transfer_id: id, .. }) if id == transfer_id && peers.len() == 1 => {
+                            eprintln!("Accepted, sending...");
+                        }
+                        Ok(EngineEvent::FileTransferRecipient { transfer_id: id, recipient }) if id == transfer_id && peers.len() > 1 => {
+                            let name = peers.get(&recipient.user_id).map_or("?", String::as_str);
+                            match recipient.error {
+                                Some(error) => eprintln!("{}: {} ({})", name, recipient.state.as_str(), error),
+                                None => eprintln!("{}: {}", name, recipient.state.as_str()),
+                            }
+                        }
+                        Ok(EngineEvent::FileTransferSent { transfer_id: id, size }) if id == transfer_id => {
+                            return Ok(size);
+                        }
+                        Ok(EngineEvent::FileTransferError { transfer_id: id, error }) if id == transfer_id => {
+                            return Err(error);
+                        }
+                        Ok(_) | Err(RecvError::Lagged(_)) => {}
+                        Err(RecvError::Closed) => return Err("engine stopped".to_string()),
+                    }
+                }
+            })
+            .await
+            .map_err(|_| "timed out waiting for the transfer".to_string())?;
+
+            let size = outcome?;
+            if peers.len() == 1 {
+                eprintln!("Sent {} ({} bytes) to {}", file_name, size, peer.name);
+            } else {
+                eprintln!("Sent {} ({} bytes)", file_name, size);
+            }
+            Ok(())
+        }
+        Command::Listen => listen(&engine, &me).await,
+    }
+}
+
+/// Announces us, answers discovery queries and prints events until Ctrl-C.
+async fn listen(engine: &RoundtableEngine, me: &User) -> Result<(), String> {
+    let mut events = engine.subscribe();
+    engine.broadcast_presence(me.clone()).await.map_err(|e| e.to_string())?;
+
+    let mut presence = tokio::time::interval(Duration::from_secs(PRESENCE_INTERVAL_SECS));
+    let stdout = std::io::stdout();
+
+    loop {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => break,
+            _ = presence.tick() => {
+                if let Err(e) = engine.broadcast_presence(me.clone()).await {
+                    eprintln!("Failed to broadcast presence: {}", e);
+                }
+            }
+            event = events.recv() => match event {
+                Ok(event) => {
+                    if let EngineEvent::DiscoveryQuery = event {
+                        if let Err(e) = engine.broadcast_presence(me.clone()).await {
+                            eprintln!("Failed to answer discovery query: {}", e);
+                        }
+                    }
+                    let line = serde_json::json!({ "event": event.name(), "payload": event });
+                    let mut out = stdout.lock();
+                    writeln!(out, "{}", line).map_err(|e| e.to_string())?;
+                    out.flush().map_err(|e| e.to_string())?;
+                }
+                Err(RecvError::Lagged(skipped)) => eprintln!("Dropped {} events", skipped),
+                Err(RecvError::Closed) => break,
+            },
+        }
+    }
+
+    engine.broadcast_offline(me.id).await.map_err(|e| e.to_string())
+}
+
+async fn discover_peers(
+    engine: &RoundtableEngine,
+    me: &User,
+    wait: Duration,
+) -> Result<BTreeMap<u64, User>, String> {
+    let mut events = engine.subscribe();
+    engine.broadcast_discovery_query().await.map_err(|e| e.to_string())?;
+    engine.broadcast_presence(me.clone()).await.map_err(|e| e.to_string())?;
+
+    let mut peers = BTreeMap::new();
+    let deadline = tokio::time::sleep(wait);
+    tokio::pin!(deadline);
+
+    loop {
+        tokio::select! {
+            _ = &mut deadline => break,
+            event = events.recv() => match event {
+                Ok(EngineEvent::UserOnline(user)) if user.id != me.id => {
+                    peers.insert(user.id, user);
+                }
+                Ok(EngineEvent::UserOffline(user)) => {
+                    peers.remove(&user.id);
+                }
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => break,
+            },
+        }
+    }
+
+    Ok(peers)
+}
+
+/// Finds exactly one of the discovered `peers` whose ID, name, username or hostname
+/// matches `query`.
+fn resolve_peer(peers: &BTreeMap<u64, User>, query: &str) -> Result<User, String> {
+    let matches: Vec<User> = peers
+        .values()
+        .filter(|peer| {
+            query.parse::<u64>().is_ok_and(|id| id == peer.id)
+                || peer.name.eq_ignore_ascii_case(query)
+                || peer.username.eq_ignore_ascii_case(query)
+                || peer.hostname.as_deref().is_some_and(|h| h.eq_ignore_ascii_case(query))
+        })
+        .cloned()
+        .collect();
+
+    match matches.len() {
+        0 => Err(format!("no online peer matches '{}'", query)),
+        1 => Ok(matches.into_iter().next().unwrap()),
+        n => Err(format!("'{}' matches {} peers, use the user ID instead", query, n)),
+    }
+}
+
+// FNV-1a, so the same name on the same host keeps the same identity across runs
+fn default_user_id(name: &str, host: &str) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in name.bytes().chain([0]).chain(host.bytes()) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    // Keep IDs within JavaScript's safe integer range for the desktop frontend
+    hash & ((1 << 53) - 1)
+}
+
+fn unix_millis() -> u128 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis()
+}
+
+fn text_or_stdin(text: Option<String>) -> Result<String, String> {
+    match text {
+        Some(text) => Ok(text),
+        None => {
+            let mut buf = String::new();
+            std::io::stdin().read_to_string(&mut buf).map_err(|e| e.to_string())?;
+            Ok(buf)
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Largest announcement content, in bytes; announcements are never chunked.
+pub const MAX_ANNOUNCEMENT_SIZE: usize = 4000;
+/// Announcements one sender, or one address, may make per `ANNOUNCEMENT_WINDOW_SECS`.
+/// More are refused when sending and dropped when received.
+pub const ANNOUNCEMENT_LIMIT: usize = 5;
+pub const ANNOUNCEMENT_WINDOW_SECS: u64 = 60;
+// Past this, senders with nothing in the current window are forgotten
+const MAX_TRACKED_SENDERS: usize = 1024;
+
+/// Recent announcements by sender ID or by source address, for rate limiting.
+#[derive(Clone)]
+pub(crate) struct AnnouncementLimiter<K> {
+    recent: Arc<Mutex<HashMap<K, VecDeque<Instant>>>>,
+}
+
+impl<K> Default for AnnouncementLimiter<K> {
+    fn default() -> Self {
+        Self { recent: Arc::default() }
+    }
+}
+
+impl<K: Eq + Hash> AnnouncementLimiter<K> {
+    /// Counts an announcement by `sender` if it is within the limit, or returns
+    /// how long until it would be.
+    pub fn admit(&self, sender: K) -> Result<(), Duration> {
+        let window = Duration::from_secs(ANNOUNCEMENT_WINDOW_SECS);
+        let now = Instant::now();
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() >= MAX_TRACKED_SENDERS {
+            recent.retain(|_, times| times.back().is_some_and(|last| now.duration_since(*last) < window));
+        }
+
+        let times = recent.entry(sender).or_default();
+        while times.front().is_some_and(|first| now.duration_since(*first) >= window) {
+            times.pop_front();
+        }
+        if times.len() >= ANNOUNCEMENT_LIMIT {
+            return Err(window - now.duration_since(times[0]));
+        }
+        times.push_back(now);
+        Ok(())
+    }
+}
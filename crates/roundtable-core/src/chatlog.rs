@@ -0,0 +1,433 @@
+use chrono::{DateTime, Local, NaiveDate};
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use log::{error, info};
+
+pub const LOG_SETTINGS_FILE: &str = "log_settings.json";
+/// How often a running app should call [`ChatLogger::prune`], so age limits apply
+/// to long sessions too.
+pub const PRUNE_INTERVAL_SECS: u64 = 60 * 60;
+const SESSIONS_DIR: &str = "sessions";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    /// One JSON object per line.
+    Jsonl,
+}
+
+impl LogFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            LogFormat::Text => "log",
+            LogFormat::Jsonl => "jsonl",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct LogSettings {
+    pub enabled: bool,
+    pub directory: PathBuf,
+    pub format: LogFormat,
+    /// A day's log is rotated to `<date>.<n>.<ext>` once it reaches this size; 0 disables.
+    pub max_file_bytes: u64,
+    /// Logs not modified for this many days are deleted; 0 keeps them forever.
+    pub max_age_days: u32,
+}
+
+impl Default for LogSettings {
+    fn default() -> Self {
+        let directory = dirs::document_dir()
+            .or_else(dirs::data_local_dir)
+            .unwrap_or_else(|| PathBuf::from("."))
+            .join("RoundtableChat");
+
+        Self {
+            enabled: true,
+            directory,
+            format: LogFormat::Text,
+            max_file_bytes: 5 * 1024 * 1024,
+            max_age_days: 90,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Incoming,
+    Outgoing,
+}
+
+/// One chat message as written to the log.
+#[derive(Serialize, Debug, Clone)]
+pub struct LogEntry {
+    pub timestamp: DateTime<Local>,
+    pub direction: Direction,
+    pub sender_name: String,
+    pub sender_ip: String,
+    pub receiver_name: String,
+    pub receiver_ip: String,
+    pub message: String,
+}
+
+impl LogEntry {
+    pub fn new(
+        sender_name: String,
+        sender_ip: String,
+        receiver_name: String,
+        receiver_ip: String,
+        message: String,
+        is_outgoing: bool,
+    ) -> Self {
+        Self {
+            timestamp: Local::now(),
+            direction: if is_outgoing { Direction::Outgoing } else { Direction::Incoming },
+            sender_name,
+            sender_ip,
+            receiver_name,
+            receiver_ip,
+            message,
+        }
+    }
+
+    // The other party decides which conversation log the entry goes to
+    fn peer(&self) -> (&str, &str) {
+        match self.direction {
+            Direction::Outgoing => (&self.receiver_name, &self.receiver_ip),
+            Direction::Incoming => (&self.sender_name, &self.sender_ip),
+        }
+    }
+
+    fn format(&self, format: LogFormat) -> String {
+        match format {
+            LogFormat::Text => format!(
+                "[{}] {} {} ({}) -> {} ({}): {}\n",
+                self.timestamp.format("%Y-%m-%d %H:%M:%S"),
+                if self.direction == Direction::Outgoing { "OUT" } else { "IN " },
+                self.sender_name,
+                self.sender_ip,
+                self.receiver_name,
+                self.receiver_ip,
+                self.message
+            ),
+            LogFormat::Jsonl => json_line(self),
+        }
+    }
+}
+
+pub fn format_session_start(user_name: &str, user_ip: &str) -> String {
+    let now: DateTime<Local> = Local::now();
+    format!(
+        "\n--- Session Started ---\nLocal User: {} ({})\nTime: {}\n{}\n",
+        user_name,
+        user_ip,
+        now.format("%Y-%m-%d %H:%M:%S"),
+        "-".repeat(30)
+    )
+}
+
+pub fn format_chat_participants(local_user: &str, local_ip: &str, remote_user: &str, remote_ip: &str) -> String {
+    format!(
+        "\n--- Chat Participants ---\nLocal: {} ({})\nRemote: {} ({})\nTime: {}\n{}\n",
+        local_user,
+        local_ip,
+        remote_user,
+        remote_ip,
+        Local::now().format("%Y-%m-%d %H:%M:%S"),
+        "-".repeat(25)
+    )
+}
+
+/// Writes chat logs split per peer and per day under the configured directory:
+/// `<dir>/<peer>_<ip>/<YYYY-MM-DD>.log` (or `.jsonl`), with session starts in
+/// `<dir>/sessions/`. Settings are persisted next to the engine's other data.
+pub struct ChatLogger {
+    settings_path: PathBuf,
+    settings: Mutex<LogSettings>,
+}
+
+impl ChatLogger {
+    /// Loads settings from `settings_path`, falling back to the defaults.
+    pub fn load(settings_path: PathBuf) -> Self {
+        let settings = match std::fs::read(&settings_path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+                error!("Ignoring unreadable log settings {}: {}", settings_path.display(), e);
+                LogSettings::default()
+            }),
+            Err(_) => LogSettings::default(),
+        };
+
+        let logger = Self { settings_path, settings: Mutex::new(settings) };
+        if let Err(e) = logger.prune() {
+            error!("Failed to prune old chat logs: {}", e);
+        }
+        logger
+    }
+
+    pub fn settings(&self) -> LogSettings {
+        self.settings.lock().unwrap().clone()
+    }
+
+    /// Applies and persists new settings. The directory must be creatable.
+    pub fn update_settings(&self, settings: LogSettings) -> io::Result<()> {
+        if settings.enabled {
+            std::fs::create_dir_all(&settings.directory)?;
+        }
+
+        if let Some(parent) = self.settings_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&self.settings_path, serde_json::to_vec_pretty(&settings)?)?;
+
+        info!("Chat logs now go to {} as {:?}", settings.directory.display(), settings.format);
+        *self.settings.lock().unwrap() = settings;
+        self.prune().map(|_| ())
+    }
+
+    pub fn log_message(&self, entry: &LogEntry) -> io::Result<()> {
+        let settings = self.settings.lock().unwrap();
+        if !settings.enabled {
+            return Ok(());
+        }
+
+        let (peer_name, peer_ip) = entry.peer();
+        let dir = settings.directory.join(peer_dir_name(peer_name, peer_ip));
+        append(&settings, &dir, entry.timestamp.date_naive(), &entry.format(settings.format))
+    }
+
+    pub fn log_session_start(&self, user_name: &str, user_ip: &str) -> io::Result<()> {
+        let settings = self.settings.lock().unwrap();
+        if !settings.enabled {
+            return Ok(());
+        }
+
+        let content = match settings.format {
+            LogFormat::Text => format_session_start(user_name, user_ip),
+            LogFormat::Jsonl => json_line(&serde_json::json!({
+                "event": "session_start",
+                "timestamp": Local::now(),
+                "user_name": user_name,
+                "user_ip": user_ip,
+            })),
+        };
+        let dir = settings.directory.join(SESSIONS_DIR);
+        append(&settings, &dir, Local::now().date_naive(), &content)
+    }
+
+    pub fn log_chat_participants(&self, local_user: &str, local_ip: &str, remote_user: &str, remote_ip: &str) -> io::Result<()> {
+        let settings = self.settings.lock().unwrap();
+        if !settings.enabled {
+            return Ok(());
+        }
+
+        let content = match settings.format {
+            LogFormat::Text => format_chat_participants(local_user, local_ip, remote_user, remote_ip),
+            LogFormat::Jsonl => json_line(&serde_json::json!({
+                "event": "participants",
+                "timestamp": Local::now(),
+                "local_name": local_user,
+                "local_ip": local_ip,
+                "remote_name": remote_user,
+                "remote_ip": remote_ip,
+            })),
+        };
+        let dir = settings.directory.join(peer_dir_name(remote_user, remote_ip));
+        append(&settings, &dir, Local::now().date_naive(), &content)
+    }
+
+    /// Deletes logs older than `max_age_days`, returning how many were removed. Only
+    /// files named as this logger names them, in directories named as it names
+    /// them, are touched, since the directory may be shared with other files.
+    pub fn prune(&self) -> io::Result<usize> {
+        let settings = self.settings();
+        if settings.max_age_days == 0 || !settings.directory.exists() {
+            return Ok(0);
+        }
+
+        let max_age = Duration::from_secs(u64::from(settings.max_age_days) * 24 * 60 * 60);
+        let mut removed = 0;
+        for peer_dir in std::fs::read_dir(&settings.directory)? {
+            let peer_dir = peer_dir?;
+            // Not followed through symlinks, which could lead anywhere
+            if !peer_dir.file_type()?.is_dir() || !is_log_dir_name(&peer_dir.file_name().to_string_lossy()) {
+                continue;
+            }
+            for file in std::fs::read_dir(peer_dir.path())? {
+                let file = file?;
+                if !file.file_type()?.is_file() || !is_log_file_name(&file.file_name().to_string_lossy()) {
+                    continue;
+                }
+                let age = file
+                    .metadata()?
+                    .modified()
+                    .ok()
+                    .and_then(|modified| SystemTime::now().duration_since(modified).ok());
+
+                if age.is_some_and(|age| age > max_age) {
+                    std::fs::remove_file(file.path())?;
+                    removed += 1;
+                }
+            }
+        }
+
+        if removed > 0 {
+            info!("Removed {} chat logs older than {} days", removed, settings.max_age_days);
+        }
+        Ok(removed)
+    }
+}
+
+fn append(settings: &LogSettings, dir: &Path, date: NaiveDate, content: &str) -> io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+
+    let ext = settings.format.extension();
+    let log_path = dir.join(format!("{}.{}", date.format("%Y-%m-%d"), ext));
+    rotate_if_full(&log_path, settings.max_file_bytes, date, ext)?;
+
+    let file_exists = log_path.exists();
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&log_path)?;
+
+    if !file_exists && settings.format == LogFormat::Text {
+        let now: DateTime<Local> = Local::now();
+        let header = format!(
+            "=== Roundtable Chat Log ===\nDate: {}\nLog started at: {}\n{}\n",
+            date.format("%Y-%m-%d"),
+            now.format("%H:%M:%S"),
+            "=".repeat(50)
+        );
+        file.write_all(header.as_bytes())?;
+    }
+
+    file.write_all(content.as_bytes())
+}
+
+// Moves a full log aside as `<date>.<n>.<ext>` so writing continues in a fresh file
+fn rotate_if_full(log_path: &Path, max_bytes: u64, date: NaiveDate, ext: &str) -> io::Result<()> {
+    if max_bytes == 0 {
+        return Ok(());
+    }
+    match std::fs::metadata(log_path) {
+        Ok(metadata) if metadata.len() >= max_bytes => {}
+        _ => return Ok(()),
+    }
+
+    let dir = log_path.parent().unwrap_or_else(|| Path::new("."));
+    let rotated = (1..)
+        .map(|n| dir.join(format!("{}.{}.{}", date.format("%Y-%m-%d"), n, ext)))
+        .find(|path| !path.exists())
+        .unwrap();
+    std::fs::rename(log_path, rotated)
+}
+
+/// Directory name for a peer's logs, safe on every platform.
+fn peer_dir_name(name: &str, ip: &str) -> String {
+    let sanitize = |s: &str| -> String {
+        s.chars()
+            .map(|c| if c.is_alphanumeric() || c == '-' || c == '.' { c } else { '_' })
+            .collect()
+    };
+    let name = sanitize(name.trim());
+    let name = if name.is_empty() { "unknown".to_string() } else { name };
+    format!("{}_{}", name, sanitize(ip))
+}
+
+// The sessions directory, or one `peer_dir_name` could have produced
+fn is_log_dir_name(name: &str) -> bool {
+    name == SESSIONS_DIR
+        || (name.contains('_') && name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '.' || c == '_'))
+}
+
+// `<YYYY-MM-DD>.<ext>` as `append` writes it, or `<YYYY-MM-DD>.<n>.<ext>` once rotated
+fn is_log_file_name(name: &str) -> bool {
+    let Some(stem) = name.strip_suffix(".log").or_else(|| name.strip_suffix(".jsonl")) else {
+        return false;
+    };
+    let (date, rotation) = match stem.split_once('.') {
+        Some((date, n)) => (date, Some(n)),
+        None => (stem, None),
+    };
+    date.len() == 10
+        && NaiveDate::parse_from_str(date, "%Y-%m-%d").is_ok()
+        && rotation.is_none_or(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+}
+
+fn json_line(value: &impl Serialize) -> String {
+    let mut line = serde_json::to_string(value).unwrap_or_default();
+    line.push('\n');
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("roundtable-chatlog-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn write_aged(path: &Path, days: u64) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let file = File::create(path).unwrap();
+        file.set_modified(SystemTime::now() - Duration::from_secs(days * 24 * 60 * 60)).unwrap();
+    }
+
+    #[test]
+    fn recognises_only_its_own_names() {
+        assert!(is_log_file_name("2024-01-31.log"));
+        assert!(is_log_file_name("2024-01-31.jsonl"));
+        assert!(is_log_file_name("2024-01-31.12.log"));
+        for name in ["notes.log", "2024-01-31.txt", "2024-13-01.log", "2024-01-31.x.log", "2024-01-31..log", "24-01-31.log"] {
+            assert!(!is_log_file_name(name), "{:?}", name);
+        }
+
+        assert!(is_log_dir_name(SESSIONS_DIR));
+        assert!(is_log_dir_name(&peer_dir_name("Bob Smith", "192.168.1.4")));
+        assert!(is_log_dir_name(&peer_dir_name("bob", "fe80::1%4")));
+        assert!(!is_log_dir_name("Projects"));
+        assert!(!is_log_dir_name("My Music_old"));
+    }
+
+    #[test]
+    fn prunes_only_old_logs_it_wrote() {
+        let dir = scratch_dir("prune");
+        let logger = ChatLogger::load(dir.join(LOG_SETTINGS_FILE));
+        logger
+            .update_settings(LogSettings { directory: dir.clone(), max_age_days: 30, ..LogSettings::default() })
+            .unwrap();
+
+        let peer = dir.join(peer_dir_name("bob", "192.0.2.1"));
+        let old_logs = [peer.join("2024-01-01.log"), peer.join("2024-01-01.1.log"), dir.join(SESSIONS_DIR).join("2024-01-01.jsonl")];
+        let kept = [
+            peer.join("2025-01-01.log"),
+            peer.join("notes.log"),
+            dir.join("Projects").join("2024-01-01.log"),
+            dir.join("2024-01-01.log"),
+        ];
+        for path in &old_logs {
+            write_aged(path, 40);
+        }
+        write_aged(&kept[0], 1);
+        for path in &kept[1..] {
+            write_aged(path, 40);
+        }
+
+        assert_eq!(logger.prune().unwrap(), old_logs.len());
+        assert!(old_logs.iter().all(|path| !path.exists()));
+        assert!(kept.iter().all(|path| path.exists()));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,302 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+
+use crate::error::MessageError;
+
+pub const CHUNK_SIZE: usize = 2000;
+/// Largest message content, in bytes, before compression or encryption.
+pub const MAX_MESSAGE_SIZE: usize = 1_000_000;
+pub const CHUNK_TIMEOUT_SECS: u64 = 30;
+pub const CHUNK_SEND_DELAY_MS: u64 = 10;
+/// How long a message ID is remembered for duplicate detection.
+pub const DEDUPE_WINDOW_SECS: u64 = 600;
+pub const DEDUPE_MAX_ENTRIES: usize = 10_000;
+
+// Improved chunk management
+#[derive(Debug, Clone)]
+struct ChunkData {
+    content: String,
+    timestamp: Instant,
+}
+
+#[derive(Debug)]
+struct PartialMessage {
+    total_chunks: u16,
+    parts: HashMap<u16, ChunkData>,
+}
+
+/// Recently seen message IDs, bounded by age and by count.
+#[derive(Debug, Default)]
+struct DedupeCache {
+    seen: HashMap<String, Instant>,
+    order: VecDeque<(Instant, String)>,
+}
+
+impl DedupeCache {
+    fn contains(&self, message_id: &str) -> bool {
+        self.seen.contains_key(message_id)
+    }
+
+    /// Returns false if the ID was already present.
+    fn insert(&mut self, message_id: String) -> bool {
+        if self.seen.contains_key(&message_id) {
+            return false;
+        }
+
+        let now = Instant::now();
+        self.seen.insert(message_id.clone(), now);
+        self.order.push_back((now, message_id));
+
+        while self.order.len() > DEDUPE_MAX_ENTRIES {
+            self.evict_oldest();
+        }
+        true
+    }
+
+    fn expire(&mut self, window: Duration) {
+        let now = Instant::now();
+        while self.order.front().is_some_and(|(seen_at, _)| now.duration_since(*seen_at) >= window) {
+            self.evict_oldest();
+        }
+    }
+
+    fn evict_oldest(&mut self) {
+        if let Some((_, message_id)) = self.order.pop_front() {
+            self.seen.remove(&message_id);
+        }
+    }
+}
+
+/// Outcome of storing one chunk.
+pub enum Reassembly {
+    Complete(String),
+    /// The first chunk of a message we had not seen before.
+    Started,
+    Pending,
+}
+
+pub struct ChunkManager {
+    chunks: Arc<RwLock<HashMap<String, PartialMessage>>>,
+    processed_messages: Arc<RwLock<DedupeCache>>,
+}
+
+impl Default for ChunkManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChunkManager {
+    pub fn new() -> Self {
+        Self {
+            chunks: Arc::new(RwLock::new(HashMap::new())),
+            processed_messages: Arc::new(RwLock::new(DedupeCache::default())),
+        }
+    }
+
+    pub async fn cleanup_old_chunks(&self) {
+        let now = Instant::now();
+        let timeout = Duration::from_secs(CHUNK_TIMEOUT_SECS);
+
+        let mut chunks = self.chunks.write().await;
+        chunks.retain(|_, message| {
+            message.parts.retain(|_, chunk_data| {
+                now.duration_since(chunk_data.timestamp) < timeout
+            });
+            !message.parts.is_empty()
+        });
+        drop(chunks);
+
+        self.processed_messages.write().await.expire(Duration::from_secs(DEDUPE_WINDOW_SECS));
+    }
+
+    pub async fn is_processed(&self, message_id: &str) -> bool {
+        self.processed_messages.read().await.contains(message_id)
+    }
+
+    /// Records a message as handled; returns false if it already was.
+    pub async fn mark_processed(&self, message_id: String) -> bool {
+        self.processed_messages.write().await.insert(message_id)
+    }
+
+    /// Stores one chunk and returns the full message once every chunk has arrived.
+    /// A chunk whose index is out of range, or whose count disagrees with earlier
+    /// chunks of the same message, is refused and the message left as it was.
+    pub async fn reassemble(
+        &self,
+        chunk_id: String,
+        chunk_index: u16,
+        total_chunks: u16,
+        content: String,
+    ) -> Result<Reassembly, MessageError> {
+        if chunk_index >= total_chunks {
+            return Err(MessageError::InvalidData(format!(
+                "Chunk {} of {} is out of range",
+                chunk_index, total_chunks
+            )));
+        }
+
+        let chunk_data = ChunkData {
+            content,
+            timestamp: Instant::now(),
+        };
+
+        let mut chunks = self.chunks.write().await;
+        let message = chunks.entry(chunk_id.clone()).or_insert_with(|| PartialMessage {
+            total_chunks,
+            parts: HashMap::new(),
+        });
+        if message.total_chunks != total_chunks {
+            return Err(MessageError::InvalidData(format!(
+                "Chunk claims {} chunks but message {} has {}",
+                total_chunks, chunk_id, message.total_chunks
+            )));
+        }
+        message.parts.insert(chunk_index, chunk_data);
+
+        if message.parts.len() == total_chunks as usize {
+            let mut complete = String::with_capacity(total_chunks as usize * CHUNK_SIZE);
+
+            let mut sorted_chunks: Vec<_> = message.parts.iter().collect();
+            sorted_chunks.sort_by_key(|(index, _)| *index);
+
+            for (_, chunk_data) in sorted_chunks {
+                complete.push_str(&chunk_data.content);
+            }
+
+            chunks.remove(&chunk_id);
+            Ok(Reassembly::Complete(complete))
+        } else if message.parts.len() == 1 {
+            Ok(Reassembly::Started)
+        } else {
+            Ok(Reassembly::Pending)
+        }
+    }
+
+    /// Indices still missing for an incomplete message, and how long ago its last chunk arrived.
+    /// `None` once the message has completed or expired.
+    pub async fn missing_chunks(&self, chunk_id: &str) -> Option<(Vec<u16>, Duration)> {
+        let chunks = self.chunks.read().await;
+        let message = chunks.get(chunk_id)?;
+
+        let missing = (0..message.total_chunks)
+            .filter(|index| !message.parts.contains_key(index))
+            .collect();
+        let last_arrival = message.parts.values().map(|chunk| chunk.timestamp).max()?;
+
+        Some((missing, last_arrival.elapsed()))
+    }
+}
+
+/// Splits a message into `CHUNK_SIZE` byte pieces without cutting through a UTF-8 character.
+pub fn split_message(message: &str) -> Vec<String> {
+    let message_bytes = message.as_bytes();
+    let mut chunks = Vec::new();
+    let mut start = 0;
+
+    while start < message_bytes.len() {
+        let end = std::cmp::min(start + CHUNK_SIZE, message_bytes.len());
+        let chunk_bytes = &message_bytes[start..end];
+
+        let chunk_str = match std::str::from_utf8(chunk_bytes) {
+            Ok(s) => s.to_string(),
+            Err(e) => {
+                let valid_end = start + e.valid_up_to();
+                let chunk_bytes = &message_bytes[start..valid_end];
+                std::str::from_utf8(chunk_bytes).unwrap().to_string()
+            }
+        };
+
+        let chunk_len = chunk_str.len();
+        chunks.push(chunk_str);
+        start += chunk_len;
+    }
+
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn complete(reassembly: Result<Reassembly, MessageError>) -> Option<String> {
+        match reassembly.unwrap() {
+            Reassembly::Complete(message) => Some(message),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn reassembles_out_of_order_chunks() {
+        let manager = ChunkManager::new();
+        assert!(matches!(manager.reassemble("m".into(), 1, 3, "b".into()).await, Ok(Reassembly::Started)));
+        assert!(matches!(manager.reassemble("m".into(), 2, 3, "c".into()).await, Ok(Reassembly::Pending)));
+        assert_eq!(manager.missing_chunks("m").await.unwrap().0, vec![0]);
+        assert_eq!(complete(manager.reassemble("m".into(), 0, 3, "a".into()).await).as_deref(), Some("abc"));
+        assert!(manager.missing_chunks("m").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn rejects_index_out_of_range() {
+        let manager = ChunkManager::new();
+        assert!(manager.reassemble("m".into(), 2, 2, "x".into()).await.is_err());
+        assert!(manager.reassemble("m".into(), u16::MAX, 2, "x".into()).await.is_err());
+        assert!(manager.missing_chunks("m").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn rejects_changed_chunk_count() {
+        let manager = ChunkManager::new();
+        manager.reassemble("m".into(), 0, 3, "a".into()).await.unwrap();
+        assert!(manager.reassemble("m".into(), 1, 2, "b".into()).await.is_err());
+        // The refused chunk must not have completed or altered the message
+        assert_eq!(manager.missing_chunks("m").await.unwrap().0, vec![1, 2]);
+        manager.reassemble("m".into(), 1, 3, "b".into()).await.unwrap();
+        assert_eq!(complete(manager.reassemble("m".into(), 2, 3, "c".into()).await).as_deref(), Some("abc"));
+    }
+
+    #[tokio::test]
+    async fn marks_each_message_once() {
+        let manager = ChunkManager::new();
+        assert!(!manager.is_processed("m").await);
+        assert!(manager.mark_processed("m".into()).await);
+        assert!(manager.is_processed("m").await);
+        assert!(!manager.mark_processed("m".into()).await);
+        // Still inside the window, so cleanup keeps it
+        manager.cleanup_old_chunks().await;
+        assert!(!manager.mark_processed("m".into()).await);
+    }
+
+    #[test]
+    fn dedupe_forgets_ids_outside_the_window() {
+        let mut cache = DedupeCache::default();
+        assert!(cache.insert("a".into()));
+        cache.expire(Duration::from_secs(DEDUPE_WINDOW_SECS));
+        assert!(cache.contains("a"));
+        cache.expire(Duration::ZERO);
+        assert!(!cache.contains("a"));
+        assert!(cache.insert("a".into()));
+    }
+
+    #[test]
+    fn dedupe_evicts_oldest_beyond_capacity() {
+        let mut cache = DedupeCache::default();
+        for i in 0..=DEDUPE_MAX_ENTRIES {
+            assert!(cache.insert(i.to_string()));
+        }
+        assert!(!cache.contains("0"));
+        assert!(cache.contains("1"));
+        assert!(cache.contains(&DEDUPE_MAX_ENTRIES.to_string()));
+        assert_eq!(cache.seen.len(), DEDUPE_MAX_ENTRIES);
+    }
+
+    #[test]
+    fn split_keeps_characters_whole() {
+        let message = "é".repeat(CHUNK_SIZE);
+        let chunks = split_message(&message);
+        assert!(chunks.iter().all(|chunk| chunk.len() <= CHUNK_SIZE));
+        assert_eq!(chunks.concat(), message);
+    }
+}
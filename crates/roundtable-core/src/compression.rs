@@ -0,0 +1,134 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine as _;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::chunk::MAX_MESSAGE_SIZE;
+use crate::error::MessageError;
+
+/// Advertised in presence by peers that read zstd-compressed messages and file streams.
+pub const ZSTD_CAPABILITY: &str = "zstd";
+/// Messages shorter than this are sent as they are.
+pub const COMPRESSION_THRESHOLD: usize = 1024;
+
+const MESSAGE_LEVEL: i32 = 3;
+// File streams favour speed; a LAN link is rarely slower than this level
+const STREAM_LEVEL: i32 = 1;
+
+const FRAME_STORED: u8 = 0;
+const FRAME_ZSTD: u8 = 1;
+
+/// Extensions of formats that are compressed already and gain nothing from another pass.
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "aac", "apk", "avi", "avif", "br", "bz2", "deb", "dmg", "docx", "epub", "flac", "gif", "gz", "heic", "heif",
+    "jar", "jpeg", "jpg", "lz4", "lzma", "m4a", "m4v", "mkv", "mov", "mp3", "mp4", "odp", "ods", "odt", "ogg", "opus",
+    "png", "pptx", "rar", "rpm", "tgz", "webm", "webp", "wmv", "xlsx", "xz", "zip", "zst",
+];
+
+/// How message content or a file stream is compressed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Zstd,
+}
+
+/// Compresses message content that is long enough to be worth it, or returns
+/// `None` when compressing would not make it smaller.
+pub fn compress_message(content: &str) -> Option<Vec<u8>> {
+    if content.len() < COMPRESSION_THRESHOLD {
+        return None;
+    }
+    zstd::bulk::compress(content.as_bytes(), MESSAGE_LEVEL)
+        .ok()
+        .filter(|compressed| compressed.len() < content.len())
+}
+
+/// Restores compressed message content, refusing anything that would expand past
+/// the largest message a peer may send.
+pub fn decompress_message(compression: Compression, bytes: &[u8]) -> Result<String, MessageError> {
+    let content = match compression {
+        Compression::Zstd => zstd::bulk::decompress(bytes, MAX_MESSAGE_SIZE)
+            .map_err(|e| MessageError::InvalidData(format!("Message failed to decompress: {}", e)))?,
+    };
+    String::from_utf8(content).map_err(|_| MessageError::InvalidData("Decompressed message is not UTF-8".to_string()))
+}
+
+/// Compressed bytes as sent in a plaintext message's `content`.
+pub fn to_text(bytes: &[u8]) -> String {
+    BASE64.encode(bytes)
+}
+
+pub fn from_text(content: &str) -> Result<Vec<u8>, MessageError> {
+    BASE64
+        .decode(content)
+        .map_err(|_| MessageError::InvalidData("Malformed compressed message".to_string()))
+}
+
+/// Whether a file's name marks it as compressed already.
+pub fn is_compressed_format(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| COMPRESSED_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str()))
+}
+
+/// Frames a file stream's chunks one at a time, so offsets still count bytes of the
+/// files and a range or resume can start at any chunk. Each frame is a flag byte
+/// and then the chunk, compressed unless that would not make it smaller.
+pub(crate) struct ChunkCompressor {
+    zstd: zstd::bulk::Compressor<'static>,
+    frame: Vec<u8>,
+}
+
+impl ChunkCompressor {
+    pub fn new(max_chunk: usize) -> std::io::Result<Self> {
+        Ok(Self { zstd: zstd::bulk::Compressor::new(STREAM_LEVEL)?, frame: vec![0u8; max_chunk + 1] })
+    }
+
+    pub fn frame(&mut self, chunk: &[u8]) -> &[u8] {
+        // Room for one byte less than the chunk, so only a smaller result fits
+        let limit = chunk.len().saturating_sub(1);
+        if let Ok(n) = self.zstd.compress_to_buffer(chunk, &mut self.frame[1..=limit]) {
+            self.frame[0] = FRAME_ZSTD;
+            return &self.frame[..n + 1];
+        }
+        self.frame[0] = FRAME_STORED;
+        self.frame[1..=chunk.len()].copy_from_slice(chunk);
+        &self.frame[..chunk.len() + 1]
+    }
+}
+
+/// Reads frames written by [`ChunkCompressor`].
+pub(crate) struct ChunkDecompressor {
+    zstd: zstd::bulk::Decompressor<'static>,
+    frame: Vec<u8>,
+}
+
+impl ChunkDecompressor {
+    pub fn new(max_chunk: usize) -> std::io::Result<Self> {
+        Ok(Self { zstd: zstd::bulk::Decompressor::new()?, frame: vec![0u8; max_chunk + 1] })
+    }
+
+    /// Where the next frame is read to.
+    pub fn frame_buffer(&mut self) -> &mut [u8] {
+        &mut self.frame
+    }
+
+    /// Unpacks the first `len` bytes of the frame buffer into `out`, failing if the
+    /// chunk does not fit.
+    pub fn unframe(&mut self, len: usize, out: &mut [u8]) -> std::io::Result<usize> {
+        let invalid = |message: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string());
+        let (flag, payload) = self.frame[..len].split_first().ok_or_else(|| invalid("Empty compressed frame"))?;
+        match *flag {
+            FRAME_STORED if payload.len() <= out.len() => {
+                out[..payload.len()].copy_from_slice(payload);
+                Ok(payload.len())
+            }
+            FRAME_STORED => Err(invalid("Sender sent more than requested")),
+            FRAME_ZSTD => self
+                .zstd
+                .decompress_to_buffer(payload, out)
+                .map_err(|_| invalid("Compressed frame failed to decompress or was too large")),
+            _ => Err(invalid("Unknown compressed frame")),
+        }
+    }
+}
@@ -0,0 +1,302 @@
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine as _;
+use hkdf::Hkdf;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::error::MessageError;
+
+const MESSAGE_KEY_INFO: &[u8] = b"roundtable-v1 message";
+const FILE_KEY_INFO: &[u8] = b"roundtable-v1 file";
+/// Authentication tag appended to every sealed file record.
+pub const TAG_SIZE: usize = 16;
+
+/// Travels with encrypted message content so the receiver can derive the same key.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Envelope {
+    /// The sender's X25519 public key, base64.
+    pub sender_key: String,
+    pub nonce: String,
+}
+
+/// This node's X25519 key pair. The public half is advertised in presence; a new
+/// pair is generated every time the engine starts.
+pub struct KeyPair {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl KeyPair {
+    pub fn generate() -> Self {
+        let secret = StaticSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret);
+        Self { secret, public }
+    }
+
+    pub fn public_key(&self) -> String {
+        BASE64.encode(self.public.as_bytes())
+    }
+
+    // X25519 followed by HKDF-SHA256, so message and file keys never coincide
+    fn derive_cipher(&self, peer_key: &str, salt: Option<&[u8]>, info: &[u8]) -> Result<Aes256Gcm, MessageError> {
+        let bytes: [u8; 32] = BASE64
+            .decode(peer_key)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| MessageError::CryptoError("Malformed public key".to_string()))?;
+
+        let shared = self.secret.diffie_hellman(&PublicKey::from(bytes));
+        if !shared.was_contributory() {
+            return Err(MessageError::CryptoError("Peer sent a low-order public key".to_string()));
+        }
+
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(salt, shared.as_bytes())
+            .expand(info, &mut key)
+            .map_err(|e| MessageError::CryptoError(e.to_string()))?;
+
+        Ok(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)))
+    }
+
+    /// Encrypts chat content for `peer_key`. `aad` binds the ciphertext to the
+    /// message it was sent in (see [`message_aad`]). Returns base64 ciphertext.
+    pub fn seal_message(&self, peer_key: &str, plaintext: &str, aad: &[u8]) -> Result<(String, Envelope), MessageError> {
+        self.seal_message_bytes(peer_key, plaintext.as_bytes(), aad)
+    }
+
+    /// As [`seal_message`](Self::seal_message), for content that is not text, such
+    /// as compressed content.
+    pub fn seal_message_bytes(&self, peer_key: &str, plaintext: &[u8], aad: &[u8]) -> Result<(String, Envelope), MessageError> {
+        let cipher = self.derive_cipher(peer_key, None, MESSAGE_KEY_INFO)?;
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+
+        let ciphertext = cipher
+            .encrypt(&nonce, Payload { msg: plaintext, aad })
+            .map_err(|_| MessageError::CryptoError("Encryption failed".to_string()))?;
+
+        let envelope = Envelope {
+            sender_key: self.public_key(),
+            nonce: BASE64.encode(nonce),
+        };
+        Ok((BASE64.encode(ciphertext), envelope))
+    }
+
+    pub fn open_message(&self, envelope: &Envelope, ciphertext: &str, aad: &[u8]) -> Result<String, MessageError> {
+        let plaintext = self.open_message_bytes(envelope, ciphertext, aad)?;
+        String::from_utf8(plaintext).map_err(|_| MessageError::CryptoError("Decrypted content is not UTF-8".to_string()))
+    }
+
+    pub fn open_message_bytes(&self, envelope: &Envelope, ciphertext: &str, aad: &[u8]) -> Result<Vec<u8>, MessageError> {
+        let cipher = self.derive_cipher(&envelope.sender_key, None, MESSAGE_KEY_INFO)?;
+
+        let nonce = BASE64
+            .decode(&envelope.nonce)
+            .ok()
+            .filter(|nonce| nonce.len() == 12)
+            .ok_or_else(|| MessageError::CryptoError("Malformed nonce".to_string()))?;
+        let ciphertext = BASE64
+            .decode(ciphertext)
+            .map_err(|_| MessageError::CryptoError("Malformed ciphertext".to_string()))?;
+
+        cipher
+            .decrypt(Nonce::from_slice(&nonce), Payload { msg: &ciphertext, aad })
+            .map_err(|_| MessageError::CryptoError("Decryption failed".to_string()))
+    }
+
+    /// The cipher for one file transfer with `peer_key`; the transfer ID salts the key.
+    pub fn file_cipher(&self, peer_key: &str, transfer_id: &str) -> Result<FileCipher, MessageError> {
+        let cipher = self.derive_cipher(peer_key, Some(transfer_id.as_bytes()), FILE_KEY_INFO)?;
+        Ok(FileCipher { cipher, session: 0, counter: 0 })
+    }
+}
+
+/// Additional authenticated data for chat content, so ciphertext cannot be replayed
+/// under another message ID or between other users.
+pub fn message_aad(message_id: &str, sender_id: u64, target_id: u64) -> Vec<u8> {
+    format!("{}:{}:{}", message_id, sender_id, target_id).into_bytes()
+}
+
+/// Seals or opens the records of a single file stream. Nonces are a session prefix
+/// and a record counter, so records cannot be dropped, reordered or replayed
+/// without failing to open.
+#[derive(Clone)]
+pub struct FileCipher {
+    cipher: Aes256Gcm,
+    session: u32,
+    counter: u64,
+}
+
+impl FileCipher {
+    /// A fresh stream under the same key. Every connection of a transfer (e.g. a
+    /// resumed one) must use a different session so nonces are never reused.
+    pub fn for_session(&self, session: u32) -> Self {
+        Self { cipher: self.cipher.clone(), session, counter: 0 }
+    }
+
+    fn next_nonce(&mut self) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[..4].copy_from_slice(&self.session.to_be_bytes());
+        nonce[4..].copy_from_slice(&self.counter.to_be_bytes());
+        self.counter += 1;
+        nonce
+    }
+
+    pub fn seal(&mut self, chunk: &[u8]) -> Result<Vec<u8>, MessageError> {
+        let nonce = self.next_nonce();
+        self.cipher
+            .encrypt(Nonce::from_slice(&nonce), chunk)
+            .map_err(|_| MessageError::CryptoError("Encryption failed".to_string()))
+    }
+
+    pub fn open(&mut self, record: &[u8]) -> Result<Vec<u8>, MessageError> {
+        let nonce = self.next_nonce();
+        self.cipher
+            .decrypt(Nonce::from_slice(&nonce), record)
+            .map_err(|_| MessageError::CryptoError("Decryption failed".to_string()))
+    }
+}
+
+/// A random session number for [`FileCipher::for_session`].
+pub fn new_session() -> u32 {
+    OsRng.next_u32()
+}
+
+/// Secret a recipient presents when connecting to a transfer server.
+pub type TransferToken = [u8; 16];
+
+/// A fresh token for one recipient's acceptance of a transfer.
+pub fn new_transfer_token() -> TransferToken {
+    let mut token = TransferToken::default();
+    OsRng.fill_bytes(&mut token);
+    token
+}
+
+pub fn encode_token(token: &TransferToken) -> String {
+    BASE64.encode(token)
+}
+
+pub fn decode_token(token: &str) -> Option<TransferToken> {
+    BASE64.decode(token).ok()?.try_into().ok()
+}
+
+/// Compares tokens in constant time.
+pub fn tokens_match(a: &TransferToken, b: &TransferToken) -> bool {
+    a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Public keys peers have advertised, by user ID.
+#[derive(Clone, Default)]
+pub struct PeerKeys {
+    keys: Arc<Mutex<HashMap<u64, String>>>,
+}
+
+impl PeerKeys {
+    pub fn insert(&self, user_id: u64, public_key: String) {
+        self.keys.lock().unwrap().insert(user_id, public_key);
+    }
+
+    pub fn get(&self, user_id: u64) -> Option<String> {
+        self.keys.lock().unwrap().get(&user_id).cloned()
+    }
+
+    pub fn remove(&self, user_id: u64) {
+        self.keys.lock().unwrap().remove(&user_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tamper(base64: &str) -> String {
+        let mut bytes = BASE64.decode(base64).unwrap();
+        bytes[0] ^= 1;
+        BASE64.encode(bytes)
+    }
+
+    #[test]
+    fn message_round_trip() {
+        let (alice, bob) = (KeyPair::generate(), KeyPair::generate());
+        let aad = message_aad("m", 1, 2);
+        let (ciphertext, envelope) = alice.seal_message(&bob.public_key(), "hello", &aad).unwrap();
+        assert_ne!(ciphertext, BASE64.encode("hello"));
+        assert_eq!(bob.open_message(&envelope, &ciphertext, &aad).unwrap(), "hello");
+    }
+
+    #[test]
+    fn message_rejects_tampering() {
+        let (alice, bob) = (KeyPair::generate(), KeyPair::generate());
+        let aad = message_aad("m", 1, 2);
+        let (ciphertext, envelope) = alice.seal_message(&bob.public_key(), "hello", &aad).unwrap();
+
+        assert!(bob.open_message(&envelope, &tamper(&ciphertext), &aad).is_err());
+        let nonce = Envelope { nonce: tamper(&envelope.nonce), ..envelope.clone() };
+        assert!(bob.open_message(&nonce, &ciphertext, &aad).is_err());
+        // Replayed under another message ID or between other users
+        assert!(bob.open_message(&envelope, &ciphertext, &message_aad("n", 1, 2)).is_err());
+        assert!(bob.open_message(&envelope, &ciphertext, &message_aad("m", 3, 2)).is_err());
+    }
+
+    #[test]
+    fn message_rejects_other_keys() {
+        let (alice, bob, eve) = (KeyPair::generate(), KeyPair::generate(), KeyPair::generate());
+        let aad = message_aad("m", 1, 2);
+        let (ciphertext, envelope) = alice.seal_message(&bob.public_key(), "hello", &aad).unwrap();
+        assert!(eve.open_message(&envelope, &ciphertext, &aad).is_err());
+        let forged = Envelope { sender_key: eve.public_key(), ..envelope };
+        assert!(bob.open_message(&forged, &ciphertext, &aad).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_and_low_order_keys() {
+        let alice = KeyPair::generate();
+        assert!(alice.seal_message("not a key", "hello", b"").is_err());
+        assert!(alice.seal_message(&BASE64.encode([0u8; 32]), "hello", b"").is_err());
+    }
+
+    #[test]
+    fn file_round_trip() {
+        let (alice, bob) = (KeyPair::generate(), KeyPair::generate());
+        let mut sealer = alice.file_cipher(&bob.public_key(), "t").unwrap().for_session(5);
+        let mut opener = bob.file_cipher(&alice.public_key(), "t").unwrap().for_session(5);
+        for chunk in [&b"one"[..], b"two", b""] {
+            let record = sealer.seal(chunk).unwrap();
+            assert_eq!(record.len(), chunk.len() + TAG_SIZE);
+            assert_eq!(opener.open(&record).unwrap(), chunk);
+        }
+    }
+
+    #[test]
+    fn file_rejects_tampered_and_reordered_records() {
+        let (alice, bob) = (KeyPair::generate(), KeyPair::generate());
+        let cipher = alice.file_cipher(&bob.public_key(), "t").unwrap();
+        let peer = bob.file_cipher(&alice.public_key(), "t").unwrap();
+
+        let mut sealer = cipher.for_session(1);
+        let first = sealer.seal(b"one").unwrap();
+        let second = sealer.seal(b"two").unwrap();
+
+        let mut flipped = first.clone();
+        flipped[0] ^= 1;
+        assert!(peer.for_session(1).open(&flipped).is_err());
+        assert!(peer.for_session(1).open(&second).is_err());
+        assert!(peer.for_session(2).open(&first).is_err());
+
+        let mut opener = peer.for_session(1);
+        opener.open(&first).unwrap();
+        assert!(opener.open(&first).is_err());
+    }
+
+    #[test]
+    fn file_keys_differ_per_transfer() {
+        let (alice, bob) = (KeyPair::generate(), KeyPair::generate());
+        let record = alice.file_cipher(&bob.public_key(), "t").unwrap().seal(b"data").unwrap();
+        assert!(bob.file_cipher(&alice.public_key(), "u").unwrap().open(&record).is_err());
+    }
+}
@@ -0,0 +1,188 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+/// How long to wait for a `Delivered` receipt before retransmitting.
+pub const ACK_TIMEOUT_MS: u64 = 2000;
+/// Total transmissions of a message, including the first one.
+pub const MAX_SEND_ATTEMPTS: u32 = 4;
+/// Quiet period after the last chunk before the receiver asks for the missing ones.
+pub const NACK_DELAY_MS: u64 = 500;
+pub const MAX_NACK_ROUNDS: u32 = 5;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    Failed,
+}
+
+impl DeliveryStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryStatus::Pending => "pending",
+            DeliveryStatus::Delivered => "delivered",
+            DeliveryStatus::Failed => "failed",
+        }
+    }
+}
+
+struct PendingDelivery {
+    target_id: u64,
+    target_addr: SocketAddr,
+    /// Serialized datagrams, indexed by chunk index (a single entry for unchunked messages).
+    datagrams: Vec<Vec<u8>>,
+    attempts: u32,
+}
+
+/// What the retransmit timer should do next for a message.
+pub enum RetryAction {
+    /// Acknowledged (or otherwise no longer tracked).
+    Done,
+    Resend { target_addr: SocketAddr, datagrams: Vec<Vec<u8>> },
+    Exhausted { target_id: u64 },
+}
+
+/// Outgoing messages that have been sent but not yet acknowledged.
+#[derive(Clone, Default)]
+pub struct DeliveryTracker {
+    pending: Arc<Mutex<HashMap<String, PendingDelivery>>>,
+}
+
+impl DeliveryTracker {
+    pub fn track(&self, message_id: String, target_id: u64, target_addr: SocketAddr, datagrams: Vec<Vec<u8>>) {
+        self.pending.lock().unwrap().insert(message_id, PendingDelivery {
+            target_id,
+            target_addr,
+            datagrams,
+            attempts: 1,
+        });
+    }
+
+    /// Marks a message delivered, returning its target if it was still pending.
+    pub fn acknowledge(&self, message_id: &str, from: SocketAddr) -> Option<u64> {
+        let mut pending = self.pending.lock().unwrap();
+        let delivery = pending.get(message_id)?;
+        // Only the peer we sent to may acknowledge
+        if delivery.target_addr.ip().to_canonical() != from.ip().to_canonical() {
+            return None;
+        }
+        pending.remove(message_id).map(|delivery| delivery.target_id)
+    }
+
+    /// Called when the ACK timer fires. Chunked messages only resend their last chunk:
+    /// a receiver holding a partial message answers with a `ChunkNack`, and one that
+    /// already completed it answers with a fresh ACK.
+    pub fn next_attempt(&self, message_id: &str) -> RetryAction {
+        let mut pending = self.pending.lock().unwrap();
+        let Some(delivery) = pending.get_mut(message_id) else {
+            return RetryAction::Done;
+        };
+
+        if delivery.attempts >= MAX_SEND_ATTEMPTS {
+            let target_id = delivery.target_id;
+            pending.remove(message_id);
+            return RetryAction::Exhausted { target_id };
+        }
+
+        delivery.attempts += 1;
+        let datagrams = match delivery.datagrams.last() {
+            Some(last) if delivery.datagrams.len() > 1 => vec![last.clone()],
+            _ => delivery.datagrams.clone(),
+        };
+
+        RetryAction::Resend {
+            target_addr: delivery.target_addr,
+            datagrams,
+        }
+    }
+
+    /// The requested chunks of a pending message, for answering a `ChunkNack`.
+    pub fn chunks(&self, message_id: &str, indices: &[u16]) -> Option<(SocketAddr, Vec<Vec<u8>>)> {
+        let pending = self.pending.lock().unwrap();
+        let delivery = pending.get(message_id)?;
+
+        let datagrams = indices
+            .iter()
+            .filter_map(|index| delivery.datagrams.get(*index as usize).cloned())
+            .collect();
+
+        Some((delivery.target_addr, datagrams))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    fn datagrams(count: u8) -> Vec<Vec<u8>> {
+        (0..count).map(|i| vec![i]).collect()
+    }
+
+    #[test]
+    fn acknowledges_once_from_the_target() {
+        let tracker = DeliveryTracker::default();
+        tracker.track("m".into(), 7, addr("192.0.2.1:2425"), datagrams(1));
+        assert_eq!(tracker.acknowledge("m", addr("192.0.2.9:2425")), None);
+        // Any port on the target's address will do
+        assert_eq!(tracker.acknowledge("m", addr("192.0.2.1:40000")), Some(7));
+        assert_eq!(tracker.acknowledge("m", addr("192.0.2.1:2425")), None);
+        assert!(matches!(tracker.next_attempt("m"), RetryAction::Done));
+    }
+
+    #[test]
+    fn acknowledges_from_ipv4_mapped_address() {
+        let tracker = DeliveryTracker::default();
+        tracker.track("m".into(), 7, addr("192.0.2.1:2425"), datagrams(1));
+        assert_eq!(tracker.acknowledge("m", addr("[::ffff:192.0.2.1]:2425")), Some(7));
+    }
+
+    #[test]
+    fn ignores_unknown_messages() {
+        let tracker = DeliveryTracker::default();
+        assert_eq!(tracker.acknowledge("m", addr("192.0.2.1:2425")), None);
+        assert!(tracker.chunks("m", &[0]).is_none());
+    }
+
+    #[test]
+    fn resends_until_attempts_run_out() {
+        let tracker = DeliveryTracker::default();
+        tracker.track("m".into(), 7, addr("192.0.2.1:2425"), datagrams(1));
+        for _ in 1..MAX_SEND_ATTEMPTS {
+            match tracker.next_attempt("m") {
+                RetryAction::Resend { target_addr, datagrams: resent } => {
+                    assert_eq!(target_addr, addr("192.0.2.1:2425"));
+                    assert_eq!(resent, datagrams(1));
+                }
+                _ => panic!("expected a resend"),
+            }
+        }
+        assert!(matches!(tracker.next_attempt("m"), RetryAction::Exhausted { target_id: 7 }));
+        assert!(matches!(tracker.next_attempt("m"), RetryAction::Done));
+    }
+
+    #[test]
+    fn resends_only_the_last_chunk() {
+        let tracker = DeliveryTracker::default();
+        tracker.track("m".into(), 7, addr("192.0.2.1:2425"), datagrams(3));
+        match tracker.next_attempt("m") {
+            RetryAction::Resend { datagrams: resent, .. } => assert_eq!(resent, vec![vec![2]]),
+            _ => panic!("expected a resend"),
+        }
+    }
+
+    #[test]
+    fn answers_nack_with_requested_chunks() {
+        let tracker = DeliveryTracker::default();
+        tracker.track("m".into(), 7, addr("192.0.2.1:2425"), datagrams(3));
+        let (target_addr, resent) = tracker.chunks("m", &[0, 2, 9]).unwrap();
+        assert_eq!(target_addr, addr("192.0.2.1:2425"));
+        assert_eq!(resent, vec![vec![0], vec![2]]);
+    }
+}
@@ -0,0 +1,2501 @@
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+use tokio::sync::broadcast;
+use tokio::time::sleep;
+use log::{debug, error, info, warn};
+
+use crate::announcement::{AnnouncementLimiter, ANNOUNCEMENT_LIMIT, ANNOUNCEMENT_WINDOW_SECS};
+use crate::chunk::{self, Reassembly, CHUNK_SEND_DELAY_MS, DEDUPE_WINDOW_SECS, MAX_MESSAGE_SIZE};
+use crate::compression::{self, Compression, ZSTD_CAPABILITY};
+use crate::crypto::{self, Envelope, FileCipher, KeyPair, PeerKeys};
+use crate::delivery::{DeliveryStatus, DeliveryTracker, RetryAction, ACK_TIMEOUT_MS, MAX_NACK_ROUNDS, NACK_DELAY_MS};
+use crate::error::MessageError;
+use crate::event::{EngineEvent, ReceivedMessage};
+use crate::history::{self, MessageStore, StoredMessage, StoredRoomMessage, StoredTransfer, HISTORY_FILE};
+use crate::identity::{self, Identity, KnownPeers, Trust, IDENTITY_FILE, KNOWN_PEERS_FILE};
+use crate::interface::{InterfaceSelection, NetworkInterface};
+use crate::manifest::{self, ManifestEntry};
+use crate::net::{self, PeerAddresses, SocketManager, BUFFER_SIZE, MAX_SINGLE_PACKET_SIZE};
+use crate::protocol::{self, DiscoveryMessage, PeerCapabilities, User};
+use crate::room::{Room, RoomDeliveries, RoomMember, RoomStore, ROOMS_FILE};
+use crate::queue::{QueueState, QueuedTransfer, TransferDirection, TransferPriority, TransferQueue, DEFAULT_MAX_ACTIVE_TRANSFERS};
+use crate::transfer::{
+    self, Admission, Download, DownloadOutcome, IncomingTransfer, OfferTarget, OutgoingFile, RecipientState, RecipientStatus, TransferControl,
+    TransferRegistry, DEFAULT_TRANSFER_STREAMS, OFFER_TIMEOUT_SECS,
+};
+use crate::{DISCOVERY_PORT, MSG_PORT};
+
+const CLEANUP_INTERVAL_SECS: u64 = 60;
+const EVENT_CHANNEL_CAPACITY: usize = 256;
+
+#[derive(Debug, Clone)]
+pub struct EngineConfig {
+    pub discovery_port: u16,
+    pub message_port: u16,
+    /// Refuse to send or accept plaintext messages and files, which cuts off peers
+    /// that predate encryption.
+    pub require_encryption: bool,
+    /// Where the identity key, the known peers store and message history are kept.
+    pub data_dir: PathBuf,
+    /// Most connections a single file is split over, sending or receiving. Files
+    /// under 32 MiB always use one.
+    pub transfer_streams: u8,
+    /// Transfers moving data at once in each direction; later ones are queued.
+    /// 0 means no limit.
+    pub max_active_transfers: usize,
+    /// Bytes per second all transfers together may use, or `None` for no limit.
+    pub rate_limit: Option<u64>,
+    /// Compress file streams to receivers that support it, unless the files are
+    /// compressed already.
+    pub compress_transfers: bool,
+    /// How many routers discovery multicast may cross; 1 keeps it on the local
+    /// network. Raise it for VLANs routed with multicast forwarding.
+    pub multicast_ttl: u32,
+    /// Names of the interfaces discovery is sent and joined on, whose address is
+    /// advertised in presence. Empty means every interface.
+    pub interfaces: Vec<String>,
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        Self {
+            discovery_port: DISCOVERY_PORT,
+            message_port: MSG_PORT,
+            require_encryption: false,
+            data_dir: dirs::data_local_dir().unwrap_or_else(|| PathBuf::from(".")).join("Roundtable"),
+            transfer_streams: DEFAULT_TRANSFER_STREAMS,
+            max_active_transfers: DEFAULT_MAX_ACTIVE_TRANSFERS,
+            rate_limit: None,
+            compress_transfers: false,
+            multicast_ttl: 1,
+            interfaces: Vec::new(),
+        }
+    }
+}
+
+/// A chat message to send to a single peer.
+#[derive(Debug, Clone)]
+pub struct OutgoingMessage {
+    pub content: String,
+    pub target_ip: String,
+    pub target_port: u16,
+    pub sender_name: String,
+    pub sender_id: u64,
+    pub target_id: u64,
+    pub sender_port: u16,
+}
+
+/// The headless Roundtable node: owns the UDP sockets, runs the listeners and
+/// reports everything it sees through [`RoundtableEngine::subscribe`].
+#[derive(Clone)]
+pub struct RoundtableEngine {
+    config: EngineConfig,
+    sockets: Arc<SocketManager>,
+    transfers: TransferRegistry,
+    queue: TransferQueue,
+    deliveries: DeliveryTracker,
+    keys: Arc<KeyPair>,
+    peer_keys: PeerKeys,
+    peer_addresses: PeerAddresses,
+    peer_capabilities: PeerCapabilities,
+    compress_transfers: Arc<AtomicBool>,
+    identity: Arc<Identity>,
+    known_peers: KnownPeers,
+    history: MessageStore,
+    rooms: RoomStore,
+    room_deliveries: RoomDeliveries,
+    announcements: AnnouncementLimiter<u64>,
+    // Sender IDs cost nothing to make up, so the address is limited as well
+    announcement_sources: AnnouncementLimiter<IpAddr>,
+    events: broadcast::Sender<EngineEvent>,
+}
+
+impl RoundtableEngine {
+    /// Binds the discovery and message sockets and starts the background listeners.
+    pub async fn start(config: EngineConfig) -> Result<Self, MessageError> {
+        let interfaces = InterfaceSelection::new(config.interfaces.clone());
+        let socket_manager = if config.discovery_port == config.message_port {
+            info!("Dev mode: Using single socket on port {}", config.message_port);
+
+            let socket = net::create_socket(config.message_port, config.multicast_ttl, &interfaces).map_err(MessageError::NetworkError)?;
+            let socket_v6 = ipv6_socket(config.message_port, &interfaces);
+            SocketManager::new(Arc::new(socket), None, socket_v6, None, config.discovery_port, config.message_port, interfaces)
+        } else {
+            info!("Release mode: Using discovery port {} and message port {}", config.discovery_port, config.message_port);
+
+            let discovery_socket = Arc::new(net::create_socket(config.discovery_port, config.multicast_ttl, &interfaces).map_err(MessageError::NetworkError)?);
+            let message_socket = Arc::new(net::create_socket(config.message_port, config.multicast_ttl, &interfaces).map_err(MessageError::NetworkError)?);
+
+            let discovery_socket_v6 = ipv6_socket(config.discovery_port, &interfaces);
+            let message_socket_v6 = ipv6_socket(config.message_port, &interfaces);
+
+            SocketManager::new(
+                message_socket,
+                Some(discovery_socket),
+                message_socket_v6,
+                discovery_socket_v6,
+                config.discovery_port,
+                config.message_port,
+                interfaces,
+            )
+        };
+
+        let identity = Identity::load_or_create(&config.data_dir.join(IDENTITY_FILE))
+            .map_err(MessageError::NetworkError)?;
+        let known_peers = KnownPeers::load(config.data_dir.join(KNOWN_PEERS_FILE));
+        let history = MessageStore::open(&config.data_dir.join(HISTORY_FILE)).or_else(|e| {
+            error!("Message history unavailable, keeping it in memory for this session: {}", e);
+            MessageStore::in_memory()
+        })?;
+
+        let (events, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        let engine = Self {
+            queue: TransferQueue::new(config.max_active_transfers, config.rate_limit),
+            compress_transfers: Arc::new(AtomicBool::new(config.compress_transfers)),
+            rooms: RoomStore::load(config.data_dir.join(ROOMS_FILE)),
+            config,
+            sockets: Arc::new(socket_manager),
+            transfers: TransferRegistry::default(),
+            deliveries: DeliveryTracker::default(),
+            keys: Arc::new(KeyPair::generate()),
+            peer_keys: PeerKeys::default(),
+            peer_addresses: PeerAddresses::default(),
+            peer_capabilities: PeerCapabilities::default(),
+            identity: Arc::new(identity),
+            known_peers,
+            history,
+            room_deliveries: RoomDeliveries::default(),
+            announcements: AnnouncementLimiter::default(),
+            announcement_sources: AnnouncementLimiter::default(),
+            events,
+        };
+
+        engine.start_cleanup_task();
+        engine.start_transfer_recorder();
+        engine.start_socket_listeners();
+
+        Ok(engine)
+    }
+
+    pub fn config(&self) -> &EngineConfig {
+        &self.config
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<EngineEvent> {
+        self.events.subscribe()
+    }
+
+    /// Persistent message and file-transfer history, written as traffic passes through.
+    pub fn history(&self) -> &MessageStore {
+        &self.history
+    }
+
+    /// Our long-term identity key, base64, for comparing out of band.
+    pub fn identity_key(&self) -> String {
+        self.identity.public_key()
+    }
+
+    /// Unpins a peer's identity key so the key it presents next is trusted. Returns
+    /// whether the peer was pinned.
+    pub fn forget_peer_identity(&self, user_id: u64) -> bool {
+        self.peer_keys.remove(user_id);
+        self.known_peers.forget(user_id)
+    }
+
+    fn emit(&self, event: EngineEvent) {
+        // No subscribers is not an error for a headless engine.
+        let _ = self.events.send(event);
+    }
+
+    /// Builds the presence record for the local user, advertising our message port,
+    /// public key and capabilities.
+    pub fn local_user(&self, id: u64, name: String, username: String, profile_picture: Option<String>) -> User {
+        User {
+            id,
+            name,
+            username,
+            ip: self.advertised_ip(),
+            port: self.config.message_port,
+            profile_picture,
+            hostname: hostname::get().ok().and_then(|s| s.into_string().ok()),
+            public_key: Some(self.keys.public_key()),
+            identity_key: Some(self.identity.public_key()),
+            // Signed when it is sent, after the caller is done with it
+            signature: None,
+            capabilities: protocol::local_capabilities(),
+            timestamp: 0,
+        }
+    }
+
+    // The chosen interface's address, or none for peers to take the packet's source
+    fn advertised_ip(&self) -> String {
+        self.sockets.interfaces.advertised_ip().map_or_else(|| "0.0.0.0".to_string(), |ip| ip.to_string())
+    }
+
+    /// Every network interface other than loopback, with its addresses and whether
+    /// discovery uses it.
+    pub fn network_interfaces(&self) -> Vec<NetworkInterface> {
+        self.sockets.interfaces.list()
+    }
+
+    /// Sends and joins discovery only on the named interfaces, or on all of them when
+    /// `names` is empty, overriding `EngineConfig::interfaces` for the rest of the session.
+    /// Presence sent from now on advertises the first chosen interface's address.
+    pub fn set_interfaces(&self, names: Vec<String>) -> Result<(), MessageError> {
+        let known = self.sockets.interfaces.list();
+        if let Some(unknown) = names.iter().find(|name| !known.iter().any(|interface| &interface.name == *name)) {
+            return Err(MessageError::InvalidData(format!("Unknown network interface: {}", unknown)));
+        }
+        net::select_interfaces(&self.sockets, names);
+        Ok(())
+    }
+
+    fn start_cleanup_task(&self) {
+        let socket_manager = self.sockets.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(CLEANUP_INTERVAL_SECS));
+            loop {
+                interval.tick().await;
+                socket_manager.chunk_manager.cleanup_old_chunks().await;
+            }
+        });
+    }
+
+    // Transfer outcomes are reported from the transfer tasks, so history follows the events
+    fn start_transfer_recorder(&self) {
+        let history = self.history.clone();
+        let mut events = self.subscribe();
+        tokio::spawn(async move {
+            loop {
+                let update = match events.recv().await {
+                    Ok(EngineEvent::FileTransferSent { transfer_id, .. }) => (transfer_id, "sent", None),
+                    Ok(EngineEvent::FileTransferComplete { transfer_id, file_path, .. }) => (transfer_id, "completed", Some(file_path)),
+                    Ok(EngineEvent::FileTransferInterrupted { transfer_id, .. }) => (transfer_id, "interrupted", None),
+                    Ok(EngineEvent::FileTransferCorrupt { transfer_id, .. }) => (transfer_id, "corrupt", None),
+                    Ok(EngineEvent::FileTransferError { transfer_id, .. }) => (transfer_id, "failed", None),
+                    Ok(EngineEvent::FileTransferCancelled { transfer_id, .. }) => (transfer_id, "cancelled", None),
+                    // Each recipient of a one-to-many offer has its own row
+                    Ok(EngineEvent::FileTransferRecipient { transfer_id, recipient }) => {
+                        (recipient_history_id(&transfer_id, recipient.user_id), recipient.state.as_str(), None)
+                    }
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return,
+                };
+                let (transfer_id, status, file_path) = update;
+                history::log_failure(history.set_transfer_status(&transfer_id, status, file_path.as_deref()));
+            }
+        });
+    }
+
+    fn start_socket_listeners(&self) {
+        let discovery_sockets = [&self.sockets.discovery_socket, &self.sockets.discovery_socket_v6];
+        for discovery_socket in discovery_sockets.into_iter().flatten() {
+            let engine = self.clone();
+            let discovery_socket_clone = discovery_socket.clone();
+
+            tokio::spawn(async move {
+                engine.socket_listener(discovery_socket_clone, true).await;
+            });
+        }
+
+        let message_sockets = [Some(&self.sockets.message_socket), self.sockets.message_socket_v6.as_ref()];
+        for msg_socket in message_sockets.into_iter().flatten() {
+            let engine = self.clone();
+            let msg_socket_clone = msg_socket.clone();
+
+            tokio::spawn(async move {
+                engine.socket_listener(msg_socket_clone, false).await;
+            });
+        }
+    }
+
+    async fn socket_listener(self, socket: Arc<UdpSocket>, is_discovery_only: bool) {
+        loop {
+            let mut buf = self.sockets.buffer_pool.get_buffer();
+            buf.resize(BUFFER_SIZE, 0);
+
+            match socket.recv_from(&mut buf).await {
+                Ok((len, addr)) => {
+                    let data = buf[..len].to_vec();
+                    let engine = self.clone();
+
+                    // Handling message in separate task to avoid blocking
+                    tokio::spawn(async move {
+                        engine.handle_message(&data, addr, is_discovery_only).await;
+                    });
+
+                    self.sockets.buffer_pool.return_buffer(buf);
+                }
+                Err(e) => {
+                    error!(" Socket receive error: {}", e);
+                    self.sockets.buffer_pool.return_buffer(buf);
+                    sleep(Duration::from_millis(100)).await;
+                }
+            }
+        }
+    }
+
+    // message handling
+    async fn handle_message(&self, data: &[u8], addr: SocketAddr, is_discovery_only: bool) {
+        debug!("Received message from {} on {} socket", addr,
+                 if is_discovery_only { "discovery" } else { "message" });
+
+        let message = match serde_json::from_slice::<DiscoveryMessage>(data) {
+            Ok(msg) => msg,
+            Err(e) => {
+                error!("Failed to deserialize message from {}: {}", addr, e);
+                return;
+            }
+        };
+
+        // logginf for file transfer messages
+        match &message {
+            DiscoveryMessage::FileOffer { transfer_id, .. } => {
+                info!("File offer message, transfer ID: {}", transfer_id);
+            },
+            DiscoveryMessage::FileAccept { transfer_id, .. } => {
+                info!("File accept message, transfer ID: {}", transfer_id);
+            },
+            DiscoveryMessage::TransferReady { transfer_id, tcp_port, .. } => {
+                info!("Transfer ready message, transfer ID: {}, TCP port: {}", transfer_id, tcp_port);
+            },
+            _ => {}
+        }
+
+        if let Err(e) = message.validate() {
+            error!("Invalid message received: {}", e);
+            return;
+        }
+
+        match message {
+            DiscoveryMessage::Online(mut user) => {
+                if !self.trust_user(&user, addr) || !self.newer_presence(&user) {
+                    return;
+                }
+                user.ip = presence_ip(&user, addr);
+                self.remember_peer(&user);
+                history::log_failure(self.history.update_peer(user.id, &user.name, &user.ip));
+                self.rooms.update_member(&user);
+                info!("{} ({}:{})", user.name, user.ip, user.port);
+                self.emit(EngineEvent::UserOnline(user));
+            }
+
+            DiscoveryMessage::Response(mut user) => {
+                if !self.trust_user(&user, addr) || !self.newer_presence(&user) {
+                    return;
+                }
+                user.ip = presence_ip(&user, addr);
+                self.remember_peer(&user);
+                history::log_failure(self.history.update_peer(user.id, &user.name, &user.ip));
+                self.rooms.update_member(&user);
+                info!("User response: {} ({})", user.name, user.ip);
+                self.emit(EngineEvent::UserOnline(user));
+            }
+
+            DiscoveryMessage::Offline(mut user) => {
+                if !self.trust_user(&user, addr) || !self.newer_presence(&user) {
+                    return;
+                }
+                user.ip = presence_ip(&user, addr);
+                info!("User offline: {}", user.name);
+                self.emit(EngineEvent::UserOffline(user));
+            }
+
+            DiscoveryMessage::Query => {
+                info!("Received Discovery from : {}", addr);
+                self.emit(EngineEvent::DiscoveryQuery);
+            }
+
+            DiscoveryMessage::Message {
+                content, sender, sender_id, target_id, sender_port, timestamp, message_id, encryption, signature, compression,
+                room_id
+            } => {
+                if is_discovery_only {
+                    return;
+                }
+                if self.sockets.chunk_manager.is_processed(&rejected_key(&message_id)).await {
+                    return;
+                }
+
+                let signed = identity::message_signing_bytes(
+                    &message_id, &sender, sender_id, target_id, timestamp, &content, room_id.as_deref(),
+                );
+                if let Err(e) = self
+                    .verify_sender(sender_id, &signed, signature.as_deref())
+                    .and_then(|_| check_fresh(timestamp, signature.as_deref()))
+                {
+                    self.reject_message(message_id, sender_id, e).await;
+                    return;
+                }
+                if let Err(e) = self.check_room_sender(room_id.as_deref(), sender_id) {
+                    self.reject_message(message_id, sender_id, e).await;
+                    return;
+                }
+
+                let content = match self.open_content(content, encryption.as_ref(), compression, &message_id, sender_id, target_id) {
+                    Ok(content) => content,
+                    Err(e) => {
+                        self.report_undecryptable(message_id, sender_id, e).await;
+                        return;
+                    }
+                };
+
+                // Peers that predate message IDs are deduped the old way
+                let dedupe_key = if message_id.is_empty() {
+                    format!("{}-{}-{}", sender_id, target_id, timestamp)
+                } else {
+                    message_id.clone()
+                };
+                if !self.sockets.chunk_manager.mark_processed(dedupe_key).await {
+                    // Our earlier receipt may have been lost
+                    self.send_delivered(&message_id, target_id, addr).await;
+                    return;
+                }
+
+                info!("Message from {} ({}): {} chars", sender, addr.ip(), content.len());
+                self.send_delivered(&message_id, target_id, addr).await;
+
+                let message_id = if message_id.is_empty() { new_message_id() } else { message_id };
+                self.emit_complete_message(message_id, content, sender, sender_id, target_id, sender_port, addr, room_id);
+            }
+
+            DiscoveryMessage::ChunkedMessage {
+                chunk_id, chunk_index, total_chunks, content, sender, sender_id, target_id, sender_port, timestamp,
+                encryption, signature, compression, room_id
+            } => {
+                if is_discovery_only {
+                    return;
+                }
+                if self.sockets.chunk_manager.is_processed(&rejected_key(&chunk_id)).await {
+                    return;
+                }
+
+                debug!("Chunk {}/{} received for message {}", chunk_index + 1, total_chunks, chunk_id);
+
+                if self.sockets.chunk_manager.is_processed(&chunk_id).await {
+                    self.send_delivered(&chunk_id, target_id, addr).await;
+                    return;
+                }
+
+                let reassembly = match self.sockets.chunk_manager
+                    .reassemble(chunk_id.clone(), chunk_index, total_chunks, content)
+                    .await
+                {
+                    Ok(reassembly) => reassembly,
+                    Err(e) => {
+                        warn!("Dropping chunk from {}: {}", addr, e);
+                        return;
+                    }
+                };
+
+                match reassembly {
+                    Reassembly::Complete(complete) => {
+                        info!("Complete message reassembled: {} chars", complete.len());
+                        let signed = identity::message_signing_bytes(
+                            &chunk_id, &sender, sender_id, target_id, timestamp, &complete, room_id.as_deref(),
+                        );
+                        if let Err(e) = self
+                            .verify_sender(sender_id, &signed, signature.as_deref())
+                            .and_then(|_| check_fresh(timestamp, signature.as_deref()))
+                        {
+                            self.reject_message(chunk_id, sender_id, e).await;
+                            return;
+                        }
+                        if let Err(e) = self.check_room_sender(room_id.as_deref(), sender_id) {
+                            self.reject_message(chunk_id, sender_id, e).await;
+                            return;
+                        }
+                        let complete = match self.open_content(complete, encryption.as_ref(), compression, &chunk_id, sender_id, target_id) {
+                            Ok(complete) => complete,
+                            Err(e) => {
+                                self.report_undecryptable(chunk_id, sender_id, e).await;
+                                return;
+                            }
+                        };
+                        if !self.sockets.chunk_manager.mark_processed(chunk_id.clone()).await {
+                            return;
+                        }
+                        self.send_delivered(&chunk_id, target_id, addr).await;
+                        self.emit_complete_message(chunk_id, complete, sender, sender_id, target_id, sender_port, addr, room_id);
+                    }
+                    Reassembly::Started => self.spawn_nack_watcher(chunk_id, addr),
+                    Reassembly::Pending => {}
+                }
+            }
+
+            DiscoveryMessage::Delivered { message_id, receiver_id } => {
+                if is_discovery_only {
+                    return;
+                }
+                if let Some(target_id) = self.deliveries.acknowledge(&message_id, addr) {
+                    debug!("Message {} delivered to {}", message_id, receiver_id);
+                    self.emit_delivery_status(message_id, target_id, DeliveryStatus::Delivered);
+                }
+            }
+
+            DiscoveryMessage::Read { message_id, reader_id } => {
+                if is_discovery_only {
+                    return;
+                }
+                if !self.read_by(&message_id, reader_id, addr) {
+                    warn!("Ignoring read receipt for {} claiming to be from {} ({})", message_id, reader_id, addr);
+                    return;
+                }
+                debug!("Message {} read by {}", message_id, reader_id);
+                history::log_failure(self.history.set_message_status(&message_id, "read"));
+                self.emit(EngineEvent::MessageRead { message_id, reader_id });
+            }
+
+            DiscoveryMessage::Typing { sender_id, target_id, is_typing } => {
+                if is_discovery_only {
+                    return;
+                }
+                if !self.peer_addresses.is_at(sender_id, addr.ip()) {
+                    debug!("Ignoring typing notice claiming to be from {} ({})", sender_id, addr);
+                    return;
+                }
+                self.emit(EngineEvent::UserTyping { sender_id, target_id, is_typing });
+            }
+
+            DiscoveryMessage::ChunkNack { chunk_id, missing } => {
+                if let Some((target_addr, datagrams)) = self.deliveries.chunks(&chunk_id, &missing) {
+                    info!("Retransmitting {} chunks of {} to {}", datagrams.len(), chunk_id, target_addr);
+                    self.send_datagrams(target_addr, datagrams).await;
+                }
+            }
+
+            DiscoveryMessage::FileOffer { sender, file_name, file_size, transfer_id, hash, file_count, signature } => {
+                info!(
+                    "Received file offer for '{}' from {} ({})",
+                    file_name, sender.name, addr
+                );
+                if !self.trust_user(&sender, addr) {
+                    return;
+                }
+                let signed = identity::offer_signing_bytes(
+                    &transfer_id, &file_name, file_size, hash.as_deref(), file_count, sender.id, sender.timestamp,
+                );
+                if let Err(e) = self.verify_sender(sender.id, &signed, signature.as_deref()) {
+                    warn!("Ignoring offer {} claiming to be from {} ({}): {}", transfer_id, sender.id, addr, e);
+                    return;
+                }
+                // Remembered as long as the sender's record stays fresh
+                if !self.sockets.chunk_manager.mark_processed(offer_key(&transfer_id)).await {
+                    warn!("Ignoring repeated offer {} from {}", transfer_id, addr);
+                    return;
+                }
+                let mut updated_sender = sender;
+                updated_sender.ip = net::peer_ip(addr);
+                self.remember_peer(&updated_sender);
+                self.transfers.register_incoming(transfer_id.clone(), IncomingTransfer {
+                    sender_key: updated_sender.public_key.clone(),
+                    hash: hash.clone(),
+                    size: file_size,
+                    bundle: file_count.is_some(),
+                    sender_ip: Some(updated_sender.ip.clone()),
+                    sender_port: updated_sender.port,
+                    ..Default::default()
+                });
+                history::log_failure(self.history.insert_transfer(&StoredTransfer {
+                    transfer_id: transfer_id.clone(),
+                    peer_id: updated_sender.id,
+                    peer_name: Some(updated_sender.name.clone()),
+                    file_name: file_name.clone(),
+                    file_size,
+                    outgoing: false,
+                    status: "offered".to_string(),
+                    file_path: None,
+                    timestamp: history::now_millis(),
+                }));
+
+                self.emit(EngineEvent::FileOfferReceived {
+                    sender: updated_sender,
+                    file_name,
+                    file_size,
+                    transfer_id,
+                    hash,
+                    file_count,
+                });
+            }
+
+            DiscoveryMessage::FileAccept { receiver, transfer_id, max_streams, signature } => {
+                info!("Received file accept ID : {}", transfer_id);
+
+                if !self.transfers.contains(&transfer_id) {
+                    return;
+                }
+
+                let actual_sender_ip = net::peer_ip(addr);
+                info!("SENDER IP : {}", actual_sender_ip);
+
+                let accepter_port = receiver.port;
+
+                if !self.trust_user(&receiver, addr) {
+                    return;
+                }
+                let signed = identity::accept_signing_bytes(&transfer_id, max_streams, receiver.id, receiver.timestamp);
+                if let Err(e) = self.verify_sender(receiver.id, &signed, signature.as_deref()) {
+                    warn!("Ignoring acceptance of {} claiming to be from {} ({}): {}", transfer_id, receiver.id, addr, e);
+                    return;
+                }
+                let mut updated_receiver = receiver;
+                updated_receiver.ip = actual_sender_ip.clone();
+                self.remember_peer(&updated_receiver);
+                history::log_failure(self.history.set_transfer_status(&transfer_id, "accepted", None));
+                let receiver_id = updated_receiver.id;
+
+                let cipher = match self.file_cipher_for(updated_receiver.public_key.as_deref(), &transfer_id) {
+                    Ok(cipher) => cipher,
+                    Err(e) => {
+                        error!("Cannot serve transfer {} securely to {}: {}", transfer_id, receiver_id, e);
+                        if let Some(recipient) =
+                            self.transfers.set_recipient_state(&transfer_id, receiver_id, RecipientState::Failed, Some(e.to_string()))
+                        {
+                            self.emit(EngineEvent::FileTransferRecipient { transfer_id: transfer_id.clone(), recipient });
+                        }
+                        if self.transfers.settle(&transfer_id) {
+                            self.emit(EngineEvent::transfer_error(&transfer_id, e.to_string()));
+                        }
+                        return;
+                    }
+                };
+                let tcp_port = transfer::get_available_tcp_port().unwrap_or(0);
+                if tcp_port == 0 {
+                    error!("Failed to get available TCP port");
+                    return;
+                }
+                let streams = self.transfers.get(&transfer_id).map_or(1, |source| {
+                    transfer::stream_count(&source, max_streams.min(self.config.transfer_streams))
+                });
+                let compression = self.stream_compression(&updated_receiver, &transfer_id);
+                // One server per transfer; later recipients are pointed at the running one
+                let addr = (actual_sender_ip, accepter_port);
+                let Some((tcp_port, start_server, recipient, admission)) =
+                    self.transfers.accept_recipient(&transfer_id, receiver_id, addr, cipher, streams, compression, tcp_port)
+                else {
+                    warn!("User {} accepted transfer {} without being offered it", receiver_id, transfer_id);
+                    return;
+                };
+
+                self.emit(EngineEvent::FileTransferAccepted {
+                    transfer_id: transfer_id.clone(),
+                    receiver: updated_receiver,
+                });
+                self.emit(EngineEvent::FileTransferRecipient { transfer_id: transfer_id.clone(), recipient });
+
+                if start_server {
+                    self.spawn_file_server(transfer_id.clone(), tcp_port);
+                }
+                if let Err(e) = self.send_transfer_ready(&transfer_id, tcp_port, &admission).await {
+                    error!("Failed to send TransferReady message to {}:{}: {}", admission.addr.0, admission.addr.1, e);
+                }
+            }
+
+            DiscoveryMessage::FileReject { transfer_id, receiver_id } => {
+                info!("Received file reject for transfer ID: {}", transfer_id);
+                let Some(recipient) = self.transfers.reject_recipient(&transfer_id, receiver_id, addr.ip().to_canonical()) else {
+                    warn!("Ignoring reject for {} claiming to be from {:?} ({})", transfer_id, receiver_id, addr);
+                    return;
+                };
+                // The recipient's own row follows the event; the transfer's is rejected
+                // only once nobody is left to take the file
+                let declined = self.transfers.recipients(&transfer_id).is_some_and(|recipients| {
+                    recipients.iter().all(|recipient| recipient.state.is_final() && recipient.state != RecipientState::Sent)
+                });
+                if declined {
+                    history::log_failure(self.history.set_transfer_status(&transfer_id, "rejected", None));
+                }
+                self.emit(EngineEvent::FileTransferRecipient { transfer_id: transfer_id.clone(), recipient });
+                self.emit(EngineEvent::FileTransferRejected { transfer_id: transfer_id.clone(), receiver_id });
+                self.transfers.settle(&transfer_id);
+            }
+
+            DiscoveryMessage::FileCancel { transfer_id, user_id } => {
+                info!("Received cancel for transfer ID: {}", transfer_id);
+                self.handle_cancel(transfer_id, user_id, addr).await;
+            }
+
+            DiscoveryMessage::FilePause { transfer_id, user_id } => {
+                info!("Received pause for transfer ID: {}", transfer_id);
+                self.handle_pause(transfer_id, user_id, addr, true);
+            }
+
+            DiscoveryMessage::FileResume { transfer_id, user_id } => {
+                info!("Received resume for transfer ID: {}", transfer_id);
+                self.handle_pause(transfer_id, user_id, addr, false);
+            }
+
+            DiscoveryMessage::TransferReady { transfer_id, tcp_port, encrypted, streams, token, compression } => {
+                info!("Received transfer ready for ID : {} on port : {}", transfer_id, tcp_port);
+                let token = crypto::decode_token(&token);
+                if token.is_none() {
+                    warn!("Transfer {} came without a valid token; the sender will refuse the download", transfer_id);
+                }
+                let ready = self.transfers.set_incoming_ready(
+                    &transfer_id, addr.ip().to_canonical(), tcp_port, encrypted, streams.max(1), token, compression,
+                );
+                let Some(sender_ip) = ready else {
+                    warn!("Ignoring transfer ready for {} from {}, which did not offer it", transfer_id, addr);
+                    return;
+                };
+                // We always advertise a key, so a sender with one of its own encrypts to us
+                let sender_key = self.transfers.incoming(&transfer_id).and_then(|incoming| incoming.sender_key);
+                if sender_key.is_some() && !encrypted {
+                    let error = plaintext_from_keyed_sender();
+                    error!("Refusing transfer {} from {}: {}", transfer_id, sender_ip, error);
+                    self.emit(EngineEvent::transfer_error(&transfer_id, error.to_string()));
+                    return;
+                }
+
+                self.emit(EngineEvent::FileTransferReady {
+                    transfer_id,
+                    port: tcp_port,
+                    sender_ip,
+                });
+            }
+
+            DiscoveryMessage::Announcement {
+                announcement_id, sender, sender_id, content, timestamp, urgent, identity_key, signature
+            } => {
+                // Our own, looped back
+                if identity_key == self.identity.public_key() {
+                    return;
+                }
+                let ip = net::peer_ip(addr);
+                let signed = identity::announcement_signing_bytes(&announcement_id, &sender, sender_id, timestamp, &content, urgent);
+                if let Err(e) = identity::verify(&identity_key, &signed, &signature) {
+                    warn!("Ignoring announcement {} claiming to be from {} ({}): {}", announcement_id, sender_id, ip, e);
+                    return;
+                }
+                // Checked against a pinned key, but never pinned from here: anyone can
+                // make up IDs and keys, and presence is what introduces a peer
+                if let Some(previous_key) = self.known_peers.pinned_key(sender_id).filter(|pinned| *pinned != identity_key) {
+                    self.report_identity_change(sender_id, &sender, &ip, previous_key, Some(identity_key));
+                    return;
+                }
+                // Older ones could be replays the dedupe window no longer catches
+                if is_stale(timestamp) {
+                    warn!("Ignoring announcement {} from {} with timestamp {}", announcement_id, sender_id, timestamp);
+                    return;
+                }
+                // Broadcast to both ports, so it usually arrives twice
+                if !self.sockets.chunk_manager.mark_processed(announcement_key(&announcement_id)).await {
+                    return;
+                }
+                if self.announcement_sources.admit(addr.ip().to_canonical()).is_err() || self.announcements.admit(sender_id).is_err() {
+                    warn!("Dropping announcement {} from {} ({}): over the rate limit", announcement_id, sender, ip);
+                    return;
+                }
+
+                info!("Announcement from {} ({}){}", sender, ip, if urgent { ", urgent" } else { "" });
+                self.emit(EngineEvent::AnnouncementReceived { announcement_id, sender, sender_id, content, urgent, ip, timestamp });
+            }
+
+            DiscoveryMessage::RoomInvite { room, sender_id, target_id, signature } => {
+                if is_discovery_only {
+                    return;
+                }
+                let signed = identity::room_signing_bytes("invite", sender_id, target_id, &room);
+                if let Err(e) = self.verify_sender(sender_id, &signed, signature.as_deref()) {
+                    warn!("Ignoring invite to room {} claiming to be from {}: {}", room.room_id, sender_id, e);
+                    return;
+                }
+                self.handle_room_invite(room, sender_id, target_id, addr);
+            }
+
+            DiscoveryMessage::RoomUpdate { room, sender_id, target_id, signature } => {
+                if is_discovery_only {
+                    return;
+                }
+                let signed = identity::room_signing_bytes("update", sender_id, target_id, &room);
+                if let Err(e) = self.verify_sender(sender_id, &signed, signature.as_deref()) {
+                    warn!("Ignoring update of room {} claiming to be from {}: {}", room.room_id, sender_id, e);
+                    return;
+                }
+                self.handle_room_update(room, sender_id, target_id, addr);
+            }
+
+            DiscoveryMessage::RoomLeave { room_id, user_id, target_id, version, signature } => {
+                if is_discovery_only {
+                    return;
+                }
+                let signed = identity::room_signing_bytes("leave", user_id, target_id, &(&room_id, version));
+                if let Err(e) = self.verify_sender(user_id, &signed, signature.as_deref()) {
+                    warn!("Ignoring leave of room {} claiming to be from {}: {}", room_id, user_id, e);
+                    return;
+                }
+                self.handle_room_departure(&room_id, user_id, None, target_id, version);
+            }
+
+            DiscoveryMessage::RoomKick { room_id, user_id, sender_id, target_id, version, signature } => {
+                if is_discovery_only {
+                    return;
+                }
+                let signed = identity::room_signing_bytes("kick", sender_id, target_id, &(&room_id, user_id, version));
+                if let Err(e) = self.verify_sender(sender_id, &signed, signature.as_deref()) {
+                    warn!("Ignoring kick from room {} claiming to be from {}: {}", room_id, sender_id, e);
+                    return;
+                }
+                self.handle_room_departure(&room_id, user_id, Some(sender_id), target_id, version);
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn emit_complete_message(
+        &self,
+        message_id: String,
+        content: String,
+        sender: String,
+        sender_id: u64,
+        target_id: u64,
+        sender_port: u16,
+        addr: SocketAddr,
+        room_id: Option<String>,
+    ) {
+        let ip = net::peer_ip(addr);
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        if let Some(room_id) = room_id {
+            history::log_failure(self.history.insert_room_message(&StoredRoomMessage {
+                seq: 0,
+                message_id: message_id.clone(),
+                room_id: room_id.clone(),
+                sender_id,
+                sender_name: sender.clone(),
+                content: content.clone(),
+                timestamp: history::now_millis(),
+                outgoing: false,
+            }));
+            let message = ReceivedMessage { message_id, content, sender, sender_id, target_id, sender_port, ip, timestamp };
+            self.emit(EngineEvent::RoomMessageReceived { room_id, message });
+            return;
+        }
+
+        history::log_failure(self.history.insert_message(
+            &StoredMessage {
+                seq: 0,
+                message_id: message_id.clone(),
+                peer_id: sender_id,
+                sender_id,
+                sender_name: sender.clone(),
+                target_id,
+                content: content.clone(),
+                timestamp: history::now_millis(),
+                outgoing: false,
+                status: "received".to_string(),
+            },
+            Some(&sender),
+            Some(&ip),
+        ));
+
+        self.emit(EngineEvent::MessageReceived(ReceivedMessage {
+            message_id,
+            content,
+            sender,
+            sender_id,
+            target_id,
+            sender_port,
+            ip,
+            timestamp,
+        }));
+    }
+
+    // A read receipt counts only for one of our messages, from its recipient at the
+    // address we know them by
+    fn read_by(&self, message_id: &str, reader_id: u64, addr: SocketAddr) -> bool {
+        match self.history.sent_message(message_id) {
+            Ok(Some((message, peer_ip))) => {
+                message.target_id == reader_id
+                    && peer_ip.as_deref().and_then(net::parse_ip) == Some(addr.ip().to_canonical())
+            }
+            Ok(None) => false,
+            Err(e) => {
+                error!("Failed to look up message {}: {}", message_id, e);
+                false
+            }
+        }
+    }
+
+    fn remember_peer(&self, user: &User) {
+        self.peer_addresses.insert(user.id, &user.ip);
+        if let Some(public_key) = &user.public_key {
+            self.peer_keys.insert(user.id, public_key.clone());
+        }
+        self.peer_capabilities.insert(user.id, user.capabilities.clone());
+    }
+
+    /// Decrypts `content` if it came with an envelope and then decompresses it if it
+    /// was compressed; plaintext passes through unless the config requires encryption.
+    fn open_content(
+        &self,
+        content: String,
+        encryption: Option<&Envelope>,
+        compression: Option<Compression>,
+        message_id: &str,
+        sender_id: u64,
+        target_id: u64,
+    ) -> Result<String, MessageError> {
+        let bytes = match encryption {
+            Some(envelope) => {
+                let aad = crypto::message_aad(message_id, sender_id, target_id);
+                self.keys.open_message_bytes(envelope, &content, &aad)?
+            }
+            None if self.config.require_encryption => {
+                return Err(MessageError::CryptoError("Plaintext message rejected".to_string()));
+            }
+            None if compression.is_some() => compression::from_text(&content)?,
+            None => return Ok(content),
+        };
+        match compression {
+            Some(compression) => compression::decompress_message(compression, &bytes),
+            None => String::from_utf8(bytes).map_err(|_| MessageError::CryptoError("Decrypted content is not UTF-8".to_string())),
+        }
+    }
+
+    // Not acknowledged, so the sender sees the message fail; retransmits are ignored
+    async fn report_undecryptable(&self, message_id: String, sender_id: u64, error: MessageError) {
+        error!("Could not decrypt message {} from {}: {}", message_id, sender_id, error);
+        if self.sockets.chunk_manager.mark_processed(rejected_key(&message_id)).await {
+            self.emit(EngineEvent::MessageDecryptionFailed {
+                message_id,
+                sender_id,
+                error: error.to_string(),
+            });
+        }
+    }
+
+    async fn reject_message(&self, message_id: String, sender_id: u64, error: MessageError) {
+        warn!("Rejected message {} claiming to be from {}: {}", message_id, sender_id, error);
+        self.sockets.chunk_manager.mark_processed(rejected_key(&message_id)).await;
+    }
+
+    /// Checks a user record, as received from `addr`, for its signature and its key
+    /// against the pinned one. Unsigned records are accepted only from users we have
+    /// never pinned.
+    fn trust_user(&self, user: &User, addr: SocketAddr) -> bool {
+        let ip = net::peer_ip(addr);
+        let (Some(identity_key), Some(signature)) = (&user.identity_key, &user.signature) else {
+            let Some(previous_key) = self.known_peers.pinned_key(user.id) else {
+                return true;
+            };
+            self.report_identity_change(user.id, &user.name, &ip, previous_key, None);
+            return false;
+        };
+
+        if let Err(e) = identity::verify(identity_key, &identity::user_signing_bytes(user), signature) {
+            warn!("Ignoring record for {} ({}) from {}: {}", user.name, user.id, ip, e);
+            return false;
+        }
+        if !identity::is_fresh(user.timestamp) {
+            warn!("Ignoring record for {} ({}) from {} signed at {}", user.name, user.id, ip, user.timestamp);
+            return false;
+        }
+
+        match self.known_peers.check(user.id, identity_key, &user.name) {
+            Trust::New => {
+                info!("Pinned identity key for {} ({})", user.name, user.id);
+                true
+            }
+            Trust::Known => true,
+            Trust::Changed { previous_key } => {
+                self.report_identity_change(user.id, &user.name, &ip, previous_key, Some(identity_key.clone()));
+                false
+            }
+        }
+    }
+
+    // Presence no newer than the last we accepted is a copy, or an old record sent
+    // again. Unsigned records have no time to go by.
+    fn newer_presence(&self, user: &User) -> bool {
+        user.signature.is_none() || self.known_peers.advance_presence(user.id, user.timestamp)
+    }
+
+    fn report_identity_change(&self, user_id: u64, name: &str, ip: &str, previous_key: String, new_key: Option<String>) {
+        if !self.known_peers.should_report(user_id, new_key.as_deref()) {
+            return;
+        }
+        warn!("Identity key of {} ({}) at {} does not match the pinned key", name, user_id, ip);
+        self.emit(EngineEvent::PeerIdentityChanged {
+            user_id,
+            name: name.to_string(),
+            ip: ip.to_string(),
+            previous_key,
+            new_key,
+        });
+    }
+
+    /// Messages from pinned users must carry a valid signature by the pinned key.
+    fn verify_sender(&self, sender_id: u64, signed: &[u8], signature: Option<&str>) -> Result<(), MessageError> {
+        let Some(identity_key) = self.known_peers.pinned_key(sender_id) else {
+            return Ok(());
+        };
+        let signature = signature.ok_or_else(|| MessageError::CryptoError("Unsigned message".to_string()))?;
+        identity::verify(&identity_key, signed, signature)
+    }
+
+    // Room messages are accepted only from members of a room we are in
+    fn check_room_sender(&self, room_id: Option<&str>, sender_id: u64) -> Result<(), MessageError> {
+        match room_id {
+            Some(room_id) if !self.rooms.is_member(room_id, sender_id) => {
+                Err(MessageError::InvalidData(format!("Sender is not in room {}", room_id)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn handle_room_invite(&self, mut room: Room, sender_id: u64, target_id: u64, addr: SocketAddr) {
+        if self.rooms.get(&room.room_id).is_some() {
+            self.handle_room_update(room, sender_id, target_id, addr);
+            return;
+        }
+        if !room.is_member(sender_id) || !room.is_member(target_id) {
+            warn!("Ignoring invite to room {} from {}, which does not list both of us", room.room_id, sender_id);
+            return;
+        }
+        // The inviter does not know its own address
+        room.set_member_ip(sender_id, net::peer_ip(addr));
+        info!("Invited to room {} ({}) by {}", room.name, room.room_id, sender_id);
+        self.rooms.insert(room.clone());
+        self.emit(EngineEvent::RoomInvited { room, inviter_id: sender_id });
+    }
+
+    fn handle_room_update(&self, mut room: Room, sender_id: u64, target_id: u64, addr: SocketAddr) {
+        let Some(current) = self.rooms.get(&room.room_id) else {
+            debug!("Ignoring update of room {}, which we are not in", room.room_id);
+            return;
+        };
+        if room.version <= current.version {
+            return;
+        }
+        if !current.is_member(sender_id) || !room.is_member(target_id) {
+            warn!("Ignoring update of room {} from {}", room.room_id, sender_id);
+            return;
+        }
+        // Only the owner removes others or hands ownership on
+        let removes_others = current.others(sender_id).any(|member| !room.is_member(member.id));
+        if sender_id != current.owner_id && (removes_others || room.owner_id != current.owner_id) {
+            warn!("Ignoring update of room {} from {}, who is not its owner", room.room_id, sender_id);
+            return;
+        }
+        room.set_member_ip(sender_id, net::peer_ip(addr));
+        self.rooms.insert(room.clone());
+        self.emit(EngineEvent::RoomUpdated(room));
+    }
+
+    // A member left (`kicked_by` unset) or was removed by the owner
+    fn handle_room_departure(&self, room_id: &str, user_id: u64, kicked_by: Option<u64>, target_id: u64, version: u64) {
+        let Some(current) = self.rooms.get(room_id) else {
+            return;
+        };
+        match kicked_by {
+            Some(owner_id) if owner_id != current.owner_id || owner_id == user_id => {
+                warn!("Ignoring removal of {} from room {} by {}, who is not its owner", user_id, room_id, owner_id);
+                return;
+            }
+            Some(owner_id) if user_id == target_id => {
+                info!("Removed from room {} ({}) by {}", current.name, room_id, owner_id);
+                self.rooms.remove(room_id);
+                self.emit(EngineEvent::RoomKicked { room_id: room_id.to_string(), kicked_by: owner_id });
+                return;
+            }
+            None if user_id == target_id => return,
+            _ => {}
+        }
+
+        let departed = self.rooms.modify(room_id, |room| {
+            let removed = room.remove_member(user_id);
+            room.version = room.version.max(version);
+            Ok(removed)
+        });
+        match departed {
+            Ok((room, true)) => self.emit(EngineEvent::RoomMemberLeft { room, user_id, kicked_by }),
+            Ok((_, false)) => {}
+            Err(e) => warn!("Could not remove {} from room {}: {}", user_id, room_id, e),
+        }
+    }
+
+    // Room changes are signed for each member, as the signature covers the target
+    async fn send_to_members<'a>(
+        &self,
+        members: impl IntoIterator<Item = &'a RoomMember>,
+        message: impl Fn(u64) -> DiscoveryMessage,
+    ) {
+        for member in members {
+            if let Err(e) = self.send_to_peer(&message(member.id), &member.ip, member.port).await {
+                error!("Failed to send room change to {} at {}: {}", member.id, member.ip, e);
+            }
+        }
+    }
+
+    fn room_invite(&self, room: &Room, sender_id: u64, target_id: u64) -> DiscoveryMessage {
+        let signature = self.identity.sign(&identity::room_signing_bytes("invite", sender_id, target_id, room));
+        DiscoveryMessage::RoomInvite { room: room.clone(), sender_id, target_id, signature: Some(signature) }
+    }
+
+    fn room_update(&self, room: &Room, sender_id: u64, target_id: u64) -> DiscoveryMessage {
+        let signature = self.identity.sign(&identity::room_signing_bytes("update", sender_id, target_id, room));
+        DiscoveryMessage::RoomUpdate { room: room.clone(), sender_id, target_id, signature: Some(signature) }
+    }
+
+    /// The stream cipher for a transfer with a peer, or `None` for a plaintext
+    /// stream when the peer has no key and encryption is optional.
+    fn file_cipher_for(&self, peer_key: Option<&str>, transfer_id: &str) -> Result<Option<FileCipher>, MessageError> {
+        match peer_key {
+            Some(peer_key) => self.keys.file_cipher(peer_key, transfer_id).map(Some),
+            None if self.config.require_encryption => {
+                Err(MessageError::CryptoError("Peer does not support encrypted transfers".to_string()))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // Compression for one recipient's stream: only when enabled, the receiver reads
+    // it and not every file is compressed already
+    fn stream_compression(&self, receiver: &User, transfer_id: &str) -> Option<Compression> {
+        if !self.compress_transfers.load(Ordering::Relaxed) || !receiver.capabilities.iter().any(|c| c == ZSTD_CAPABILITY) {
+            return None;
+        }
+        let source = self.transfers.get(transfer_id)?;
+        let worthwhile = source.files.iter().any(|path| !compression::is_compressed_format(path));
+        worthwhile.then_some(Compression::Zstd)
+    }
+
+    fn spawn_file_server(&self, transfer_id: String, tcp_port: u16) {
+        let registry = self.transfers.clone();
+        let queue = self.queue.clone();
+        let events = self.events.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = transfer::setup_file_transfer_server(transfer_id, tcp_port, registry, queue, events).await {
+                error!("File transfer server error: {}", e);
+            }
+        });
+    }
+
+    // Which of our transfers a pause or cancel from `addr` is about: one we send, from
+    // one of its recipients, or one we receive, from its sender
+    fn control_peer(&self, transfer_id: &str, user_id: Option<u64>, addr: SocketAddr) -> Option<ControlPeer> {
+        let ip = net::peer_ip(addr);
+        match user_id {
+            Some(user_id) => self
+                .transfers
+                .is_recipient_at(transfer_id, user_id, &ip)
+                .then_some(ControlPeer::Recipient(user_id)),
+            None => self
+                .transfers
+                .incoming(transfer_id)
+                .filter(|incoming| incoming.sender_ip.as_deref() == Some(ip.as_str()))
+                .map(ControlPeer::Sender),
+        }
+    }
+
+    async fn handle_cancel(&self, transfer_id: String, user_id: Option<u64>, addr: SocketAddr) {
+        match self.control_peer(&transfer_id, user_id, addr) {
+            Some(ControlPeer::Recipient(user_id)) => {
+                if let Some(recipient) = self.transfers.cancel_recipient(&transfer_id, user_id) {
+                    self.emit(EngineEvent::FileTransferRecipient { transfer_id: transfer_id.clone(), recipient });
+                }
+                // A running server reports how its session ended itself
+                if self.transfers.settle(&transfer_id) {
+                    self.emit(EngineEvent::FileTransferCancelled { transfer_id, by_peer: true });
+                }
+            }
+            Some(ControlPeer::Sender(_)) => {
+                if let Some(incoming) = self.transfers.cancel_incoming(&transfer_id) {
+                    discard_download(&incoming).await;
+                    self.emit(EngineEvent::FileTransferCancelled { transfer_id, by_peer: true });
+                }
+            }
+            None => warn!("Ignoring cancel of transfer {} from {}", transfer_id, addr),
+        }
+    }
+
+    fn handle_pause(&self, transfer_id: String, user_id: Option<u64>, addr: SocketAddr, paused: bool) {
+        let (control, recipient_id) = match self.control_peer(&transfer_id, user_id, addr) {
+            Some(ControlPeer::Recipient(user_id)) => (self.transfers.control(&transfer_id), Some(user_id)),
+            Some(ControlPeer::Sender(incoming)) => (Some(incoming.control), None),
+            None => {
+                warn!("Ignoring pause or resume of transfer {} from {}", transfer_id, addr);
+                return;
+            }
+        };
+        let Some(control) = control else {
+            return;
+        };
+
+        if paused && control.pause(recipient_id) {
+            self.emit(EngineEvent::FileTransferPaused { transfer_id, recipient_id, by_peer: true });
+        } else if !paused && control.resume(recipient_id) {
+            self.emit(EngineEvent::FileTransferResumed { transfer_id, recipient_id, by_peer: true });
+        }
+    }
+
+    // Where the sender of an incoming transfer takes control messages
+    fn sender_addr(&self, incoming: &IncomingTransfer) -> Option<(String, u16)> {
+        let port = if incoming.sender_port == 0 { self.config.message_port } else { incoming.sender_port };
+        incoming.sender_ip.clone().map(|ip| (ip, port))
+    }
+
+    async fn send_control(&self, message: &DiscoveryMessage, peers: &[(String, u16)]) {
+        for (ip, port) in peers {
+            if let Err(e) = self.send_to_peer(message, ip, *port).await {
+                error!("Failed to send transfer control message to {}: {}", ip, e);
+            }
+        }
+    }
+
+    async fn send_to_peer(&self, message: &DiscoveryMessage, ip: &str, port: u16) -> Result<usize, MessageError> {
+        net::send_to(&self.sockets, message, net::peer_addr(ip, port)?).await
+    }
+
+    pub async fn broadcast_discovery_query(&self) -> Result<(), MessageError> {
+        info!("Broadcasting Discovery");
+        net::broadcast_message(&self.sockets, &DiscoveryMessage::Query).await
+    }
+
+    pub async fn broadcast_presence(&self, mut user: User) -> Result<(), MessageError> {
+        user.validate()?;
+        self.identity.sign_user(&mut user);
+        net::broadcast_message(&self.sockets, &DiscoveryMessage::Online(user)).await
+    }
+
+    pub async fn broadcast_offline(&self, user_id: u64) -> Result<(), MessageError> {
+        let mut user_to_remove = User {
+            id: user_id,
+            name: "Roundtable User".to_string(),
+            username: "".to_string(),
+            ip: self.advertised_ip(),
+            port: self.config.message_port,
+            profile_picture: None,
+            hostname: None,
+            public_key: None,
+            identity_key: None,
+            signature: None,
+            capabilities: Vec::new(),
+            timestamp: 0,
+        };
+        self.identity.sign_user(&mut user_to_remove);
+        net::broadcast_message(&self.sockets, &DiscoveryMessage::Offline(user_to_remove)).await
+    }
+
+    /// Sends a chat message as a single datagram when it fits, falling back to chunks.
+    /// Long content is compressed if the target supports it, and content is encrypted
+    /// whenever the target has advertised a key.
+    /// Returns the message ID; delivery is reported later as `MessageDeliveryStatus`.
+    pub async fn send_message(&self, message: OutgoingMessage) -> Result<String, MessageError> {
+        check_content(&message.content)?;
+        let message_id = new_message_id();
+
+        history::log_failure(self.history.insert_message(
+            &StoredMessage {
+                seq: 0,
+                message_id: message_id.clone(),
+                peer_id: message.target_id,
+                sender_id: message.sender_id,
+                sender_name: message.sender_name.clone(),
+                target_id: message.target_id,
+                content: message.content.clone(),
+                timestamp: history::now_millis(),
+                outgoing: true,
+                status: DeliveryStatus::Pending.as_str().to_string(),
+            },
+            None,
+            Some(&message.target_ip),
+        ));
+
+        self.deliver(message, message_id, None).await
+    }
+
+    // Sends one message, or one member's copy of a room message, to its target
+    async fn deliver(&self, mut message: OutgoingMessage, message_id: String, room_id: Option<&str>) -> Result<String, MessageError> {
+        info!("Sending message: {} chars to {}:{}", message.content.len(), message.target_ip, message.target_port);
+
+        let target_addr = net::peer_addr(&message.target_ip, message.target_port)?;
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)
+            .unwrap().as_secs();
+
+        // Compressed before it is encrypted, as ciphertext does not compress
+        let compressed = if self.peer_capabilities.supports(message.target_id, ZSTD_CAPABILITY) {
+            compression::compress_message(&message.content)
+        } else {
+            None
+        };
+        let compression = compressed.as_ref().map(|compressed| {
+            debug!("Compressed message {} from {} to {} bytes", message_id, message.content.len(), compressed.len());
+            Compression::Zstd
+        });
+
+        let encryption = match self.peer_keys.get(message.target_id) {
+            Some(peer_key) => {
+                let aad = crypto::message_aad(&message_id, message.sender_id, message.target_id);
+                let plaintext = compressed.as_deref().unwrap_or(message.content.as_bytes());
+                let (ciphertext, envelope) = self.keys.seal_message_bytes(&peer_key, plaintext, &aad)?;
+                message.content = ciphertext;
+                Some(envelope)
+            }
+            None if self.config.require_encryption => {
+                return Err(MessageError::CryptoError(format!("No encryption key known for user {}", message.target_id)));
+            }
+            None => {
+                if let Some(compressed) = &compressed {
+                    message.content = compression::to_text(compressed);
+                }
+                None
+            }
+        };
+
+        let signature = self.identity.sign(&identity::message_signing_bytes(
+            &message_id,
+            &message.sender_name,
+            message.sender_id,
+            message.target_id,
+            timestamp,
+            &message.content,
+            room_id,
+        ));
+
+        let single_msg = DiscoveryMessage::Message {
+            content: message.content.clone(),
+            sender: message.sender_name.clone(),
+            sender_id: message.sender_id,
+            target_id: message.target_id,
+            sender_port: message.sender_port,
+            timestamp,
+            message_id: message_id.clone(),
+            encryption: encryption.clone(),
+            signature: Some(signature.clone()),
+            compression,
+            room_id: room_id.map(str::to_string),
+        };
+
+        if let Ok(message_bytes) = serde_json::to_vec(&single_msg) {
+            if message_bytes.len() <= MAX_SINGLE_PACKET_SIZE {
+                // Track before sending so a fast ACK is never missed
+                self.track_delivery(&message_id, message.target_id, target_addr, vec![message_bytes.clone()]);
+
+                match self.sockets.socket_for(&target_addr).send_to(&message_bytes, target_addr).await {
+                    Ok(bytes_sent) => {
+                        info!("Single message sent: {} bytes", bytes_sent);
+                        return Ok(message_id);
+                    }
+                    Err(e) => {
+                        info!("Single message failed ({}), trying chunked approach", e);
+                    }
+                }
+            }
+        }
+
+        self.send_chunked_message(message, encryption, compression, room_id, signature, timestamp, target_addr, message_id).await
+    }
+
+    // Chunked message sending with better performance
+    #[allow(clippy::too_many_arguments)]
+    async fn send_chunked_message(
+        &self,
+        message: OutgoingMessage,
+        encryption: Option<Envelope>,
+        compression: Option<Compression>,
+        room_id: Option<&str>,
+        signature: String,
+        timestamp: u64,
+        target_addr: SocketAddr,
+        chunk_id: String,
+    ) -> Result<String, MessageError> {
+        let chunks = chunk::split_message(&message.content);
+        let total_chunks = chunks.len() as u16;
+        info!("Sending {} chunks for message of {} chars", total_chunks, message.content.len());
+
+        let mut datagrams = Vec::with_capacity(chunks.len());
+        for (index, chunk) in chunks.into_iter().enumerate() {
+            let chunked_msg = DiscoveryMessage::ChunkedMessage {
+                chunk_id: chunk_id.clone(),
+                chunk_index: index as u16,
+                total_chunks,
+                content: chunk,
+                sender: message.sender_name.clone(),
+                sender_id: message.sender_id,
+                target_id: message.target_id,
+                sender_port: message.sender_port,
+                timestamp,
+                encryption: encryption.clone(),
+                signature: Some(signature.clone()),
+                compression,
+                room_id: room_id.map(str::to_string),
+            };
+
+            datagrams.push(serde_json::to_vec(&chunked_msg)
+                .map_err(MessageError::SerializationError)?);
+        }
+
+        self.track_delivery(&chunk_id, message.target_id, target_addr, datagrams.clone());
+        self.send_datagrams(target_addr, datagrams).await;
+
+        info!("Chunked message sent ({} chunks)", total_chunks);
+        Ok(chunk_id)
+    }
+
+    /// Sends datagrams to one peer, at most ten in flight and paced by `CHUNK_SEND_DELAY_MS`.
+    async fn send_datagrams(&self, target_addr: SocketAddr, datagrams: Vec<Vec<u8>>) {
+        let total = datagrams.len();
+        let semaphore = Arc::new(tokio::sync::Semaphore::new(10));
+        let mut tasks = Vec::new();
+
+        for (index, bytes) in datagrams.into_iter().enumerate() {
+            let socket = self.sockets.socket_for(&target_addr).clone();
+            let permit = semaphore.clone().acquire_owned().await.unwrap();
+
+            let task = tokio::spawn(async move {
+                let _permit = permit;
+
+                let result = socket.send_to(&bytes, target_addr).await;
+                if let Err(e) = result {
+                    error!("Failed to send chunk {}: {}", index, e);
+                } else {
+                    debug!("Sent chunk {}/{}", index + 1, total);
+                }
+
+                sleep(Duration::from_millis(CHUNK_SEND_DELAY_MS)).await;
+            });
+
+            tasks.push(task);
+        }
+
+        for task in tasks {
+            let _ = task.await;
+        }
+    }
+
+    /// Registers an outgoing message and retransmits it until it is acknowledged or
+    /// `MAX_SEND_ATTEMPTS` is used up. Peers that predate ACKs always end up `Failed`.
+    fn track_delivery(&self, message_id: &str, target_id: u64, target_addr: SocketAddr, datagrams: Vec<Vec<u8>>) {
+        self.deliveries.track(message_id.to_string(), target_id, target_addr, datagrams);
+        self.emit_delivery_status(message_id.to_string(), target_id, DeliveryStatus::Pending);
+
+        let engine = self.clone();
+        let message_id = message_id.to_string();
+        tokio::spawn(async move {
+            loop {
+                sleep(Duration::from_millis(ACK_TIMEOUT_MS)).await;
+
+                match engine.deliveries.next_attempt(&message_id) {
+                    RetryAction::Done => return,
+                    RetryAction::Resend { target_addr, datagrams } => {
+                        info!("No ACK for {}, retransmitting", message_id);
+                        engine.send_datagrams(target_addr, datagrams).await;
+                    }
+                    RetryAction::Exhausted { target_id } => {
+                        error!("Message {} was not acknowledged, giving up", message_id);
+                        engine.emit_delivery_status(message_id, target_id, DeliveryStatus::Failed);
+                        return;
+                    }
+                }
+            }
+        });
+    }
+
+    fn emit_delivery_status(&self, message_id: String, target_id: u64, status: DeliveryStatus) {
+        if let Some((room_id, room_message_id)) = self.room_deliveries.resolve(&message_id, status) {
+            self.emit(EngineEvent::RoomMessageDeliveryStatus {
+                room_id,
+                message_id: room_message_id,
+                member_id: target_id,
+                status,
+            });
+            return;
+        }
+        history::log_failure(self.history.set_message_status(&message_id, status.as_str()));
+        self.emit(EngineEvent::MessageDeliveryStatus { message_id, target_id, status });
+    }
+
+    async fn send_delivered(&self, message_id: &str, receiver_id: u64, addr: SocketAddr) {
+        if message_id.is_empty() {
+            return;
+        }
+
+        let receipt = DiscoveryMessage::Delivered {
+            message_id: message_id.to_string(),
+            receiver_id,
+        };
+        if let Err(e) = net::send_to(&self.sockets, &receipt, addr).await {
+            error!("Failed to send delivery receipt for {} to {}: {}", message_id, addr, e);
+        }
+    }
+
+    /// Asks the sender for missing chunks whenever a partial message goes quiet.
+    fn spawn_nack_watcher(&self, chunk_id: String, addr: SocketAddr) {
+        let engine = self.clone();
+        tokio::spawn(async move {
+            let delay = Duration::from_millis(NACK_DELAY_MS);
+            let mut rounds = 0;
+
+            while rounds < MAX_NACK_ROUNDS {
+                sleep(delay).await;
+
+                let Some((missing, quiet_for)) = engine.sockets.chunk_manager.missing_chunks(&chunk_id).await else {
+                    return;
+                };
+                if quiet_for < delay || missing.is_empty() {
+                    continue;
+                }
+
+                rounds += 1;
+                info!("Requesting {} missing chunks of {}", missing.len(), chunk_id);
+                let nack = DiscoveryMessage::ChunkNack { chunk_id: chunk_id.clone(), missing };
+                if let Err(e) = net::send_to(&engine.sockets, &nack, addr).await {
+                    error!("Failed to send NACK for {}: {}", chunk_id, e);
+                }
+            }
+        });
+    }
+
+    /// Tells the original sender that we have read `message_id`.
+    pub async fn send_read_receipt(
+        &self,
+        message_id: String,
+        reader_id: u64,
+        target_ip: &str,
+        target_port: u16,
+    ) -> Result<(), MessageError> {
+        let receipt = DiscoveryMessage::Read { message_id, reader_id };
+        receipt.validate()?;
+
+        let target_addr = net::peer_addr(target_ip, target_port)?;
+        net::send_to(&self.sockets, &receipt, target_addr).await?;
+        Ok(())
+    }
+
+    /// Starts or stops our "is typing" indicator in a peer's chat. Receivers should
+    /// expire it themselves in case the stop never arrives.
+    pub async fn send_typing(
+        &self,
+        sender_id: u64,
+        target_id: u64,
+        target_ip: &str,
+        target_port: u16,
+        is_typing: bool,
+    ) -> Result<(), MessageError> {
+        let typing = DiscoveryMessage::Typing { sender_id, target_id, is_typing };
+
+        let target_addr = net::peer_addr(target_ip, target_port)?;
+        net::send_to(&self.sockets, &typing, target_addr).await?;
+        Ok(())
+    }
+
+    /// Broadcasts a signed notice to everyone on the subnet, raising
+    /// `announcement-received` on every other engine. A sender may make
+    /// `ANNOUNCEMENT_LIMIT` announcements per `ANNOUNCEMENT_WINDOW_SECS`.
+    pub async fn send_announcement(&self, sender_id: u64, sender_name: String, content: String, urgent: bool) -> Result<String, MessageError> {
+        let announcement_id = new_message_id();
+        let timestamp = now_secs();
+        let signature = self.identity.sign(&identity::announcement_signing_bytes(
+            &announcement_id, &sender_name, sender_id, timestamp, &content, urgent,
+        ));
+        let announcement = DiscoveryMessage::Announcement {
+            announcement_id: announcement_id.clone(),
+            sender: sender_name,
+            sender_id,
+            content,
+            timestamp,
+            urgent,
+            identity_key: self.identity.public_key(),
+            signature,
+        };
+        announcement.validate()?;
+
+        if let Err(wait) = self.announcements.admit(sender_id) {
+            return Err(MessageError::InvalidData(format!(
+                "At most {} announcements per {} seconds; try again in {} seconds",
+                ANNOUNCEMENT_LIMIT,
+                ANNOUNCEMENT_WINDOW_SECS,
+                wait.as_secs() + 1
+            )));
+        }
+        info!("Broadcasting announcement {}", announcement_id);
+        net::broadcast_message(&self.sockets, &announcement).await?;
+        Ok(announcement_id)
+    }
+
+    /// The rooms we are in, most recently created first.
+    pub fn rooms(&self) -> Vec<Room> {
+        self.rooms.list()
+    }
+
+    pub fn room(&self, room_id: &str) -> Option<Room> {
+        self.rooms.get(room_id)
+    }
+
+    /// Creates a room owned by the local user `creator_id` and invites `members`.
+    pub async fn create_room(
+        &self,
+        creator_id: u64,
+        creator_name: String,
+        name: String,
+        members: Vec<RoomMember>,
+    ) -> Result<Room, MessageError> {
+        let mut room = Room {
+            room_id: uuid::Uuid::new_v4().to_string(),
+            name: name.trim().to_string(),
+            owner_id: creator_id,
+            // Members learn our address from the packets we send
+            members: vec![RoomMember { id: creator_id, name: creator_name, ip: "0.0.0.0".to_string(), port: self.config.message_port }],
+            version: 1,
+            created_at: history::now_millis(),
+        };
+        room.add_members(members);
+        room.validate()?;
+        self.rooms.insert(room.clone());
+        info!("Created room {} ({}) with {} members", room.name, room.room_id, room.members.len());
+
+        self.send_to_members(room.others(creator_id), |target_id| self.room_invite(&room, creator_id, target_id)).await;
+        Ok(room)
+    }
+
+    /// Adds members to a room; they are sent the room and everyone else the change.
+    pub async fn invite_to_room(&self, room_id: &str, sender_id: u64, members: Vec<RoomMember>) -> Result<Room, MessageError> {
+        let (room, added) = self.rooms.modify(room_id, |room| {
+            ensure_room_member(room, sender_id)?;
+            let added = room.add_members(members);
+            if added.is_empty() {
+                return Err(MessageError::InvalidData("Everyone invited is already in the room".to_string()));
+            }
+            room.version += 1;
+            Ok(added)
+        })?;
+
+        let added_ids: HashSet<u64> = added.iter().map(|member| member.id).collect();
+        self.send_to_members(&added, |target_id| self.room_invite(&room, sender_id, target_id)).await;
+        self.send_to_members(
+            room.others(sender_id).filter(|member| !added_ids.contains(&member.id)),
+            |target_id| self.room_update(&room, sender_id, target_id),
+        )
+        .await;
+        Ok(room)
+    }
+
+    pub async fn rename_room(&self, room_id: &str, sender_id: u64, name: String) -> Result<Room, MessageError> {
+        let (room, ()) = self.rooms.modify(room_id, |room| {
+            ensure_room_member(room, sender_id)?;
+            room.name = name.trim().to_string();
+            room.version += 1;
+            Ok(())
+        })?;
+
+        self.send_to_members(room.others(sender_id), |target_id| self.room_update(&room, sender_id, target_id)).await;
+        Ok(room)
+    }
+
+    /// Tells the other members that `user_id` left and forgets the room. Its history
+    /// is kept.
+    pub async fn leave_room(&self, room_id: &str, user_id: u64) -> Result<(), MessageError> {
+        let room = self.rooms.get(room_id).ok_or_else(|| MessageError::InvalidData(format!("Unknown room {}", room_id)))?;
+        ensure_room_member(&room, user_id)?;
+
+        let version = room.version + 1;
+        self.send_to_members(room.others(user_id), |target_id| {
+            let signature = self.identity.sign(&identity::room_signing_bytes("leave", user_id, target_id, &(room_id, version)));
+            DiscoveryMessage::RoomLeave { room_id: room_id.to_string(), user_id, target_id, version, signature: Some(signature) }
+        })
+        .await;
+        self.rooms.remove(room_id);
+        Ok(())
+    }
+
+    /// Removes a member; only the room's owner may.
+    pub async fn kick_from_room(&self, room_id: &str, sender_id: u64, user_id: u64) -> Result<Room, MessageError> {
+        let (room, kicked) = self.rooms.modify(room_id, |room| {
+            if room.owner_id != sender_id {
+                return Err(MessageError::InvalidData("Only the room's owner can remove members".to_string()));
+            }
+            if user_id == sender_id {
+                return Err(MessageError::InvalidData("Leave the room instead".to_string()));
+            }
+            let kicked = room
+                .others(sender_id)
+                .find(|member| member.id == user_id)
+                .cloned()
+                .ok_or_else(|| MessageError::InvalidData(format!("User {} is not in room {}", user_id, room_id)))?;
+            room.remove_member(user_id);
+            room.version += 1;
+            Ok(kicked)
+        })?;
+
+        let version = room.version;
+        self.send_to_members(room.others(sender_id).chain([&kicked]), |target_id| {
+            let signature =
+                self.identity.sign(&identity::room_signing_bytes("kick", sender_id, target_id, &(room_id, user_id, version)));
+            DiscoveryMessage::RoomKick { room_id: room_id.to_string(), user_id, sender_id, target_id, version, signature: Some(signature) }
+        })
+        .await;
+        Ok(room)
+    }
+
+    /// Sends every other member their own copy of a message, encrypted for them.
+    /// Returns the message ID; each copy's delivery is reported against it as
+    /// `RoomMessageDeliveryStatus`.
+    pub async fn send_room_message(&self, room_id: &str, sender_id: u64, sender_name: String, content: String) -> Result<String, MessageError> {
+        check_content(&content)?;
+        let room = self.rooms.get(room_id).ok_or_else(|| MessageError::InvalidData(format!("Unknown room {}", room_id)))?;
+        ensure_room_member(&room, sender_id)?;
+
+        let message_id = new_message_id();
+        history::log_failure(self.history.insert_room_message(&StoredRoomMessage {
+            seq: 0,
+            message_id: message_id.clone(),
+            room_id: room_id.to_string(),
+            sender_id,
+            sender_name: sender_name.clone(),
+            content: content.clone(),
+            timestamp: history::now_millis(),
+            outgoing: true,
+        }));
+
+        for member in room.others(sender_id) {
+            let copy_id = new_message_id();
+            self.room_deliveries.track(copy_id.clone(), room_id, &message_id);
+            let copy = OutgoingMessage {
+                content: content.clone(),
+                target_ip: member.ip.clone(),
+                target_port: member.port,
+                sender_name: sender_name.clone(),
+                sender_id,
+                target_id: member.id,
+                sender_port: self.config.message_port,
+            };
+            if let Err(e) = self.deliver(copy, copy_id.clone(), Some(room_id)).await {
+                error!("Failed to send room message {} to {}: {}", message_id, member.id, e);
+                self.emit_delivery_status(copy_id, member.id, DeliveryStatus::Failed);
+            }
+        }
+        Ok(message_id)
+    }
+
+    /// Registers `file` for transfer and offers it to the peer at `target_ip:target_port`.
+    pub async fn offer_file(
+        &self,
+        sender: User,
+        target_id: u64,
+        target_ip: &str,
+        target_port: u16,
+        file: OutgoingFile,
+    ) -> Result<(), MessageError> {
+        info!(
+            "Initiating file offer '{}' (path: {}) to {}:{}",
+            file.file_name, file.path.display(), target_ip, target_port
+        );
+
+        // Hashing reads the whole file, so it happens before anything is registered
+        let hash = transfer::hash_file(file.path.clone()).await.map_err(MessageError::NetworkError)?;
+
+        let target = OfferTarget { id: target_id, ip: target_ip.to_string(), port: target_port };
+        self.transfers.register(file.transfer_id.clone(), file.path.clone(), &[target]);
+        history::log_failure(self.history.insert_transfer(&StoredTransfer {
+            transfer_id: file.transfer_id.clone(),
+            peer_id: target_id,
+            peer_name: None,
+            file_name: file.file_name.clone(),
+            file_size: file.file_size,
+            outgoing: true,
+            status: "offered".to_string(),
+            file_path: Some(file.path.to_string_lossy().into_owned()),
+            timestamp: history::now_millis(),
+        }));
+        info!("Registered transfer : {} -> {}", &file.transfer_id, &file.file_name);
+
+        let offer_message = self.file_offer(sender, file.transfer_id, file.file_name, file.file_size, hash, None);
+
+        let target_addr = net::peer_addr(target_ip, target_port)?;
+        net::send_to(&self.sockets, &offer_message, target_addr).await?;
+        info!("FT Offer Sent to : {}", target_addr);
+        Ok(())
+    }
+
+    /// Offers several files and folders as one transfer. Folders are sent with
+    /// their contents, keeping their own name as the top-level directory; the
+    /// receiver recreates the tree under the directory it downloads to.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn offer_files(
+        &self,
+        sender: User,
+        target_id: u64,
+        target_ip: &str,
+        target_port: u16,
+        transfer_id: String,
+        name: String,
+        paths: Vec<PathBuf>,
+    ) -> Result<(), MessageError> {
+        info!("Initiating offer '{}' of {} path(s) to {}:{}", name, paths.len(), target_ip, target_port);
+
+        let entries = tokio::task::spawn_blocking(move || manifest::build(&paths))
+            .await
+            .map_err(|e| MessageError::NetworkError(std::io::Error::other(e)))?
+            .map_err(MessageError::NetworkError)?;
+        let (files, entries): (Vec<PathBuf>, Vec<ManifestEntry>) = entries.into_iter().unzip();
+        let total_size = entries.iter().map(|entry| entry.size).sum();
+        let file_count = entries.len() as u64;
+        let hash = manifest::digest(&manifest::to_bytes(&entries));
+
+        let target = OfferTarget { id: target_id, ip: target_ip.to_string(), port: target_port };
+        self.transfers.register_bundle(transfer_id.clone(), files, entries, &[target]);
+        history::log_failure(self.history.insert_transfer(&StoredTransfer {
+            transfer_id: transfer_id.clone(),
+            peer_id: target_id,
+            peer_name: None,
+            file_name: name.clone(),
+            file_size: total_size,
+            outgoing: true,
+            status: "offered".to_string(),
+            file_path: None,
+            timestamp: history::now_millis(),
+        }));
+        info!("Registered transfer : {} -> {} ({} files)", &transfer_id, &name, file_count);
+
+        let offer_message = self.file_offer(sender, transfer_id, name, total_size, hash, Some(file_count));
+
+        let target_addr = net::peer_addr(target_ip, target_port)?;
+        net::send_to(&self.sockets, &offer_message, target_addr).await?;
+        info!("FT Offer Sent to : {}", target_addr);
+        Ok(())
+    }
+
+    /// Offers one file to several peers at once. The file is hashed once and a single
+    /// transfer server, started when the first of them accepts, serves everyone who
+    /// accepts. Each recipient's progress is reported with `FileTransferRecipient`
+    /// and can be listed with [`RoundtableEngine::transfer_recipients`]; those who do
+    /// not answer within ten minutes are given up on.
+    pub async fn offer_file_to_many(&self, sender: User, targets: Vec<OfferTarget>, file: OutgoingFile) -> Result<(), MessageError> {
+        if targets.is_empty() {
+            return Err(MessageError::InvalidData("No recipients to offer the file to".to_string()));
+        }
+        info!(
+            "Initiating file offer '{}' (path: {}) to {} recipients",
+            file.file_name, file.path.display(), targets.len()
+        );
+
+        let hash = transfer::hash_file(file.path.clone()).await.map_err(MessageError::NetworkError)?;
+
+        self.transfers.register(file.transfer_id.clone(), file.path.clone(), &targets);
+        for target in &targets {
+            history::log_failure(self.history.insert_transfer(&StoredTransfer {
+                transfer_id: recipient_history_id(&file.transfer_id, target.id),
+                peer_id: target.id,
+                peer_name: None,
+                file_name: file.file_name.clone(),
+                file_size: file.file_size,
+                outgoing: true,
+                status: "offered".to_string(),
+                file_path: Some(file.path.to_string_lossy().into_owned()),
+                timestamp: history::now_millis(),
+            }));
+        }
+        info!("Registered transfer : {} -> {}", &file.transfer_id, &file.file_name);
+
+        let transfer_id = file.transfer_id.clone();
+        let offer_message = self.file_offer(sender, file.transfer_id, file.file_name, file.file_size, hash, None);
+
+        for target in &targets {
+            if let Err(e) = self.send_to_peer(&offer_message, &target.ip, target.port).await {
+                error!("Failed to send offer to {}: {}", target.ip, e);
+                if let Some(recipient) =
+                    self.transfers.set_recipient_state(&transfer_id, target.id, RecipientState::Failed, Some(e.to_string()))
+                {
+                    self.emit(EngineEvent::FileTransferRecipient { transfer_id: transfer_id.clone(), recipient });
+                }
+            }
+        }
+        if self.transfers.settle(&transfer_id) {
+            return Err(MessageError::InvalidData("The offer could not be sent to any recipient".to_string()));
+        }
+
+        let engine = self.clone();
+        tokio::spawn(async move {
+            sleep(Duration::from_secs(OFFER_TIMEOUT_SECS)).await;
+            for recipient in engine.transfers.expire_offers(&transfer_id) {
+                info!("Recipient {} did not answer offer {}", recipient.user_id, transfer_id);
+                engine.emit(EngineEvent::FileTransferRecipient { transfer_id: transfer_id.clone(), recipient });
+            }
+            if engine.transfers.settle(&transfer_id) {
+                engine.emit(EngineEvent::transfer_error(&transfer_id, "Nobody accepted the offer in time"));
+            }
+        });
+        Ok(())
+    }
+
+    // Signs our record and the offer itself, so neither can be altered or sent again
+    fn file_offer(
+        &self,
+        mut sender: User,
+        transfer_id: String,
+        file_name: String,
+        file_size: u64,
+        hash: String,
+        file_count: Option<u64>,
+    ) -> DiscoveryMessage {
+        self.identity.sign_user(&mut sender);
+        let signed = identity::offer_signing_bytes(
+            &transfer_id, &file_name, file_size, Some(&hash), file_count, sender.id, sender.timestamp,
+        );
+        DiscoveryMessage::FileOffer {
+            sender,
+            file_name,
+            file_size,
+            transfer_id,
+            hash: Some(hash),
+            file_count,
+            signature: Some(self.identity.sign(&signed)),
+        }
+    }
+
+    /// Where each recipient of an outgoing transfer stands, or `None` once the
+    /// transfer is finished.
+    pub fn transfer_recipients(&self, transfer_id: &str) -> Option<Vec<RecipientStatus>> {
+        self.transfers.recipients(transfer_id)
+    }
+
+    /// Accepts or rejects an offer; without a target the answer goes to the address
+    /// the offer came from, and fails if we were never offered the transfer.
+    pub async fn respond_to_file_offer(
+        &self,
+        transfer_id: String,
+        accepted: bool,
+        mut receiver: User,
+        target: Option<(String, u16)>,
+    ) -> Result<(), MessageError> {
+        let (ip, port) = target
+            .or_else(|| self.transfers.incoming(&transfer_id).and_then(|incoming| self.sender_addr(&incoming)))
+            .ok_or_else(|| MessageError::InvalidData(format!("No sender known for transfer {}", transfer_id)))?;
+        let target_addr = net::peer_addr(&ip, port)?;
+
+        self.identity.sign_user(&mut receiver);
+        let response_message = if accepted {
+            self.transfers.set_incoming_receiver(&transfer_id, receiver.id);
+            let max_streams = self.config.transfer_streams;
+            let signed = identity::accept_signing_bytes(&transfer_id, max_streams, receiver.id, receiver.timestamp);
+            DiscoveryMessage::FileAccept {
+                receiver,
+                transfer_id,
+                max_streams,
+                signature: Some(self.identity.sign(&signed)),
+            }
+        } else {
+            DiscoveryMessage::FileReject {
+                transfer_id,
+                receiver_id: Some(receiver.id),
+            }
+        };
+
+        info!("Sending response to: {}", target_addr);
+        net::send_to(&self.sockets, &response_message, target_addr).await?;
+        info!("File offer response sent");
+        Ok(())
+    }
+
+    /// Tells every recipient that accepted a transfer, and has not finished it, where
+    /// to fetch it again, each with its own token. The server is started when the
+    /// first recipient accepts; this is for one that missed its `TransferReady`.
+    pub async fn start_file_transfer(&self, transfer_id: String) -> Result<(), MessageError> {
+        info!("Starting file transfer for: {}", transfer_id);
+
+        let (tcp_port, admissions) = self
+            .transfers
+            .admissions(&transfer_id)
+            .ok_or_else(|| MessageError::InvalidData(format!("Transfer {} is not being served", transfer_id)))?;
+        if admissions.is_empty() {
+            return Err(MessageError::InvalidData(format!("Nobody is waiting to receive transfer {}", transfer_id)));
+        }
+
+        for admission in &admissions {
+            self.send_transfer_ready(&transfer_id, tcp_port, admission).await?;
+        }
+        Ok(())
+    }
+
+    // Points one recipient at the transfer server; never broadcast, as it carries
+    // the recipient's token
+    async fn send_transfer_ready(&self, transfer_id: &str, tcp_port: u16, admission: &Admission) -> Result<(), MessageError> {
+        let ready_message = DiscoveryMessage::TransferReady {
+            transfer_id: transfer_id.to_string(),
+            tcp_port,
+            encrypted: admission.encrypted,
+            streams: admission.streams,
+            token: crypto::encode_token(&admission.token),
+            compression: admission.compression,
+        };
+
+        let target_addr = net::peer_addr(&admission.addr.0, admission.addr.1)?;
+        info!("Sending TransferReady for {} to user {} at {}", transfer_id, admission.user_id, target_addr);
+        net::send_to(&self.sockets, &ready_message, target_addr).await?;
+        Ok(())
+    }
+
+    /// Downloads an accepted transfer in the background; the outcome arrives as events.
+    /// A multi-file transfer is saved under `save_path` as a directory. Downloading
+    /// the same transfer to the same path again continues an interrupted attempt
+    /// instead of starting over.
+    pub async fn download_file(
+        &self,
+        transfer_id: String,
+        sender_ip: String,
+        port: u16,
+        save_path: PathBuf,
+    ) -> Result<(), MessageError> {
+        info!("Downloading file from {}:{} to {}", sender_ip, port, save_path.display());
+
+        let bundle = self.transfers.incoming(&transfer_id).is_some_and(|incoming| incoming.bundle);
+        let directory = if bundle { Some(save_path.as_path()) } else { save_path.parent() };
+        if let Some(directory) = directory {
+            if !directory.exists() {
+                tokio::fs::create_dir_all(directory).await.map_err(MessageError::NetworkError)?;
+            }
+        }
+
+        let previous = self
+            .transfers
+            .start_download(&transfer_id, sender_ip, port, save_path.clone())
+            .ok_or_else(|| MessageError::InvalidData(format!("Transfer {} is already downloading", transfer_id)))?;
+
+        // Part files are only trusted when they come from an earlier attempt at this transfer
+        let resume = previous.save_path.as_ref() == Some(&save_path);
+        self.spawn_download(transfer_id, resume)
+    }
+
+    /// Reconnects an interrupted download to the sender's transfer server and
+    /// continues from the end of its `.part` file.
+    pub async fn resume_download(&self, transfer_id: String) -> Result<(), MessageError> {
+        let incoming = self
+            .transfers
+            .incoming(&transfer_id)
+            .ok_or_else(|| MessageError::InvalidData(format!("Unknown transfer {}", transfer_id)))?;
+        let (sender_ip, save_path) = incoming
+            .sender_ip
+            .zip(incoming.save_path)
+            .ok_or_else(|| MessageError::InvalidData(format!("Transfer {} was never started", transfer_id)))?;
+
+        info!("Resuming transfer {} from {}:{}", transfer_id, sender_ip, incoming.port);
+        self.transfers
+            .start_download(&transfer_id, sender_ip, incoming.port, save_path)
+            .ok_or_else(|| MessageError::InvalidData(format!("Transfer {} is already downloading", transfer_id)))?;
+
+        self.spawn_download(transfer_id, true)
+    }
+
+    /// Stops a transfer we are sending or receiving, and tells the other side. The
+    /// `.part` file of a cancelled download is deleted; files of a folder that were
+    /// already saved are kept.
+    pub async fn cancel_transfer(&self, transfer_id: String) -> Result<(), MessageError> {
+        if self.transfers.contains(&transfer_id) {
+            let peers: Vec<_> = self
+                .transfers
+                .recipient_addrs(&transfer_id, false)
+                .into_iter()
+                .map(|(_, addr)| addr)
+                .collect();
+            // Told first, so they hear why before their connections drop
+            let message = DiscoveryMessage::FileCancel { transfer_id: transfer_id.clone(), user_id: None };
+            self.send_control(&message, &peers).await;
+            self.transfers.cancel(&transfer_id);
+        } else {
+            let incoming = self
+                .transfers
+                .incoming(&transfer_id)
+                .ok_or_else(|| MessageError::InvalidData(format!("Unknown transfer {}", transfer_id)))?;
+            // Before accepting there is no recipient ID to withdraw; rejecting is the answer then
+            if incoming.receiver_id != 0 {
+                let message = DiscoveryMessage::FileCancel { transfer_id: transfer_id.clone(), user_id: Some(incoming.receiver_id) };
+                self.send_control(&message, &self.sender_addr(&incoming).into_iter().collect::<Vec<_>>()).await;
+            }
+            if let Some(incoming) = self.transfers.cancel_incoming(&transfer_id) {
+                discard_download(&incoming).await;
+            }
+        }
+
+        self.queue.forget(&transfer_id);
+        info!("Cancelled transfer {}", transfer_id);
+        self.emit(EngineEvent::FileTransferCancelled { transfer_id, by_peer: false });
+        Ok(())
+    }
+
+    /// Holds a transfer we are sending or receiving without closing its connections,
+    /// and asks the other side to hold it too. Pausing an outgoing transfer holds
+    /// every recipient that has accepted.
+    pub async fn pause_transfer(&self, transfer_id: String) -> Result<(), MessageError> {
+        self.set_transfer_paused(transfer_id, true).await
+    }
+
+    /// Lets a paused transfer continue, whichever side paused it.
+    pub async fn resume_transfer(&self, transfer_id: String) -> Result<(), MessageError> {
+        self.set_transfer_paused(transfer_id, false).await
+    }
+
+    /// Whether a transfer, or any recipient of it, is paused.
+    pub fn transfer_paused(&self, transfer_id: &str) -> bool {
+        self.transfer_control(transfer_id).is_some_and(|control| control.is_paused())
+    }
+
+    fn transfer_control(&self, transfer_id: &str) -> Option<TransferControl> {
+        self.transfers
+            .control(transfer_id)
+            .or_else(|| self.transfers.incoming(transfer_id).map(|incoming| incoming.control))
+    }
+
+    async fn set_transfer_paused(&self, transfer_id: String, paused: bool) -> Result<(), MessageError> {
+        let (changed, peers, user_id) = if let Some(control) = self.transfers.control(&transfer_id) {
+            let recipients = self.transfers.recipient_addrs(&transfer_id, true);
+            // Held one by one, so a recipient resuming releases only itself
+            let changed = if paused {
+                recipients.iter().fold(false, |changed, (user_id, _)| control.pause(Some(*user_id)) | changed)
+            } else {
+                control.resume(None)
+            };
+            (changed, recipients.into_iter().map(|(_, addr)| addr).collect(), None)
+        } else {
+            let incoming = self
+                .transfers
+                .incoming(&transfer_id)
+                .ok_or_else(|| MessageError::InvalidData(format!("Unknown transfer {}", transfer_id)))?;
+            let changed = if paused { incoming.control.pause(None) } else { incoming.control.resume(None) };
+            (changed, self.sender_addr(&incoming).into_iter().collect::<Vec<_>>(), Some(incoming.receiver_id))
+        };
+        if !changed {
+            return Ok(());
+        }
+
+        let message = if paused {
+            DiscoveryMessage::FilePause { transfer_id: transfer_id.clone(), user_id }
+        } else {
+            DiscoveryMessage::FileResume { transfer_id: transfer_id.clone(), user_id }
+        };
+        self.send_control(&message, &peers).await;
+
+        info!("{} transfer {}", if paused { "Paused" } else { "Resumed" }, transfer_id);
+        if paused {
+            self.emit(EngineEvent::FileTransferPaused { transfer_id, recipient_id: None, by_peer: false });
+        } else {
+            self.emit(EngineEvent::FileTransferResumed { transfer_id, recipient_id: None, by_peer: false });
+        }
+        Ok(())
+    }
+
+    /// Running transfers in both directions, then queued ones in the order they will
+    /// start, with their speed and time remaining.
+    pub fn transfer_queue(&self) -> Vec<QueuedTransfer> {
+        let mut queue = self.queue.list();
+        for transfer in &mut queue {
+            if transfer.state == QueueState::Active && self.transfer_paused(&transfer.transfer_id) {
+                transfer.state = QueueState::Paused;
+            }
+        }
+        queue
+    }
+
+    /// Moves a transfer ahead of or behind others waiting in the same direction.
+    /// Set before the transfer is queued, it applies once it is.
+    pub fn set_transfer_priority(&self, transfer_id: &str, priority: TransferPriority) -> Result<(), MessageError> {
+        self.ensure_transfer(transfer_id)?;
+        self.queue.set_priority(transfer_id, priority);
+        Ok(())
+    }
+
+    /// Caps one transfer, in bytes per second; `None` lifts the cap. Set before the
+    /// transfer is queued, it applies once it is.
+    pub fn set_transfer_rate_limit(&self, transfer_id: &str, rate_limit: Option<u64>) -> Result<(), MessageError> {
+        self.ensure_transfer(transfer_id)?;
+        self.queue.set_transfer_rate_limit(transfer_id, rate_limit);
+        Ok(())
+    }
+
+    fn ensure_transfer(&self, transfer_id: &str) -> Result<(), MessageError> {
+        if self.transfers.contains(transfer_id) || self.transfers.incoming(transfer_id).is_some() {
+            return Ok(());
+        }
+        Err(MessageError::InvalidData(format!("Unknown transfer {}", transfer_id)))
+    }
+
+    /// Caps all transfers together, in bytes per second, overriding
+    /// `EngineConfig::rate_limit` for the rest of the session.
+    pub fn set_rate_limit(&self, rate_limit: Option<u64>) {
+        self.queue.set_rate_limit(rate_limit);
+    }
+
+    /// Overrides `EngineConfig::max_active_transfers` for the rest of the session.
+    pub fn set_max_active_transfers(&self, max_active: usize) {
+        self.queue.set_max_active(max_active);
+    }
+
+    /// Overrides `EngineConfig::compress_transfers` for transfers accepted from now on.
+    pub fn set_compress_transfers(&self, enabled: bool) {
+        self.compress_transfers.store(enabled, Ordering::Relaxed);
+    }
+
+    fn spawn_download(&self, transfer_id: String, resume: bool) -> Result<(), MessageError> {
+        let incoming = self.transfers.incoming(&transfer_id).unwrap_or_default();
+        let cipher = match (incoming.sender_key.as_deref(), incoming.encrypted) {
+            (Some(sender_key), true) => self.keys.file_cipher(sender_key, &transfer_id).map(Some),
+            (Some(_), false) => Err(plaintext_from_keyed_sender()),
+            (None, true) => Err(MessageError::CryptoError("Encrypted transfer from a sender without a key".to_string())),
+            (None, false) => self.file_cipher_for(None, &transfer_id),
+        };
+        let cipher = match cipher {
+            Ok(cipher) => cipher,
+            Err(e) => {
+                self.transfers.finish_download(&transfer_id, DownloadOutcome::Stopped(incoming.missing.clone()));
+                return Err(e);
+            }
+        };
+
+        let (Some(sender_ip), Some(save_path)) = (incoming.sender_ip, incoming.save_path) else {
+            self.transfers.finish_download(&transfer_id, DownloadOutcome::Stopped(incoming.missing.clone()));
+            return Err(MessageError::InvalidData(format!("Transfer {} was never started", transfer_id)));
+        };
+
+        let download = Download {
+            transfer_id: transfer_id.clone(),
+            receiver_id: incoming.receiver_id,
+            token: incoming.token.unwrap_or_default(),
+            sender_ip,
+            port: incoming.port,
+            save_path,
+            expected_hash: incoming.hash,
+            bundle: incoming.bundle,
+            resume,
+            streams: incoming.streams.max(1).min(self.config.transfer_streams.max(1)),
+            compression: incoming.compression,
+            missing: if resume { incoming.missing } else { None },
+            control: incoming.control,
+        };
+        // Queued right away, so it can be listed and limited before its task runs
+        let ticket = self.queue.enqueue(TransferDirection::Incoming, &transfer_id, incoming.size);
+        let control = download.control.clone();
+        let registry = self.transfers.clone();
+        let events = self.events.clone();
+        tokio::spawn(async move {
+            let slot = transfer::wait_for_slot(ticket, &transfer_id, &control, 0, &events).await;
+            let outcome = match slot {
+                Ok(slot) => transfer::receive_file(download, slot, cipher, events).await,
+                // Cancelled while queued
+                Err(_) => DownloadOutcome::Stopped(None),
+            };
+            registry.finish_download(&transfer_id, outcome);
+        });
+
+        Ok(())
+    }
+}
+
+// IPv6 is optional: a host without it still runs over IPv4
+fn ipv6_socket(port: u16, interfaces: &InterfaceSelection) -> Option<Arc<UdpSocket>> {
+    match net::create_socket_v6(port, interfaces) {
+        Ok(socket) => Some(Arc::new(socket)),
+        Err(e) => {
+            warn!("No IPv6 socket on port {}: {}", port, e);
+            None
+        }
+    }
+}
+
+// Presence carries the address a peer chose to be reached at, unless it left the
+// choice to us. Only a signed one is taken, as the packet's source is otherwise all
+// we know. A link-local address, whose scope is the sender's, or another IP version
+// than the packet's is no use here.
+fn presence_ip(user: &User, addr: SocketAddr) -> String {
+    match net::parse_ip(&user.ip) {
+        Some(ip)
+            if user.signature.is_some()
+                && !ip.is_unspecified()
+                && !ip.is_loopback()
+                && !matches!(ip, IpAddr::V6(ip) if ip.is_unicast_link_local())
+                && ip.is_ipv4() == addr.ip().to_canonical().is_ipv4() =>
+        {
+            ip.to_string()
+        }
+        _ => net::peer_ip(addr),
+    }
+}
+
+fn plaintext_from_keyed_sender() -> MessageError {
+    MessageError::CryptoError("The sender has an encryption key but offered the transfer unencrypted".to_string())
+}
+
+fn ensure_room_member(room: &Room, user_id: u64) -> Result<(), MessageError> {
+    if room.is_member(user_id) {
+        Ok(())
+    } else {
+        Err(MessageError::InvalidData(format!("User {} is not in room {}", user_id, room.room_id)))
+    }
+}
+
+fn check_content(content: &str) -> Result<(), MessageError> {
+    if content.is_empty() {
+        return Err(MessageError::InvalidData("Message cannot be empty".to_string()));
+    }
+    if content.len() > MAX_MESSAGE_SIZE {
+        return Err(MessageError::InvalidData("Message too large".to_string()));
+    }
+    Ok(())
+}
+
+/// Sender-generated ID carried by every chat message (and used as the chunk ID).
+pub fn new_message_id() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+// Whether a signed record's timestamp, in Unix seconds, is too far from our clock
+// for the dedupe window to still catch a replay of it
+fn is_stale(timestamp: u64) -> bool {
+    now_secs().abs_diff(timestamp) > DEDUPE_WINDOW_SECS
+}
+
+// Signed messages are refused once they are stale, as their signature would still
+// verify on a replay. Unsigned ones can be forged outright, so are not checked.
+fn check_fresh(timestamp: u64, signature: Option<&str>) -> Result<(), MessageError> {
+    if signature.is_some() && is_stale(timestamp) {
+        return Err(MessageError::InvalidData(format!("Stale message timestamp {}", timestamp)));
+    }
+    Ok(())
+}
+
+fn announcement_key(announcement_id: &str) -> String {
+    format!("announcement:{}", announcement_id)
+}
+
+fn offer_key(transfer_id: &str) -> String {
+    format!("offer:{}", transfer_id)
+}
+
+// Dedupe entry that silences retransmits of a message we refused
+fn rejected_key(message_id: &str) -> String {
+    format!("rejected:{}", message_id)
+}
+
+// Who sent a pause or cancel for one of our transfers
+enum ControlPeer {
+    Recipient(u64),
+    Sender(IncomingTransfer),
+}
+
+// Deletes what a cancelled download left behind, unless its task is still running
+// and cleans up itself. Only a single file's part is known here.
+async fn discard_download(incoming: &IncomingTransfer) {
+    if incoming.active || incoming.bundle {
+        return;
+    }
+    if let Some(save_path) = &incoming.save_path {
+        let _ = tokio::fs::remove_file(transfer::part_path(save_path)).await;
+    }
+}
+
+// History key for one recipient of a one-to-many offer
+fn recipient_history_id(transfer_id: &str, user_id: u64) -> String {
+    format!("{}:{}", transfer_id, user_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    const WAIT: Duration = Duration::from_secs(10);
+
+    // A message from user 1 to user 2, signed by `identity`
+    fn signed_message(identity: &Identity, message_id: &str, timestamp: u64, sender_port: u16) -> Vec<u8> {
+        let content = "hello".to_string();
+        let signed = identity::message_signing_bytes(message_id, "alice", 1, 2, timestamp, &content, None);
+        serde_json::to_vec(&DiscoveryMessage::Message {
+            content,
+            sender: "alice".to_string(),
+            sender_id: 1,
+            target_id: 2,
+            sender_port,
+            timestamp,
+            message_id: message_id.to_string(),
+            encryption: None,
+            signature: Some(identity.sign(&signed)),
+            compression: None,
+            room_id: None,
+        })
+        .unwrap()
+    }
+
+    // An engine in a fresh directory, and the port it takes messages on
+    async fn engine(dir: &Path) -> (RoundtableEngine, u16) {
+        let _ = std::fs::remove_dir_all(dir);
+        let port = std::net::UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        // One port for discovery and messages, so no other one has to be free
+        let config = EngineConfig { discovery_port: port, message_port: port, data_dir: dir.join("engine"), ..Default::default() };
+        (RoundtableEngine::start(config).await.unwrap(), port)
+    }
+
+    #[tokio::test]
+    async fn refuses_stale_signed_messages() {
+        let dir = std::env::temp_dir().join(format!("roundtable-engine-stale-{}", std::process::id()));
+        let (bob, port) = engine(&dir).await;
+        let mut events = bob.subscribe();
+
+        let alice = Identity::load_or_create(&dir.join("alice.key")).unwrap();
+        assert!(matches!(bob.known_peers.check(1, &alice.public_key(), "alice"), Trust::New));
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let sender_port = socket.local_addr().unwrap().port();
+        let stale = now_secs() - DEDUPE_WINDOW_SECS - 60;
+        socket.send_to(&signed_message(&alice, "stale", stale, sender_port), ("127.0.0.1", port)).await.unwrap();
+        socket.send_to(&signed_message(&alice, "fresh", now_secs(), sender_port), ("127.0.0.1", port)).await.unwrap();
+
+        let received = tokio::time::timeout(WAIT, async {
+            loop {
+                if let Ok(EngineEvent::MessageReceived(message)) = events.recv().await {
+                    return message.message_id;
+                }
+            }
+        })
+        .await
+        .expect("the fresh message should arrive");
+        assert_eq!(received, "fresh");
+        assert!(bob.sockets.chunk_manager.is_processed(&rejected_key("stale")).await);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn typing_only_from_the_peers_address() {
+        let dir = std::env::temp_dir().join(format!("roundtable-engine-typing-{}", std::process::id()));
+        let (bob, port) = engine(&dir).await;
+        let mut events = bob.subscribe();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let typing = |sender_id| serde_json::to_vec(&DiscoveryMessage::Typing { sender_id, target_id: 2, is_typing: true }).unwrap();
+
+        // Alice was last seen elsewhere, and carol not at all
+        let mut alice = bob.local_user(1, "alice".to_string(), "alice".to_string(), None);
+        alice.ip = "192.0.2.1".to_string();
+        bob.remember_peer(&alice);
+        socket.send_to(&typing(1), ("127.0.0.1", port)).await.unwrap();
+        socket.send_to(&typing(3), ("127.0.0.1", port)).await.unwrap();
+        sleep(Duration::from_millis(300)).await;
+
+        alice.ip = "127.0.0.1".to_string();
+        bob.remember_peer(&alice);
+        socket.send_to(&typing(1), ("127.0.0.1", port)).await.unwrap();
+
+        let sender_id = tokio::time::timeout(WAIT, async {
+            loop {
+                if let Ok(EngineEvent::UserTyping { sender_id, .. }) = events.recv().await {
+                    return sender_id;
+                }
+            }
+        })
+        .await
+        .expect("the genuine notice should arrive");
+        assert_eq!(sender_id, 1);
+        sleep(Duration::from_millis(300)).await;
+        while let Ok(event) = events.try_recv() {
+            assert!(!matches!(event, EngineEvent::UserTyping { .. }), "only one notice was genuine");
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,22 @@
+#[derive(Debug)]
+pub enum MessageError {
+    SerializationError(serde_json::Error),
+    NetworkError(std::io::Error),
+    InvalidData(String),
+    CryptoError(String),
+    StorageError(rusqlite::Error),
+}
+
+impl std::fmt::Display for MessageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MessageError::SerializationError(e) => write!(f, "Serialization error: {}", e),
+            MessageError::NetworkError(e) => write!(f, "Network error: {}", e),
+            MessageError::InvalidData(s) => write!(f, "Invalid data: {}", s),
+            MessageError::CryptoError(s) => write!(f, "Crypto error: {}", s),
+            MessageError::StorageError(e) => write!(f, "Storage error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for MessageError {}
@@ -0,0 +1,283 @@
+use serde::Serialize;
+
+use crate::delivery::DeliveryStatus;
+use crate::protocol::User;
+use crate::queue::TransferDirection;
+use crate::room::Room;
+use crate::transfer::RecipientStatus;
+
+/// A chat message that has been fully received (and reassembled, if it was chunked).
+#[derive(Serialize, Debug, Clone)]
+pub struct ReceivedMessage {
+    pub message_id: String,
+    pub content: String,
+    pub sender: String,
+    pub sender_id: u64,
+    pub target_id: u64,
+    pub sender_port: u16,
+    pub ip: String,
+    pub timestamp: u64,
+}
+
+/// Everything the engine reports to its embedder.
+///
+/// Events serialize to the same payloads the desktop app has always emitted, so an
+/// adapter can forward them as `emit(event.name(), &event)`.
+#[derive(Serialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum EngineEvent {
+    UserOnline(User),
+    UserOffline(User),
+    DiscoveryQuery,
+    MessageReceived(ReceivedMessage),
+    #[serde(rename_all = "camelCase")]
+    MessageDeliveryStatus {
+        message_id: String,
+        target_id: u64,
+        status: DeliveryStatus,
+    },
+    /// An encrypted message arrived that we could not decrypt; it is not acknowledged.
+    #[serde(rename_all = "camelCase")]
+    MessageDecryptionFailed {
+        message_id: String,
+        sender_id: u64,
+        error: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    MessageRead {
+        message_id: String,
+        reader_id: u64,
+    },
+    #[serde(rename_all = "camelCase")]
+    UserTyping {
+        sender_id: u64,
+        target_id: u64,
+        is_typing: bool,
+    },
+    /// A pinned peer presented a different identity key (or none). Its presence,
+    /// messages and offers are ignored until the pin is forgotten.
+    #[serde(rename_all = "camelCase")]
+    PeerIdentityChanged {
+        user_id: u64,
+        name: String,
+        ip: String,
+        previous_key: String,
+        new_key: Option<String>,
+    },
+    /// A signed notice someone broadcast to the whole subnet.
+    #[serde(rename_all = "camelCase")]
+    AnnouncementReceived {
+        announcement_id: String,
+        sender: String,
+        sender_id: u64,
+        content: String,
+        urgent: bool,
+        ip: String,
+        timestamp: u64,
+    },
+    /// A member added us to a room.
+    #[serde(rename_all = "camelCase")]
+    RoomInvited {
+        room: Room,
+        inviter_id: u64,
+    },
+    /// A room's name or members changed.
+    RoomUpdated(Room),
+    /// A member left, or was removed by the owner (`kicked_by`). `room` is the room
+    /// without them.
+    #[serde(rename_all = "camelCase")]
+    RoomMemberLeft {
+        room: Room,
+        user_id: u64,
+        kicked_by: Option<u64>,
+    },
+    /// The owner removed us from a room, which is forgotten.
+    #[serde(rename_all = "camelCase")]
+    RoomKicked {
+        room_id: String,
+        kicked_by: u64,
+    },
+    #[serde(rename_all = "camelCase")]
+    RoomMessageReceived {
+        room_id: String,
+        message: ReceivedMessage,
+    },
+    /// Delivery of one member's copy of a room message we sent.
+    #[serde(rename_all = "camelCase")]
+    RoomMessageDeliveryStatus {
+        room_id: String,
+        message_id: String,
+        member_id: u64,
+        status: DeliveryStatus,
+    },
+    #[serde(rename_all = "camelCase")]
+    FileOfferReceived {
+        sender: User,
+        file_name: String,
+        file_size: u64,
+        transfer_id: String,
+        hash: Option<String>,
+        file_count: Option<u64>,
+    },
+    #[serde(rename_all = "camelCase")]
+    FileTransferAccepted {
+        transfer_id: String,
+        receiver: User,
+    },
+    #[serde(rename_all = "camelCase")]
+    FileTransferReady {
+        transfer_id: String,
+        port: u16,
+        sender_ip: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    FileTransferProgress {
+        transfer_id: String,
+        progress: u8,
+        transferred: u64,
+        size: u64,
+        /// Average over the current attempt, across all streams.
+        bytes_per_second: u64,
+        /// `None` until the rate is known.
+        eta_seconds: Option<u64>,
+    },
+    /// Sender-side counterpart of `FileTransferProgress`.
+    #[serde(rename_all = "camelCase")]
+    FileTransferSendProgress {
+        transfer_id: String,
+        /// `None` for transfers announced by broadcast.
+        recipient_id: Option<u64>,
+        progress: u8,
+        sent: u64,
+        size: u64,
+        bytes_per_second: u64,
+        eta_seconds: Option<u64>,
+    },
+    #[serde(rename_all = "camelCase")]
+    FileTransferComplete {
+        transfer_id: String,
+        file_path: String,
+        size: u64,
+    },
+    #[serde(rename_all = "camelCase")]
+    FileTransferSent {
+        transfer_id: String,
+        size: u64,
+    },
+    /// The connection dropped part way; the transfer can be resumed from `transferred`.
+    #[serde(rename_all = "camelCase")]
+    FileTransferInterrupted {
+        transfer_id: String,
+        transferred: u64,
+        size: u64,
+    },
+    /// Progress of one file of a multi-file transfer, by its manifest path.
+    #[serde(rename_all = "camelCase")]
+    FileTransferEntryProgress {
+        transfer_id: String,
+        path: String,
+        index: usize,
+        progress: u8,
+    },
+    /// The saved file did not match its hash and was deleted. `file` is the
+    /// manifest path for multi-file transfers.
+    #[serde(rename_all = "camelCase")]
+    FileTransferCorrupt {
+        transfer_id: String,
+        file: Option<String>,
+        expected: String,
+        actual: String,
+    },
+    /// A recipient of an outgoing transfer accepted, rejected, started, finished,
+    /// failed or timed out.
+    #[serde(rename_all = "camelCase")]
+    FileTransferRecipient {
+        transfer_id: String,
+        recipient: RecipientStatus,
+    },
+    /// The transfer has to wait for others in the same direction to finish.
+    #[serde(rename_all = "camelCase")]
+    FileTransferQueued {
+        transfer_id: String,
+        direction: TransferDirection,
+    },
+    /// A peer declined our offer. `receiver_id` is missing from older peers.
+    #[serde(rename_all = "camelCase")]
+    FileTransferRejected {
+        transfer_id: String,
+        receiver_id: Option<u64>,
+    },
+    /// The transfer was stopped for good, here or by the other side (`by_peer`).
+    #[serde(rename_all = "camelCase")]
+    FileTransferCancelled {
+        transfer_id: String,
+        by_peer: bool,
+    },
+    /// Data stopped flowing until the transfer is resumed. `recipient_id` is set
+    /// when only one recipient of an outgoing transfer paused.
+    #[serde(rename_all = "camelCase")]
+    FileTransferPaused {
+        transfer_id: String,
+        recipient_id: Option<u64>,
+        by_peer: bool,
+    },
+    #[serde(rename_all = "camelCase")]
+    FileTransferResumed {
+        transfer_id: String,
+        recipient_id: Option<u64>,
+        by_peer: bool,
+    },
+    #[serde(rename_all = "camelCase")]
+    FileTransferError {
+        transfer_id: String,
+        error: String,
+    },
+}
+
+impl EngineEvent {
+    /// The window event name used by the desktop frontend.
+    pub fn name(&self) -> &'static str {
+        match self {
+            EngineEvent::UserOnline(_) => "user-online",
+            EngineEvent::UserOffline(_) => "user-offline",
+            EngineEvent::DiscoveryQuery => "discovery-query-received",
+            EngineEvent::MessageReceived(_) => "message-received",
+            EngineEvent::MessageDeliveryStatus { .. } => "message-delivery-status",
+            EngineEvent::MessageDecryptionFailed { .. } => "message-decryption-failed",
+            EngineEvent::MessageRead { .. } => "message-read",
+            EngineEvent::UserTyping { .. } => "user-typing",
+            EngineEvent::PeerIdentityChanged { .. } => "peer-identity-changed",
+            EngineEvent::AnnouncementReceived { .. } => "announcement-received",
+            EngineEvent::RoomInvited { .. } => "room-invited",
+            EngineEvent::RoomUpdated(_) => "room-updated",
+            EngineEvent::RoomMemberLeft { .. } => "room-member-left",
+            EngineEvent::RoomKicked { .. } => "room-kicked",
+            EngineEvent::RoomMessageReceived { .. } => "room-message-received",
+            EngineEvent::RoomMessageDeliveryStatus { .. } => "room-message-delivery-status",
+            EngineEvent::FileOfferReceived { .. } => "file-offer-received",
+            EngineEvent::FileTransferAccepted { .. } => "file-transfer-accepted",
+            EngineEvent::FileTransferReady { .. } => "file-transfer-ready",
+            EngineEvent::FileTransferProgress { .. } => "file-transfer-progress",
+            EngineEvent::FileTransferSendProgress { .. } => "file-transfer-send-progress",
+            EngineEvent::FileTransferComplete { .. } => "file-transfer-complete",
+            EngineEvent::FileTransferSent { .. } => "file-transfer-sent",
+            EngineEvent::FileTransferInterrupted { .. } => "file-transfer-interrupted",
+            EngineEvent::FileTransferEntryProgress { .. } => "file-transfer-entry-progress",
+            EngineEvent::FileTransferCorrupt { .. } => "file-transfer-corrupt",
+            EngineEvent::FileTransferRecipient { .. } => "file-transfer-recipient",
+            EngineEvent::FileTransferQueued { .. } => "file-transfer-queued",
+            EngineEvent::FileTransferRejected { .. } => "file-transfer-rejected",
+            EngineEvent::FileTransferCancelled { .. } => "file-transfer-cancelled",
+            EngineEvent::FileTransferPaused { .. } => "file-transfer-paused",
+            EngineEvent::FileTransferResumed { .. } => "file-transfer-resumed",
+            EngineEvent::FileTransferError { .. } => "file-transfer-error",
+        }
+    }
+
+    pub(crate) fn transfer_error(transfer_id: &str, error: impl Into<String>) -> Self {
+        EngineEvent::FileTransferError {
+            transfer_id: transfer_id.to_string(),
+            error: error.into(),
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use chrono::{DateTime, Local, TimeZone};
+use serde::{Deserialize, Serialize};
+
+use crate::error::MessageError;
+use crate::history::{now_millis, MessageStore, StoredMessage};
+
+/// Tag identifying a Roundtable history archive.
+pub const ARCHIVE_FORMAT: &str = "roundtable-history";
+pub const ARCHIVE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// An archive that [`import_archive`] can read back.
+    Json,
+    /// A self-contained transcript with inline styles.
+    Html,
+    Text,
+}
+
+impl ExportFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Json => "json",
+            ExportFormat::Html => "html",
+            ExportFormat::Text => "txt",
+        }
+    }
+}
+
+/// What to export: one conversation or all of them, optionally limited to
+/// `from..=to` (Unix milliseconds).
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ExportRange {
+    pub peer_id: Option<u64>,
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Archive {
+    pub format: String,
+    pub version: u32,
+    pub exported_at: u64,
+    #[serde(default)]
+    pub peer_id: Option<u64>,
+    #[serde(default)]
+    pub peer_name: Option<String>,
+    #[serde(default)]
+    pub from: Option<u64>,
+    #[serde(default)]
+    pub to: Option<u64>,
+    pub messages: Vec<StoredMessage>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportSummary {
+    pub imported: usize,
+    /// Messages whose ID was already in history.
+    pub skipped: usize,
+}
+
+/// Renders the messages in `range` in `format`. Returns the document and how many
+/// messages it holds.
+pub fn export(store: &MessageStore, range: &ExportRange, format: ExportFormat) -> Result<(String, usize), MessageError> {
+    let messages = store.messages_between(range.peer_id, range.from, range.to)?;
+    let peer_name = match range.peer_id {
+        Some(peer_id) => store
+            .conversations()?
+            .into_iter()
+            .find(|c| c.peer_id == peer_id)
+            .and_then(|c| c.peer_name),
+        None => None,
+    };
+
+    let archive = Archive {
+        format: ARCHIVE_FORMAT.to_string(),
+        version: ARCHIVE_VERSION,
+        exported_at: now_millis(),
+        peer_id: range.peer_id,
+        peer_name,
+        from: range.from,
+        to: range.to,
+        messages,
+    };
+
+    let document = match format {
+        ExportFormat::Json => serde_json::to_string_pretty(&archive).map_err(MessageError::SerializationError)?,
+        ExportFormat::Html => render_html(&archive),
+        ExportFormat::Text => render_text(&archive),
+    };
+    Ok((document, archive.messages.len()))
+}
+
+/// Reads a JSON archive back into history. Messages already stored are skipped.
+pub fn import_archive(store: &MessageStore, json: &str) -> Result<ImportSummary, MessageError> {
+    let archive: Archive = serde_json::from_str(json).map_err(MessageError::SerializationError)?;
+    if archive.format != ARCHIVE_FORMAT {
+        return Err(MessageError::InvalidData(format!("Not a Roundtable history archive: {}", archive.format)));
+    }
+    if archive.version > ARCHIVE_VERSION {
+        return Err(MessageError::InvalidData(format!("Unsupported archive version {}", archive.version)));
+    }
+
+    let imported = store.insert_messages(&archive.messages)?;
+    Ok(ImportSummary { imported, skipped: archive.messages.len() - imported })
+}
+
+fn local_time(millis: u64) -> DateTime<Local> {
+    Local
+        .timestamp_millis_opt(millis as i64)
+        .single()
+        .unwrap_or_else(Local::now)
+}
+
+fn title(archive: &Archive) -> String {
+    match (&archive.peer_name, archive.peer_id) {
+        (Some(name), _) => format!("Conversation with {}", name),
+        (None, Some(peer_id)) => format!("Conversation with user {}", peer_id),
+        (None, None) => "Roundtable history".to_string(),
+    }
+}
+
+fn render_text(archive: &Archive) -> String {
+    let mut out = format!(
+        "{}\nExported: {}\n{}\n",
+        title(archive),
+        local_time(archive.exported_at).format("%Y-%m-%d %H:%M:%S"),
+        "=".repeat(50)
+    );
+
+    for message in &archive.messages {
+        // Continuation lines are indented so every line still starts with a timestamp or a blank
+        let content = message.content.replace('\n', "\n    ");
+        out.push_str(&format!(
+            "[{}] {}: {}\n",
+            local_time(message.timestamp).format("%Y-%m-%d %H:%M:%S"),
+            message.sender_name,
+            content
+        ));
+    }
+    out
+}
+
+fn render_html(archive: &Archive) -> String {
+    let title = escape_html(&title(archive));
+    let mut body = String::new();
+    let mut current_day = None;
+
+    for message in &archive.messages {
+        let time = local_time(message.timestamp);
+        let day = time.date_naive();
+        if current_day != Some(day) {
+            body.push_str(&format!("<h2>{}</h2>\n", day.format("%A, %d %B %Y")));
+            current_day = Some(day);
+        }
+
+        body.push_str(&format!(
+            "<div class=\"msg {}\"><span class=\"meta\">{} &middot; {}</span><p>{}</p></div>\n",
+            if message.outgoing { "out" } else { "in" },
+            escape_html(&message.sender_name),
+            time.format("%H:%M:%S"),
+            escape_html(&message.content).replace('\n', "<br>")
+        ));
+    }
+
+    format!(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n<style>\n{STYLE}</style>\n</head>\n<body>\n<h1>{title}</h1>\n<p class=\"exported\">Exported {exported} &middot; {count} messages</p>\n{body}</body>\n</html>\n",
+        title = title,
+        exported = local_time(archive.exported_at).format("%Y-%m-%d %H:%M:%S"),
+        count = archive.messages.len(),
+        body = body,
+    )
+}
+
+const STYLE: &str = "body { font-family: system-ui, sans-serif; max-width: 48rem; margin: 2rem auto; padding: 0 1rem; color: #1f2937; }
+h2 { font-size: 0.9rem; color: #6b7280; text-align: center; margin: 1.5rem 0 0.5rem; }
+.exported { color: #6b7280; font-size: 0.85rem; }
+.msg { margin: 0.4rem 0; padding: 0.5rem 0.75rem; border-radius: 0.5rem; max-width: 80%; }
+.msg p { margin: 0.2rem 0 0; white-space: pre-wrap; word-wrap: break-word; }
+.msg.in { background: #f3f4f6; }
+.msg.out { background: #dbeafe; margin-left: auto; }
+.meta { font-size: 0.75rem; color: #6b7280; }
+";
+
+fn escape_html(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
@@ -0,0 +1,538 @@
+use rusqlite::{params, Connection, Row};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use log::error;
+
+use crate::error::MessageError;
+
+pub const HISTORY_FILE: &str = "history.sqlite3";
+pub const DEFAULT_PAGE_SIZE: u32 = 50;
+const MAX_PAGE_SIZE: u32 = 500;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS conversations (
+    peer_id INTEGER PRIMARY KEY,
+    peer_name TEXT,
+    peer_ip TEXT,
+    last_message_at INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS messages (
+    message_id TEXT PRIMARY KEY,
+    peer_id INTEGER NOT NULL,
+    sender_id INTEGER NOT NULL,
+    sender_name TEXT NOT NULL,
+    target_id INTEGER NOT NULL,
+    content TEXT NOT NULL,
+    timestamp INTEGER NOT NULL,
+    outgoing INTEGER NOT NULL,
+    status TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS messages_by_peer ON messages (peer_id, timestamp);
+
+CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts USING fts5 (
+    content, content = 'messages', content_rowid = 'rowid'
+);
+CREATE TRIGGER IF NOT EXISTS messages_ai AFTER INSERT ON messages BEGIN
+    INSERT INTO messages_fts (rowid, content) VALUES (new.rowid, new.content);
+END;
+CREATE TRIGGER IF NOT EXISTS messages_ad AFTER DELETE ON messages BEGIN
+    INSERT INTO messages_fts (messages_fts, rowid, content) VALUES ('delete', old.rowid, old.content);
+END;
+
+CREATE TABLE IF NOT EXISTS file_transfers (
+    transfer_id TEXT PRIMARY KEY,
+    peer_id INTEGER NOT NULL,
+    peer_name TEXT,
+    file_name TEXT NOT NULL,
+    file_size INTEGER NOT NULL,
+    outgoing INTEGER NOT NULL,
+    status TEXT NOT NULL,
+    file_path TEXT,
+    timestamp INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS file_transfers_by_peer ON file_transfers (peer_id, timestamp);
+
+CREATE TABLE IF NOT EXISTS room_messages (
+    message_id TEXT PRIMARY KEY,
+    room_id TEXT NOT NULL,
+    sender_id INTEGER NOT NULL,
+    sender_name TEXT NOT NULL,
+    content TEXT NOT NULL,
+    timestamp INTEGER NOT NULL,
+    outgoing INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS room_messages_by_room ON room_messages (room_id, timestamp);
+";
+
+/// A chat message as kept in history. Timestamps are Unix milliseconds.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct StoredMessage {
+    /// Insertion order; pass it back as `before_seq` to fetch the next page.
+    #[serde(default)]
+    pub seq: i64,
+    pub message_id: String,
+    pub peer_id: u64,
+    pub sender_id: u64,
+    pub sender_name: String,
+    pub target_id: u64,
+    pub content: String,
+    pub timestamp: u64,
+    pub outgoing: bool,
+    /// "pending", "delivered", "failed" or "read" when outgoing, "received" otherwise.
+    pub status: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Conversation {
+    pub peer_id: u64,
+    pub peer_name: Option<String>,
+    pub peer_ip: Option<String>,
+    pub last_message_at: u64,
+    pub message_count: u64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct StoredTransfer {
+    pub transfer_id: String,
+    pub peer_id: u64,
+    pub peer_name: Option<String>,
+    pub file_name: String,
+    pub file_size: u64,
+    pub outgoing: bool,
+    pub status: String,
+    pub file_path: Option<String>,
+    pub timestamp: u64,
+}
+
+/// A message in a room. Ours are stored once under the ID returned when sending,
+/// whatever the per-member copies were called.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct StoredRoomMessage {
+    pub seq: i64,
+    pub message_id: String,
+    pub room_id: String,
+    pub sender_id: u64,
+    pub sender_name: String,
+    pub content: String,
+    pub timestamp: u64,
+    pub outgoing: bool,
+}
+
+/// SQLite-backed message, conversation, room and file-transfer history.
+#[derive(Clone)]
+pub struct MessageStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl MessageStore {
+    pub fn open(path: &Path) -> Result<Self, MessageError> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(MessageError::NetworkError)?;
+        }
+        let conn = Connection::open(path).map_err(MessageError::StorageError)?;
+        conn.pragma_update(None, "journal_mode", "WAL").map_err(MessageError::StorageError)?;
+        Self::init(conn)
+    }
+
+    /// A store that lives only as long as the process.
+    pub fn in_memory() -> Result<Self, MessageError> {
+        Self::init(Connection::open_in_memory().map_err(MessageError::StorageError)?)
+    }
+
+    fn init(conn: Connection) -> Result<Self, MessageError> {
+        conn.execute_batch(SCHEMA).map_err(MessageError::StorageError)?;
+        Ok(Self { conn: Arc::new(Mutex::new(conn)) })
+    }
+
+    /// Records a message; a message ID that is already stored is left untouched.
+    pub fn insert_message(&self, message: &StoredMessage, peer_name: Option<&str>, peer_ip: Option<&str>) -> Result<bool, MessageError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(MessageError::StorageError)?;
+        let inserted = insert_message(&tx, message, peer_name, peer_ip)?;
+        tx.commit().map_err(MessageError::StorageError)?;
+        Ok(inserted)
+    }
+
+    /// Records many messages in one transaction, skipping IDs already stored.
+    /// Returns how many were new.
+    pub fn insert_messages(&self, messages: &[StoredMessage]) -> Result<usize, MessageError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(MessageError::StorageError)?;
+
+        let mut inserted = 0;
+        for message in messages {
+            let peer_name = (!message.outgoing).then_some(message.sender_name.as_str());
+            if insert_message(&tx, message, peer_name, None)? {
+                inserted += 1;
+            }
+        }
+
+        tx.commit().map_err(MessageError::StorageError)?;
+        Ok(inserted)
+    }
+
+    /// Updates the status of one of our outgoing messages.
+    pub fn set_message_status(&self, message_id: &str, status: &str) -> Result<(), MessageError> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute("UPDATE messages SET status = ?2 WHERE message_id = ?1 AND outgoing = 1", params![message_id, status])
+            .map_err(MessageError::StorageError)?;
+        Ok(())
+    }
+
+    /// One of our outgoing messages, with the address its conversation's peer was
+    /// last known at.
+    pub fn sent_message(&self, message_id: &str) -> Result<Option<(StoredMessage, Option<String>)>, MessageError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare(
+                "SELECT m.rowid, m.*, c.peer_ip FROM messages m
+                 LEFT JOIN conversations c ON c.peer_id = m.peer_id
+                 WHERE m.message_id = ?1 AND m.outgoing = 1",
+            )
+            .map_err(MessageError::StorageError)?;
+
+        let mut rows = stmt
+            .query_map(params![message_id], |row| Ok((message_from_row(row)?, row.get(10)?)))
+            .map_err(MessageError::StorageError)?;
+        rows.next().transpose().map_err(MessageError::StorageError)
+    }
+
+    /// Keeps a known conversation's peer name and address current.
+    pub fn update_peer(&self, peer_id: u64, peer_name: &str, peer_ip: &str) -> Result<(), MessageError> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "UPDATE conversations SET peer_name = ?2, peer_ip = ?3 WHERE peer_id = ?1",
+                params![peer_id as i64, peer_name, peer_ip],
+            )
+            .map_err(MessageError::StorageError)?;
+        Ok(())
+    }
+
+    pub fn conversations(&self) -> Result<Vec<Conversation>, MessageError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare(
+                "SELECT c.peer_id, c.peer_name, c.peer_ip, c.last_message_at,
+                        (SELECT COUNT(*) FROM messages m WHERE m.peer_id = c.peer_id)
+                 FROM conversations c ORDER BY c.last_message_at DESC",
+            )
+            .map_err(MessageError::StorageError)?;
+
+        let rows = stmt
+            .query_map([], |row| {
+                Ok(Conversation {
+                    peer_id: row.get::<_, i64>(0)? as u64,
+                    peer_name: row.get(1)?,
+                    peer_ip: row.get(2)?,
+                    last_message_at: row.get::<_, i64>(3)? as u64,
+                    message_count: row.get::<_, i64>(4)? as u64,
+                })
+            })
+            .map_err(MessageError::StorageError)?;
+        rows.collect::<Result<_, _>>().map_err(MessageError::StorageError)
+    }
+
+    /// One page of a conversation, newest first. `before_seq` is the `seq` of the
+    /// oldest message already shown.
+    pub fn history(&self, peer_id: u64, before_seq: Option<i64>, limit: u32) -> Result<Vec<StoredMessage>, MessageError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare(
+                "SELECT rowid, * FROM messages
+                 WHERE peer_id = ?1
+                   AND (?2 IS NULL OR (timestamp, rowid) < (SELECT timestamp, rowid FROM messages WHERE rowid = ?2))
+                 ORDER BY timestamp DESC, rowid DESC
+                 LIMIT ?3",
+            )
+            .map_err(MessageError::StorageError)?;
+
+        let rows = stmt
+            .query_map(params![peer_id as i64, before_seq, page_size(limit)], message_from_row)
+            .map_err(MessageError::StorageError)?;
+        rows.collect::<Result<_, _>>().map_err(MessageError::StorageError)
+    }
+
+    /// Messages in chronological order, optionally limited to one conversation and
+    /// to `from..=to` (Unix milliseconds).
+    pub fn messages_between(&self, peer_id: Option<u64>, from: Option<u64>, to: Option<u64>) -> Result<Vec<StoredMessage>, MessageError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare(
+                "SELECT rowid, * FROM messages
+                 WHERE (?1 IS NULL OR peer_id = ?1)
+                   AND (?2 IS NULL OR timestamp >= ?2)
+                   AND (?3 IS NULL OR timestamp <= ?3)
+                 ORDER BY timestamp, rowid",
+            )
+            .map_err(MessageError::StorageError)?;
+
+        let rows = stmt
+            .query_map(
+                params![peer_id.map(|id| id as i64), from.map(|t| t as i64), to.map(|t| t as i64)],
+                message_from_row,
+            )
+            .map_err(MessageError::StorageError)?;
+        rows.collect::<Result<_, _>>().map_err(MessageError::StorageError)
+    }
+
+    /// Full-text search over message content, newest first. Every word in `query`
+    /// must match; FTS operators are not interpreted.
+    pub fn search(&self, query: &str, peer_id: Option<u64>, limit: u32) -> Result<Vec<StoredMessage>, MessageError> {
+        let terms: Vec<String> = query
+            .split_whitespace()
+            .map(|term| format!("\"{}\"", term.replace('"', "\"\"")))
+            .collect();
+        if terms.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare(
+                "SELECT m.rowid, m.* FROM messages_fts f JOIN messages m ON m.rowid = f.rowid
+                 WHERE messages_fts MATCH ?1 AND (?2 IS NULL OR m.peer_id = ?2)
+                 ORDER BY m.timestamp DESC
+                 LIMIT ?3",
+            )
+            .map_err(MessageError::StorageError)?;
+
+        let rows = stmt
+            .query_map(params![terms.join(" "), peer_id.map(|id| id as i64), page_size(limit)], message_from_row)
+            .map_err(MessageError::StorageError)?;
+        rows.collect::<Result<_, _>>().map_err(MessageError::StorageError)
+    }
+
+    /// Removes a conversation with all its messages and file transfers. Returns the
+    /// number of messages deleted.
+    pub fn delete_conversation(&self, peer_id: u64) -> Result<usize, MessageError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(MessageError::StorageError)?;
+
+        let deleted = tx
+            .execute("DELETE FROM messages WHERE peer_id = ?1", [peer_id as i64])
+            .map_err(MessageError::StorageError)?;
+        tx.execute("DELETE FROM file_transfers WHERE peer_id = ?1", [peer_id as i64])
+            .map_err(MessageError::StorageError)?;
+        tx.execute("DELETE FROM conversations WHERE peer_id = ?1", [peer_id as i64])
+            .map_err(MessageError::StorageError)?;
+
+        tx.commit().map_err(MessageError::StorageError)?;
+        Ok(deleted)
+    }
+
+    pub fn insert_transfer(&self, transfer: &StoredTransfer) -> Result<(), MessageError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(MessageError::StorageError)?;
+
+        tx.execute(
+            "INSERT OR REPLACE INTO file_transfers
+                (transfer_id, peer_id, peer_name, file_name, file_size, outgoing, status, file_path, timestamp)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                transfer.transfer_id,
+                transfer.peer_id as i64,
+                transfer.peer_name,
+                transfer.file_name,
+                transfer.file_size as i64,
+                transfer.outgoing,
+                transfer.status,
+                transfer.file_path,
+                transfer.timestamp as i64,
+            ],
+        )
+        .map_err(MessageError::StorageError)?;
+        upsert_conversation(&tx, transfer.peer_id, transfer.peer_name.as_deref(), None, transfer.timestamp)?;
+
+        tx.commit().map_err(MessageError::StorageError)
+    }
+
+    pub fn set_transfer_status(&self, transfer_id: &str, status: &str, file_path: Option<&str>) -> Result<(), MessageError> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "UPDATE file_transfers SET status = ?2, file_path = COALESCE(?3, file_path) WHERE transfer_id = ?1",
+                params![transfer_id, status, file_path],
+            )
+            .map_err(MessageError::StorageError)?;
+        Ok(())
+    }
+
+    pub fn transfers(&self, peer_id: u64) -> Result<Vec<StoredTransfer>, MessageError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare(
+                "SELECT transfer_id, peer_id, peer_name, file_name, file_size, outgoing, status, file_path, timestamp
+                 FROM file_transfers WHERE peer_id = ?1 ORDER BY timestamp DESC",
+            )
+            .map_err(MessageError::StorageError)?;
+
+        let rows = stmt
+            .query_map([peer_id as i64], |row| {
+                Ok(StoredTransfer {
+                    transfer_id: row.get(0)?,
+                    peer_id: row.get::<_, i64>(1)? as u64,
+                    peer_name: row.get(2)?,
+                    file_name: row.get(3)?,
+                    file_size: row.get::<_, i64>(4)? as u64,
+                    outgoing: row.get(5)?,
+                    status: row.get(6)?,
+                    file_path: row.get(7)?,
+                    timestamp: row.get::<_, i64>(8)? as u64,
+                })
+            })
+            .map_err(MessageError::StorageError)?;
+        rows.collect::<Result<_, _>>().map_err(MessageError::StorageError)
+    }
+
+    /// Records a room message; an ID that is already stored is left untouched.
+    pub fn insert_room_message(&self, message: &StoredRoomMessage) -> Result<bool, MessageError> {
+        let inserted = self
+            .conn
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT OR IGNORE INTO room_messages
+                    (message_id, room_id, sender_id, sender_name, content, timestamp, outgoing)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    message.message_id,
+                    message.room_id,
+                    message.sender_id as i64,
+                    message.sender_name,
+                    message.content,
+                    message.timestamp as i64,
+                    message.outgoing,
+                ],
+            )
+            .map_err(MessageError::StorageError)?;
+        Ok(inserted > 0)
+    }
+
+    /// One page of a room, newest first, paged like [`MessageStore::history`].
+    pub fn room_history(&self, room_id: &str, before_seq: Option<i64>, limit: u32) -> Result<Vec<StoredRoomMessage>, MessageError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare(
+                "SELECT rowid, message_id, room_id, sender_id, sender_name, content, timestamp, outgoing FROM room_messages
+                 WHERE room_id = ?1
+                   AND (?2 IS NULL OR (timestamp, rowid) < (SELECT timestamp, rowid FROM room_messages WHERE rowid = ?2))
+                 ORDER BY timestamp DESC, rowid DESC
+                 LIMIT ?3",
+            )
+            .map_err(MessageError::StorageError)?;
+
+        let rows = stmt
+            .query_map(params![room_id, before_seq, page_size(limit)], |row| {
+                Ok(StoredRoomMessage {
+                    seq: row.get(0)?,
+                    message_id: row.get(1)?,
+                    room_id: row.get(2)?,
+                    sender_id: row.get::<_, i64>(3)? as u64,
+                    sender_name: row.get(4)?,
+                    content: row.get(5)?,
+                    timestamp: row.get::<_, i64>(6)? as u64,
+                    outgoing: row.get(7)?,
+                })
+            })
+            .map_err(MessageError::StorageError)?;
+        rows.collect::<Result<_, _>>().map_err(MessageError::StorageError)
+    }
+}
+
+fn insert_message(
+    conn: &Connection,
+    message: &StoredMessage,
+    peer_name: Option<&str>,
+    peer_ip: Option<&str>,
+) -> Result<bool, MessageError> {
+    let inserted = conn
+        .execute(
+            "INSERT OR IGNORE INTO messages
+                (message_id, peer_id, sender_id, sender_name, target_id, content, timestamp, outgoing, status)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                message.message_id,
+                message.peer_id as i64,
+                message.sender_id as i64,
+                message.sender_name,
+                message.target_id as i64,
+                message.content,
+                message.timestamp as i64,
+                message.outgoing,
+                message.status,
+            ],
+        )
+        .map_err(MessageError::StorageError)?
+        > 0;
+
+    if inserted {
+        upsert_conversation(conn, message.peer_id, peer_name, peer_ip, message.timestamp)?;
+    }
+    Ok(inserted)
+}
+
+fn upsert_conversation(
+    conn: &Connection,
+    peer_id: u64,
+    peer_name: Option<&str>,
+    peer_ip: Option<&str>,
+    timestamp: u64,
+) -> Result<(), MessageError> {
+    conn.execute(
+        "INSERT INTO conversations (peer_id, peer_name, peer_ip, last_message_at) VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT (peer_id) DO UPDATE SET
+            peer_name = COALESCE(excluded.peer_name, peer_name),
+            peer_ip = COALESCE(excluded.peer_ip, peer_ip),
+            last_message_at = MAX(last_message_at, excluded.last_message_at)",
+        params![peer_id as i64, peer_name, peer_ip, timestamp as i64],
+    )
+    .map_err(MessageError::StorageError)?;
+    Ok(())
+}
+
+// Columns are `rowid` followed by `messages.*` in declaration order
+fn message_from_row(row: &Row) -> rusqlite::Result<StoredMessage> {
+    Ok(StoredMessage {
+        seq: row.get(0)?,
+        message_id: row.get(1)?,
+        peer_id: row.get::<_, i64>(2)? as u64,
+        sender_id: row.get::<_, i64>(3)? as u64,
+        sender_name: row.get(4)?,
+        target_id: row.get::<_, i64>(5)? as u64,
+        content: row.get(6)?,
+        timestamp: row.get::<_, i64>(7)? as u64,
+        outgoing: row.get(8)?,
+        status: row.get(9)?,
+    })
+}
+
+fn page_size(limit: u32) -> u32 {
+    if limit == 0 {
+        DEFAULT_PAGE_SIZE
+    } else {
+        limit.min(MAX_PAGE_SIZE)
+    }
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+/// Logs and swallows a history write failure; history must never break messaging.
+pub(crate) fn log_failure<T>(result: Result<T, MessageError>) {
+    if let Err(e) = result {
+        error!("Failed to update message history: {}", e);
+    }
+}
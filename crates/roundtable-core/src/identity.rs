@@ -0,0 +1,297 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine as _;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use aes_gcm::aead::OsRng;
+use log::{error, info};
+
+use crate::error::MessageError;
+use crate::history::now_millis;
+use crate::protocol::User;
+
+pub const IDENTITY_FILE: &str = "identity.key";
+pub const KNOWN_PEERS_FILE: &str = "known_peers.json";
+/// How far a signed user record's timestamp may be from our clock.
+pub const MAX_RECORD_AGE_MS: u64 = 10 * 60 * 1000;
+
+/// The long-term Ed25519 key of this installation, used to sign presence and messages.
+pub struct Identity {
+    signing_key: SigningKey,
+}
+
+impl Identity {
+    /// Loads the key from `path`, generating and saving a new one on first run.
+    pub fn load_or_create(path: &Path) -> io::Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(encoded) => {
+                let seed: [u8; 32] = BASE64
+                    .decode(encoded.trim())
+                    .ok()
+                    .and_then(|bytes| bytes.try_into().ok())
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Malformed identity key"))?;
+                Ok(Self { signing_key: SigningKey::from_bytes(&seed) })
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let signing_key = SigningKey::generate(&mut OsRng);
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                std::fs::write(path, BASE64.encode(signing_key.to_bytes()))?;
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::PermissionsExt;
+                    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+                }
+                info!("Generated new identity key at {}", path.display());
+                Ok(Self { signing_key })
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn public_key(&self) -> String {
+        BASE64.encode(self.signing_key.verifying_key().as_bytes())
+    }
+
+    pub fn sign(&self, bytes: &[u8]) -> String {
+        BASE64.encode(self.signing_key.sign(bytes).to_bytes())
+    }
+
+    /// Stamps our identity key, the time and a signature over the rest of the record
+    /// onto `user`.
+    pub fn sign_user(&self, user: &mut User) {
+        user.identity_key = Some(self.public_key());
+        user.timestamp = now_millis();
+        user.signature = Some(self.sign(&user_signing_bytes(user)));
+    }
+}
+
+pub fn verify(public_key: &str, bytes: &[u8], signature: &str) -> Result<(), MessageError> {
+    let key: [u8; 32] = BASE64
+        .decode(public_key)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| MessageError::CryptoError("Malformed identity key".to_string()))?;
+    let signature: [u8; 64] = BASE64
+        .decode(signature)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| MessageError::CryptoError("Malformed signature".to_string()))?;
+
+    VerifyingKey::from_bytes(&key)
+        .and_then(|key| key.verify(bytes, &Signature::from_bytes(&signature)))
+        .map_err(|_| MessageError::CryptoError("Invalid signature".to_string()))
+}
+
+/// Whether a signed record's `timestamp`, in Unix milliseconds, is recent enough
+/// not to be a replay.
+pub(crate) fn is_fresh(timestamp: u64) -> bool {
+    now_millis().abs_diff(timestamp) <= MAX_RECORD_AGE_MS
+}
+
+// `ip` is covered because receivers take an advertised address over the packet's source
+pub(crate) fn user_signing_bytes(user: &User) -> Vec<u8> {
+    serde_json::to_vec(&(
+        "roundtable-user-v2",
+        user.id,
+        &user.name,
+        &user.username,
+        &user.ip,
+        user.port,
+        &user.profile_picture,
+        &user.hostname,
+        &user.public_key,
+        &user.identity_key,
+        &user.capabilities,
+        user.timestamp,
+    ))
+    .unwrap_or_default()
+}
+
+/// What a file offer signature covers: everything the receiver acts on, and the
+/// time the sender's record was signed, so an old offer cannot be sent again.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn offer_signing_bytes(
+    transfer_id: &str,
+    file_name: &str,
+    file_size: u64,
+    hash: Option<&str>,
+    file_count: Option<u64>,
+    sender_id: u64,
+    timestamp: u64,
+) -> Vec<u8> {
+    serde_json::to_vec(&("roundtable-offer-v1", transfer_id, file_name, file_size, hash, file_count, sender_id, timestamp))
+        .unwrap_or_default()
+}
+
+/// What a file accept signature covers, as for an offer.
+pub(crate) fn accept_signing_bytes(transfer_id: &str, max_streams: u8, receiver_id: u64, timestamp: u64) -> Vec<u8> {
+    serde_json::to_vec(&("roundtable-accept-v1", transfer_id, max_streams, receiver_id, timestamp)).unwrap_or_default()
+}
+
+/// What a message signature covers. `content` is the content as sent, i.e. the
+/// ciphertext for encrypted messages and the whole message for chunked ones. Room
+/// messages also cover the room, and direct ones are signed as they always were.
+pub(crate) fn message_signing_bytes(
+    message_id: &str,
+    sender: &str,
+    sender_id: u64,
+    target_id: u64,
+    timestamp: u64,
+    content: &str,
+    room_id: Option<&str>,
+) -> Vec<u8> {
+    match room_id {
+        None => serde_json::to_vec(&("roundtable-message-v1", message_id, sender, sender_id, target_id, timestamp, content)),
+        Some(room_id) => serde_json::to_vec(&(
+            "roundtable-room-message-v1",
+            message_id,
+            sender,
+            sender_id,
+            target_id,
+            timestamp,
+            content,
+            room_id,
+        )),
+    }
+    .unwrap_or_default()
+}
+
+/// What an announcement signature covers.
+pub(crate) fn announcement_signing_bytes(
+    announcement_id: &str,
+    sender: &str,
+    sender_id: u64,
+    timestamp: u64,
+    content: &str,
+    urgent: bool,
+) -> Vec<u8> {
+    serde_json::to_vec(&("roundtable-announcement-v1", announcement_id, sender, sender_id, timestamp, content, urgent))
+        .unwrap_or_default()
+}
+
+/// What a room invite, update, leave or kick signature covers: the kind of change,
+/// who sent it to whom, and its payload.
+pub(crate) fn room_signing_bytes(kind: &str, sender_id: u64, target_id: u64, payload: &impl Serialize) -> Vec<u8> {
+    serde_json::to_vec(&("roundtable-room-v1", kind, sender_id, target_id, payload)).unwrap_or_default()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KnownPeer {
+    pub identity_key: String,
+    pub name: String,
+    /// Unix seconds of the first contact, when the key was pinned.
+    pub first_seen: u64,
+}
+
+pub enum Trust {
+    /// First contact; the key is now pinned.
+    New,
+    Known,
+    Changed { previous_key: String },
+}
+
+/// Trust-on-first-use store pinning each user ID to the identity key it first
+/// presented, persisted as JSON.
+#[derive(Clone)]
+pub struct KnownPeers {
+    path: PathBuf,
+    peers: Arc<Mutex<BTreeMap<u64, KnownPeer>>>,
+    // Last reported key per user, so repeated presence does not re-raise the warning
+    reported: Arc<Mutex<HashMap<u64, Option<String>>>>,
+    // Timestamp of the latest presence accepted from each user this session
+    presence: Arc<Mutex<HashMap<u64, u64>>>,
+}
+
+impl KnownPeers {
+    pub fn load(path: PathBuf) -> Self {
+        let peers = match std::fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+                error!("Ignoring unreadable known peers file {}: {}", path.display(), e);
+                BTreeMap::new()
+            }),
+            Err(_) => BTreeMap::new(),
+        };
+
+        Self {
+            path,
+            peers: Arc::new(Mutex::new(peers)),
+            reported: Arc::default(),
+            presence: Arc::default(),
+        }
+    }
+
+    pub fn pinned_key(&self, user_id: u64) -> Option<String> {
+        self.peers.lock().unwrap().get(&user_id).map(|peer| peer.identity_key.clone())
+    }
+
+    /// Compares `identity_key` with the pinned key for `user_id`, pinning it if there is none.
+    pub fn check(&self, user_id: u64, identity_key: &str, name: &str) -> Trust {
+        let mut peers = self.peers.lock().unwrap();
+        match peers.get(&user_id) {
+            Some(peer) if peer.identity_key == identity_key => Trust::Known,
+            Some(peer) => Trust::Changed { previous_key: peer.identity_key.clone() },
+            None => {
+                peers.insert(user_id, KnownPeer {
+                    identity_key: identity_key.to_string(),
+                    name: name.to_string(),
+                    first_seen: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+                });
+                self.save(&peers);
+                Trust::New
+            }
+        }
+    }
+
+    /// Unpins a user so the next key they present is trusted.
+    pub fn forget(&self, user_id: u64) -> bool {
+        let mut peers = self.peers.lock().unwrap();
+        let removed = peers.remove(&user_id).is_some();
+        if removed {
+            self.save(&peers);
+            self.reported.lock().unwrap().remove(&user_id);
+        }
+        removed
+    }
+
+    /// Records `timestamp` as the latest presence from `user_id`. False if it is no
+    /// newer than one already accepted, i.e. a copy or a replay.
+    pub fn advance_presence(&self, user_id: u64, timestamp: u64) -> bool {
+        let mut presence = self.presence.lock().unwrap();
+        let latest = presence.entry(user_id).or_default();
+        if timestamp <= *latest {
+            return false;
+        }
+        *latest = timestamp;
+        true
+    }
+
+    /// True the first time a given identity change is seen this session.
+    pub fn should_report(&self, user_id: u64, new_key: Option<&str>) -> bool {
+        let new_key = new_key.map(str::to_string);
+        let previous = self.reported.lock().unwrap().insert(user_id, new_key.clone());
+        previous != Some(new_key)
+    }
+
+    fn save(&self, peers: &BTreeMap<u64, KnownPeer>) {
+        let result = serde_json::to_vec_pretty(peers)
+            .map_err(io::Error::from)
+            .and_then(|bytes| {
+                if let Some(parent) = self.path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                let tmp = self.path.with_extension("json.tmp");
+                std::fs::write(&tmp, bytes)?;
+                std::fs::rename(&tmp, &self.path)
+            });
+
+        if let Err(e) = result {
+            error!("Failed to save known peers to {}: {}", self.path.display(), e);
+        }
+    }
+}
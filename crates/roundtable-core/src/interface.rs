@@ -0,0 +1,122 @@
+use if_addrs::{IfAddr, Interface};
+use serde::Serialize;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+use log::warn;
+
+/// A network interface other than loopback, as offered for selection.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct NetworkInterface {
+    pub name: String,
+    /// Also the scope ID of its link-local IPv6 addresses.
+    pub index: Option<u32>,
+    pub addresses: Vec<InterfaceAddress>,
+    /// Whether discovery is sent and joined on it.
+    pub selected: bool,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct InterfaceAddress {
+    pub ip: String,
+    pub prefix_len: u8,
+    /// The subnet's directed broadcast address; IPv4 only.
+    pub broadcast: Option<String>,
+}
+
+impl From<&IfAddr> for InterfaceAddress {
+    fn from(addr: &IfAddr) -> Self {
+        match addr {
+            IfAddr::V4(addr) => Self {
+                ip: addr.ip.to_string(),
+                prefix_len: addr.prefixlen,
+                broadcast: addr.broadcast.map(|broadcast| broadcast.to_string()),
+            },
+            IfAddr::V6(addr) => Self { ip: addr.ip.to_string(), prefix_len: addr.prefixlen, broadcast: None },
+        }
+    }
+}
+
+/// The interfaces discovery is broadcast, multicast and joined on, by name. None
+/// chosen means all of them.
+#[derive(Clone, Default)]
+pub struct InterfaceSelection {
+    names: Arc<RwLock<Vec<String>>>,
+}
+
+impl InterfaceSelection {
+    pub fn new(names: Vec<String>) -> Self {
+        let selection = Self::default();
+        selection.set(names);
+        selection
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.names.read().unwrap().clone()
+    }
+
+    pub(crate) fn set(&self, names: Vec<String>) {
+        let known = list_addresses();
+        for name in names.iter().filter(|name| !known.iter().any(|interface| &interface.name == *name)) {
+            warn!("Network interface {} is not up", name);
+        }
+        *self.names.write().unwrap() = names;
+    }
+
+    pub fn is_chosen(&self) -> bool {
+        !self.names.read().unwrap().is_empty()
+    }
+
+    pub fn includes(&self, name: &str) -> bool {
+        let names = self.names.read().unwrap();
+        names.is_empty() || names.iter().any(|chosen| chosen == name)
+    }
+
+    /// Every interface, marking the selected ones.
+    pub fn list(&self) -> Vec<NetworkInterface> {
+        let mut interfaces: Vec<NetworkInterface> = Vec::new();
+        for interface in list_addresses() {
+            let address = InterfaceAddress::from(&interface.addr);
+            match interfaces.iter_mut().find(|known| known.name == interface.name) {
+                Some(known) => known.addresses.push(address),
+                None => interfaces.push(NetworkInterface {
+                    selected: self.includes(&interface.name),
+                    name: interface.name,
+                    index: interface.index,
+                    addresses: vec![address],
+                }),
+            }
+        }
+        interfaces
+    }
+
+    /// The addresses of the selected interfaces, one entry per address.
+    pub(crate) fn addresses(&self) -> Vec<Interface> {
+        list_addresses().into_iter().filter(|interface| self.includes(&interface.name)).collect()
+    }
+
+    /// What to advertise in presence once interfaces are chosen: the IPv4 address of
+    /// the first chosen interface that is up, or else its first IPv6 address that is
+    /// not link-local, as a scope means nothing to other hosts.
+    pub fn advertised_ip(&self) -> Option<IpAddr> {
+        let addresses = list_addresses();
+        self.names().iter().find_map(|name| {
+            let ips = || addresses.iter().filter(|interface| &interface.name == name).map(Interface::ip);
+            ips()
+                .find(IpAddr::is_ipv4)
+                .or_else(|| ips().find(|ip| matches!(ip, IpAddr::V6(ip) if !ip.is_unicast_link_local())))
+        })
+    }
+}
+
+// Every address of every interface other than loopback
+fn list_addresses() -> Vec<Interface> {
+    match if_addrs::get_if_addrs() {
+        Ok(interfaces) => interfaces.into_iter().filter(|interface| !interface.is_loopback()).collect(),
+        Err(e) => {
+            warn!("Could not list network interfaces: {}", e);
+            Vec::new()
+        }
+    }
+}
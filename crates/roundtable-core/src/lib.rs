@@ -0,0 +1,54 @@
+//! Roundtable's LAN protocol and a headless engine that speaks it.
+//!
+//! The desktop app is a thin Tauri adapter over [`RoundtableEngine`]; anything else
+//! (scripts, tests, other front ends) can drive the same engine and consume its
+//! [`EngineEvent`] stream directly.
+
+pub mod announcement;
+pub mod chatlog;
+pub mod chunk;
+pub mod compression;
+pub mod crypto;
+pub mod delivery;
+pub mod engine;
+pub mod error;
+pub mod event;
+pub mod export;
+pub mod history;
+pub mod identity;
+pub mod interface;
+pub mod manifest;
+pub mod net;
+pub mod protocol;
+pub mod queue;
+pub mod room;
+pub mod transfer;
+
+pub use compression::Compression;
+pub use delivery::DeliveryStatus;
+pub use engine::{EngineConfig, OutgoingMessage, RoundtableEngine};
+pub use error::MessageError;
+pub use event::{EngineEvent, ReceivedMessage};
+pub use export::{ExportFormat, ExportRange, ImportSummary};
+pub use history::{Conversation, MessageStore, StoredMessage, StoredRoomMessage, StoredTransfer};
+pub use interface::{InterfaceAddress, NetworkInterface};
+pub use manifest::ManifestEntry;
+pub use protocol::{DiscoveryMessage, User};
+pub use queue::{QueueState, QueuedTransfer, TransferDirection, TransferPriority, DEFAULT_MAX_ACTIVE_TRANSFERS};
+pub use room::{Room, RoomMember};
+pub use transfer::{DownloadOutcome, OfferTarget, OutgoingFile, RecipientState, RecipientStatus, DEFAULT_TRANSFER_STREAMS};
+
+pub const DISCOVERY_PORT: u16 = 2425;
+
+/// Organization-local group that discovery is also multicast to, so it reaches
+/// peers past routers that forward multicast but drop broadcasts.
+pub const DISCOVERY_GROUP: std::net::Ipv4Addr = std::net::Ipv4Addr::new(239, 255, 24, 25);
+
+/// Link-local group that discovery is multicast to over IPv6, which has no broadcast.
+pub const DISCOVERY_GROUP_V6: std::net::Ipv6Addr = std::net::Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0x2425);
+
+#[cfg(debug_assertions)]
+pub const MSG_PORT: u16 = 2426;
+
+#[cfg(not(debug_assertions))]
+pub const MSG_PORT: u16 = 2427;
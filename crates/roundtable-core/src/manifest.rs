@@ -0,0 +1,238 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use crate::error::MessageError;
+use crate::transfer;
+
+/// Largest manifest a receiver will accept.
+pub const MAX_MANIFEST_BYTES: usize = 16 * 1024 * 1024;
+
+// Device names Windows reserves in every directory, with or without an extension
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9", "LPT1",
+    "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// One file of a multi-file transfer. Entries are streamed back to back in
+/// manifest order.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ManifestEntry {
+    /// Relative, `/`-separated path under the receiver's chosen directory.
+    pub path: String,
+    pub size: u64,
+    /// Unix permission bits, applied where the platform supports them.
+    #[serde(default)]
+    pub mode: Option<u32>,
+    /// `blake3:<hex>` digest of the file.
+    pub hash: String,
+}
+
+/// Walks `paths` into manifest entries, each with the local file it is read from.
+/// A directory keeps its own name as the top-level folder; symlinks are skipped.
+/// Reads and hashes every file, so call it off the async runtime.
+pub fn build(paths: &[PathBuf]) -> io::Result<Vec<(PathBuf, ManifestEntry)>> {
+    let mut entries = Vec::new();
+    for path in paths {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{} has no name", path.display())))?;
+        add(path, name, &mut entries)?;
+    }
+
+    if entries.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Nothing to send"));
+    }
+    Ok(entries)
+}
+
+fn add(path: &Path, relative: String, entries: &mut Vec<(PathBuf, ManifestEntry)>) -> io::Result<()> {
+    let metadata = std::fs::symlink_metadata(path)?;
+    if metadata.is_dir() {
+        let mut children = std::fs::read_dir(path)?.collect::<Result<Vec<_>, _>>()?;
+        // Sorted so the same tree always yields the same manifest
+        children.sort_by_key(|child| child.file_name());
+        for child in children {
+            let name = child.file_name().to_string_lossy().into_owned();
+            add(&child.path(), format!("{}/{}", relative, name), entries)?;
+        }
+    } else if metadata.is_file() {
+        entries.push((
+            path.to_path_buf(),
+            ManifestEntry {
+                path: relative,
+                size: metadata.len(),
+                mode: mode(&metadata),
+                hash: transfer::hash_path(path)?,
+            },
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn mode(metadata: &std::fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o777)
+}
+
+#[cfg(not(unix))]
+fn mode(_metadata: &std::fs::Metadata) -> Option<u32> {
+    None
+}
+
+/// The local path for a manifest `relative` path under `root`, refusing anything
+/// that could land outside it: absolute paths, `..`, drive prefixes and backslashes,
+/// as well as names Windows reserves for devices, on every platform so a manifest
+/// means the same everywhere.
+pub fn resolve(root: &Path, relative: &str) -> Result<PathBuf, MessageError> {
+    let invalid = || MessageError::InvalidData(format!("Unsafe path in manifest: {:?}", relative));
+
+    if relative.is_empty() || relative.contains('\\') || relative.contains('\0') {
+        return Err(invalid());
+    }
+    #[cfg(windows)]
+    if relative.contains(':') {
+        return Err(invalid());
+    }
+
+    let mut path = root.to_path_buf();
+    for part in relative.split('/') {
+        let mut components = Path::new(part).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(name)), None) if name == part && !is_reserved(part) => path.push(name),
+            _ => return Err(invalid()),
+        }
+    }
+    Ok(path)
+}
+
+fn is_reserved(name: &str) -> bool {
+    let stem = name.split('.').next().unwrap_or(name).trim_end();
+    RESERVED_NAMES.iter().any(|reserved| stem.eq_ignore_ascii_case(reserved))
+}
+
+/// Checks a received manifest before anything is written: every path must be safe
+/// and unique, no file may also be another entry's folder, both ignoring case since
+/// the receiver's file system may, and the sizes must add up to the size of the stream.
+pub fn validate(entries: &[ManifestEntry], total_size: u64) -> Result<(), MessageError> {
+    let mut files = HashSet::new();
+    let mut folders = HashSet::new();
+    let mut sum: u64 = 0;
+    for entry in entries {
+        resolve(Path::new(""), &entry.path)?;
+        let path = entry.path.to_lowercase();
+        if files.contains(&path) {
+            return Err(MessageError::InvalidData(format!("Duplicate path in manifest: {}", entry.path)));
+        }
+        let parents: Vec<&str> = path.match_indices('/').map(|(end, _)| &path[..end]).collect();
+        if folders.contains(&path) || parents.iter().any(|parent| files.contains(*parent)) {
+            return Err(MessageError::InvalidData(format!("Path in manifest is both a file and a folder: {}", entry.path)));
+        }
+        folders.extend(parents.into_iter().map(str::to_string));
+        files.insert(path);
+        sum = sum
+            .checked_add(entry.size)
+            .ok_or_else(|| MessageError::InvalidData("Manifest sizes overflow".to_string()))?;
+    }
+
+    if sum != total_size {
+        return Err(MessageError::InvalidData(format!(
+            "Manifest lists {} bytes but the transfer is {} bytes",
+            sum, total_size
+        )));
+    }
+    Ok(())
+}
+
+pub fn to_bytes(entries: &[ManifestEntry]) -> Vec<u8> {
+    serde_json::to_vec(entries).unwrap_or_default()
+}
+
+/// The digest carried in a multi-file offer, binding the offer to its manifest.
+pub fn digest(bytes: &[u8]) -> String {
+    format!("{}{}", transfer::HASH_PREFIX, blake3::hash(bytes).to_hex())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, size: u64) -> ManifestEntry {
+        ManifestEntry { path: path.to_string(), size, mode: None, hash: digest(b"") }
+    }
+
+    #[test]
+    fn resolves_relative_paths_under_root() {
+        let root = Path::new("/downloads");
+        assert_eq!(resolve(root, "photos/a.jpg").unwrap(), root.join("photos").join("a.jpg"));
+        assert_eq!(resolve(root, "CONSOLE.txt").unwrap(), root.join("CONSOLE.txt"));
+    }
+
+    #[test]
+    fn rejects_parent_components() {
+        for path in ["..", "../x", "a/../../x", "a/..", "./x", "a//b", "a/"] {
+            assert!(resolve(Path::new("/downloads"), path).is_err(), "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn rejects_absolute_paths() {
+        for path in ["/etc/passwd", "/", ""] {
+            assert!(resolve(Path::new("/downloads"), path).is_err(), "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn rejects_backslashes_and_nul() {
+        for path in ["a\\b", "..\\x", "\\x", "a\0b"] {
+            assert!(resolve(Path::new("/downloads"), path).is_err(), "{:?}", path);
+        }
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn rejects_drive_prefixes_and_streams() {
+        for path in ["C:x", "C:/x", "a/b:stream"] {
+            assert!(resolve(Path::new("C:/downloads"), path).is_err(), "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn rejects_reserved_windows_names() {
+        for path in ["CON", "nul", "aux.txt", "dir/Com1", "lpt9.tar.gz", "PRN /x"] {
+            assert!(resolve(Path::new("/downloads"), path).is_err(), "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn validate_rejects_unsafe_entries() {
+        assert!(validate(&[entry("ok", 1), entry("../x", 1)], 2).is_err());
+    }
+
+    #[test]
+    fn validate_rejects_duplicates_ignoring_case() {
+        assert!(validate(&[entry("a/x", 1), entry("a/x", 1)], 2).is_err());
+        assert!(validate(&[entry("A/x", 1), entry("a/x", 1)], 2).is_err());
+        assert!(validate(&[entry("a/x", 1), entry("a/y", 1)], 2).is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_files_that_are_also_folders() {
+        assert!(validate(&[entry("a", 1), entry("a/b", 1)], 2).is_err());
+        assert!(validate(&[entry("a/b/c", 1), entry("a/b", 1)], 2).is_err());
+        assert!(validate(&[entry("A", 1), entry("a/b", 1)], 2).is_err());
+        assert!(validate(&[entry("a/B/c", 1), entry("a/b", 1)], 2).is_err());
+        assert!(validate(&[entry("a/b", 1), entry("a/c", 1), entry("ab", 1)], 3).is_ok());
+    }
+
+    #[test]
+    fn validate_checks_total_size() {
+        assert!(validate(&[entry("a", 1), entry("b", 2)], 3).is_ok());
+        assert!(validate(&[entry("a", 1), entry("b", 2)], 4).is_err());
+        assert!(validate(&[entry("a", u64::MAX), entry("b", 1)], 0).is_err());
+    }
+}
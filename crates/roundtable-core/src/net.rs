@@ -0,0 +1,424 @@
+use if_addrs::{IfAddr, Ifv4Addr, Interface};
+use socket2::{Domain, SockRef, Socket, Type};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::sync::{Arc, Mutex};
+use tokio::net::UdpSocket;
+use log::warn;
+
+use crate::chunk::ChunkManager;
+use crate::error::MessageError;
+use crate::interface::InterfaceSelection;
+use crate::protocol::DiscoveryMessage;
+use crate::{DISCOVERY_GROUP, DISCOVERY_GROUP_V6};
+
+pub const BUFFER_SIZE: usize = 8192;
+pub const MAX_SINGLE_PACKET_SIZE: usize = 6000;
+const BUFFER_POOL_SIZE: usize = 50;
+
+// Optimized buffer pool // might update later for better memory management
+pub struct BufferPool {
+    buffers: Arc<Mutex<Vec<Vec<u8>>>>,
+    max_size: usize,
+}
+
+impl BufferPool {
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            buffers: Arc::new(Mutex::new(Vec::with_capacity(max_size))),
+            max_size,
+        }
+    }
+
+    pub fn get_buffer(&self) -> Vec<u8> {
+        self.buffers
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| Vec::with_capacity(BUFFER_SIZE))
+    }
+
+    pub fn return_buffer(&self, mut buf: Vec<u8>) {
+        if buf.capacity() >= BUFFER_SIZE {
+            buf.clear();
+            buf.resize(BUFFER_SIZE, 0);
+
+            let mut buffers = self.buffers.lock().unwrap();
+            if buffers.len() < self.max_size {
+                buffers.push(buf);
+            }
+        }
+    }
+}
+
+// Socket management
+pub struct SocketManager {
+    pub message_socket: Arc<UdpSocket>,
+    pub discovery_socket: Option<Arc<UdpSocket>>,
+    /// IPv6 sockets on the same ports; absent when the host has no IPv6.
+    pub message_socket_v6: Option<Arc<UdpSocket>>,
+    pub discovery_socket_v6: Option<Arc<UdpSocket>>,
+    pub discovery_port: u16,
+    pub message_port: u16,
+    pub(crate) buffer_pool: BufferPool,
+    pub(crate) chunk_manager: ChunkManager,
+    pub(crate) interfaces: InterfaceSelection,
+    /// Held while the outgoing multicast interface is switched for each send.
+    multicast_lock: tokio::sync::Mutex<()>,
+}
+
+impl SocketManager {
+    pub fn new(
+        message_socket: Arc<UdpSocket>,
+        discovery_socket: Option<Arc<UdpSocket>>,
+        message_socket_v6: Option<Arc<UdpSocket>>,
+        discovery_socket_v6: Option<Arc<UdpSocket>>,
+        discovery_port: u16,
+        message_port: u16,
+        interfaces: InterfaceSelection,
+    ) -> Self {
+        Self {
+            message_socket,
+            discovery_socket,
+            message_socket_v6,
+            discovery_socket_v6,
+            discovery_port,
+            message_port,
+            buffer_pool: BufferPool::new(BUFFER_POOL_SIZE),
+            chunk_manager: ChunkManager::new(),
+            interfaces,
+            multicast_lock: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// The message socket of `addr`'s address family.
+    pub(crate) fn socket_for(&self, addr: &SocketAddr) -> &Arc<UdpSocket> {
+        match (addr, &self.message_socket_v6) {
+            (SocketAddr::V6(_), Some(socket)) => socket,
+            _ => &self.message_socket,
+        }
+    }
+}
+
+/// Binds a UDP socket for broadcast and joins [`DISCOVERY_GROUP`] on the selected
+/// interfaces. `multicast_ttl` is how many routers our multicast may cross.
+pub fn create_socket(port: u16, multicast_ttl: u32, interfaces: &InterfaceSelection) -> std::io::Result<UdpSocket> {
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+
+    let raw_socket = Socket::new(Domain::IPV4, Type::DGRAM, None)?;
+    raw_socket.set_reuse_address(true)?;
+    raw_socket.set_nonblocking(true)?;
+    raw_socket.bind(&addr.into())?;
+    raw_socket.set_broadcast(true)?;
+
+    if let Err(e) = raw_socket.set_multicast_ttl_v4(multicast_ttl) {
+        warn!("Could not set multicast TTL: {}", e);
+    }
+    join_discovery_group(&raw_socket, &ipv4_interfaces(&interfaces.addresses()));
+    set_buffer_sizes(&raw_socket);
+
+    UdpSocket::from_std(raw_socket.into())
+}
+
+/// Binds the IPv6 counterpart of [`create_socket`] and joins [`DISCOVERY_GROUP_V6`]
+/// on the selected interfaces with IPv6.
+pub fn create_socket_v6(port: u16, interfaces: &InterfaceSelection) -> std::io::Result<UdpSocket> {
+    let addr = SocketAddr::from((Ipv6Addr::UNSPECIFIED, port));
+
+    let raw_socket = Socket::new(Domain::IPV6, Type::DGRAM, None)?;
+    // IPv4 has a socket of its own on the port
+    raw_socket.set_only_v6(true)?;
+    raw_socket.set_reuse_address(true)?;
+    raw_socket.set_nonblocking(true)?;
+    raw_socket.bind(&addr.into())?;
+
+    join_discovery_group_v6(&raw_socket, &ipv6_interfaces(&interfaces.addresses()));
+    set_buffer_sizes(&raw_socket);
+
+    UdpSocket::from_std(raw_socket.into())
+}
+
+/// Moves the discovery group memberships of every socket to newly selected interfaces.
+pub(crate) fn select_interfaces(socket_manager: &SocketManager, names: Vec<String>) {
+    let previous = socket_manager.interfaces.addresses();
+    socket_manager.interfaces.set(names);
+    let current = socket_manager.interfaces.addresses();
+
+    let ipv4_sockets = [Some(&socket_manager.message_socket), socket_manager.discovery_socket.as_ref()];
+    for socket in ipv4_sockets.into_iter().flatten() {
+        let socket = SockRef::from(socket.as_ref());
+        // Including the fallback, should no interface have had an address
+        for ip in one_per_interface(&ipv4_interfaces(&previous)).into_iter().chain([Ipv4Addr::UNSPECIFIED]) {
+            let _ = socket.leave_multicast_v4(&DISCOVERY_GROUP, &ip);
+        }
+        join_discovery_group(&socket, &ipv4_interfaces(&current));
+    }
+
+    let ipv6_sockets = [socket_manager.message_socket_v6.as_ref(), socket_manager.discovery_socket_v6.as_ref()];
+    for socket in ipv6_sockets.into_iter().flatten() {
+        let socket = SockRef::from(socket.as_ref());
+        for index in ipv6_interfaces(&previous).into_iter().chain([0]) {
+            let _ = socket.leave_multicast_v6(&DISCOVERY_GROUP_V6, index);
+        }
+        join_discovery_group_v6(&socket, &ipv6_interfaces(&current));
+    }
+}
+
+fn set_buffer_sizes(socket: &Socket) {
+    if let Err(e) = socket.set_recv_buffer_size(BUFFER_SIZE * 4) {
+        warn!("Could not set receive buffer size: {}", e);
+    }
+    if let Err(e) = socket.set_send_buffer_size(BUFFER_SIZE * 4) {
+        warn!("Could not set send buffer size: {}", e);
+    }
+}
+
+/// Sends a message to everyone reachable: to the directed broadcast address of each
+/// interface on both ports, to [`DISCOVERY_GROUP`] out of each interface on the
+/// discovery port, and to [`DISCOVERY_GROUP_V6`] on each IPv6 interface on both ports.
+pub async fn broadcast_message(
+    socket_manager: &SocketManager,
+    message: &DiscoveryMessage
+) -> Result<(), MessageError> {
+    message.validate()?;
+
+    let message_bytes = serde_json::to_vec(message)
+        .map_err(MessageError::SerializationError)?;
+
+    let discovery_socket = socket_manager.discovery_socket.as_ref()
+        .unwrap_or(&socket_manager.message_socket);
+    let interfaces = socket_manager.interfaces.addresses();
+    // Falling back to the default route would pick exactly what the user ruled out
+    if interfaces.is_empty() && socket_manager.interfaces.is_chosen() {
+        warn!("None of the chosen network interfaces is up; nothing was sent");
+        return Ok(());
+    }
+    let ipv4 = ipv4_interfaces(&interfaces);
+
+    send_to_group(socket_manager, discovery_socket, &message_bytes, &ipv4).await;
+
+    for broadcast in broadcast_addresses(&ipv4) {
+        let discovery_addr = SocketAddr::from((broadcast, socket_manager.discovery_port));
+        if let Err(e) = discovery_socket.send_to(&message_bytes, discovery_addr).await {
+            warn!("Failed to send to discovery port at {}: {}", broadcast, e);
+        }
+
+        if socket_manager.discovery_port != socket_manager.message_port {
+            let msg_addr = SocketAddr::from((broadcast, socket_manager.message_port));
+            if let Err(e) = socket_manager.message_socket.send_to(&message_bytes, msg_addr).await {
+                warn!("Failed to send to message port at {}: {}", broadcast, e);
+            }
+        }
+    }
+
+    // IPv6 has no broadcast; the link-local group reaches the same peers
+    let ipv6 = ipv6_interfaces(&interfaces);
+    let discovery_socket_v6 = socket_manager.discovery_socket_v6.as_ref()
+        .or(socket_manager.message_socket_v6.as_ref());
+    if let Some(socket) = discovery_socket_v6 {
+        send_to_group_v6(socket, &message_bytes, socket_manager.discovery_port, &ipv6).await;
+    }
+    if socket_manager.discovery_port != socket_manager.message_port {
+        if let Some(socket) = &socket_manager.message_socket_v6 {
+            send_to_group_v6(socket, &message_bytes, socket_manager.message_port, &ipv6).await;
+        }
+    }
+
+    Ok(())
+}
+
+/// IPv4 addresses of the interfaces, with their interface names.
+fn ipv4_interfaces(interfaces: &[Interface]) -> Vec<(String, Ifv4Addr)> {
+    interfaces
+        .iter()
+        .filter_map(|interface| match &interface.addr {
+            IfAddr::V4(addr) => Some((interface.name.clone(), addr.clone())),
+            IfAddr::V6(_) => None,
+        })
+        .collect()
+}
+
+/// Indexes of the interfaces with IPv6, which are also their link-local scope IDs.
+fn ipv6_interfaces(interfaces: &[Interface]) -> Vec<u32> {
+    let mut indexes = Vec::new();
+    for interface in interfaces {
+        if let (IfAddr::V6(_), Some(index)) = (&interface.addr, interface.index) {
+            if !indexes.contains(&index) {
+                indexes.push(index);
+            }
+        }
+    }
+    indexes
+}
+
+/// One address per interface, which is what multicast membership is keyed on.
+fn one_per_interface(interfaces: &[(String, Ifv4Addr)]) -> Vec<Ipv4Addr> {
+    let mut seen = HashSet::new();
+    interfaces
+        .iter()
+        .filter(|(name, _)| seen.insert(name.as_str()))
+        .map(|(_, addr)| addr.ip)
+        .collect()
+}
+
+/// Each subnet's directed broadcast address, or the limited broadcast address when
+/// no interface has one.
+fn broadcast_addresses(interfaces: &[(String, Ifv4Addr)]) -> Vec<Ipv4Addr> {
+    let mut addresses = Vec::new();
+    for broadcast in interfaces.iter().filter_map(|(_, addr)| addr.broadcast) {
+        if !addresses.contains(&broadcast) {
+            addresses.push(broadcast);
+        }
+    }
+    if addresses.is_empty() {
+        addresses.push(Ipv4Addr::BROADCAST);
+    }
+    addresses
+}
+
+/// Failing to join only costs us multicast discovery; broadcasts still arrive.
+fn join_discovery_group(socket: &Socket, interfaces: &[(String, Ifv4Addr)]) {
+    let mut joined = false;
+    for ip in one_per_interface(interfaces) {
+        match socket.join_multicast_v4(&DISCOVERY_GROUP, &ip) {
+            Ok(()) => joined = true,
+            Err(e) => warn!("Could not join discovery group on {}: {}", ip, e),
+        }
+    }
+    if !joined {
+        if let Err(e) = socket.join_multicast_v4(&DISCOVERY_GROUP, &Ipv4Addr::UNSPECIFIED) {
+            warn!("Could not join discovery group: {}", e);
+        }
+    }
+}
+
+// Index 0 lets the system pick when no interface has IPv6 yet
+fn join_discovery_group_v6(socket: &Socket, indexes: &[u32]) {
+    let indexes = if indexes.is_empty() { &[0][..] } else { indexes };
+    for &index in indexes {
+        if let Err(e) = socket.join_multicast_v6(&DISCOVERY_GROUP_V6, index) {
+            warn!("Could not join IPv6 discovery group on interface {}: {}", index, e);
+        }
+    }
+}
+
+/// Multicast leaves by a single interface, so a copy is sent out of each one.
+async fn send_to_group(
+    socket_manager: &SocketManager,
+    socket: &UdpSocket,
+    message_bytes: &[u8],
+    interfaces: &[(String, Ifv4Addr)],
+) {
+    let group = SocketAddr::from((DISCOVERY_GROUP, socket_manager.discovery_port));
+    let mut sources = one_per_interface(interfaces);
+    if sources.is_empty() {
+        sources.push(Ipv4Addr::UNSPECIFIED);
+    }
+
+    let _guard = socket_manager.multicast_lock.lock().await;
+    for ip in sources {
+        if let Err(e) = SockRef::from(socket).set_multicast_if_v4(&ip) {
+            warn!("Could not multicast from {}: {}", ip, e);
+            continue;
+        }
+        if let Err(e) = socket.send_to(message_bytes, group).await {
+            warn!("Failed to send to discovery group from {}: {}", ip, e);
+        }
+    }
+}
+
+/// A link-local group has one instance per link, named by the interface's scope ID.
+async fn send_to_group_v6(socket: &UdpSocket, message_bytes: &[u8], port: u16, indexes: &[u32]) {
+    for &index in indexes {
+        let group = SocketAddrV6::new(DISCOVERY_GROUP_V6, port, 0, index);
+        if let Err(e) = socket.send_to(message_bytes, group).await {
+            warn!("Failed to send to IPv6 discovery group on interface {}: {}", index, e);
+        }
+    }
+}
+
+/// How a peer's address is recorded, e.g. in `User.ip`: IPv4-mapped IPv6 as plain
+/// IPv4, and link-local IPv6 with the `%scope` of the interface it was heard on.
+pub fn peer_ip(addr: SocketAddr) -> String {
+    match addr {
+        SocketAddr::V6(addr) if addr.ip().is_unicast_link_local() && addr.scope_id() != 0 => {
+            format!("{}%{}", addr.ip(), addr.scope_id())
+        }
+        _ => addr.ip().to_canonical().to_string(),
+    }
+}
+
+/// The address each peer was last seen at, by user ID, for checking where messages
+/// claiming to be from them come from.
+#[derive(Clone, Default)]
+pub struct PeerAddresses {
+    addresses: Arc<Mutex<HashMap<u64, IpAddr>>>,
+}
+
+impl PeerAddresses {
+    /// Records `ip`, as recorded by [`peer_ip`], for `user_id`.
+    pub fn insert(&self, user_id: u64, ip: &str) {
+        if let Some(ip) = parse_ip(ip) {
+            self.addresses.lock().unwrap().insert(user_id, ip);
+        }
+    }
+
+    /// False for peers we have not seen.
+    pub fn is_at(&self, user_id: u64, ip: IpAddr) -> bool {
+        self.addresses.lock().unwrap().get(&user_id) == Some(&ip.to_canonical())
+    }
+}
+
+/// The IP of an address recorded by [`peer_ip`], without its scope.
+pub fn parse_ip(ip: &str) -> Option<IpAddr> {
+    let ip = ip.split_once('%').map_or(ip, |(ip, _)| ip);
+    ip.parse::<IpAddr>().ok().map(|ip| ip.to_canonical())
+}
+
+/// Where to reach a peer at an address recorded by [`peer_ip`]. A scope may also
+/// name the interface, as in `fe80::1%eth0`.
+pub fn peer_addr(ip: &str, port: u16) -> Result<SocketAddr, MessageError> {
+    let invalid = || MessageError::InvalidData(format!("Invalid peer address: {}", ip));
+    let (address, scope) = match ip.split_once('%') {
+        Some((address, scope)) => (address, Some(scope)),
+        None => (ip, None),
+    };
+
+    match (address.parse::<IpAddr>().map_err(|_| invalid())?.to_canonical(), scope) {
+        (IpAddr::V4(address), None) => Ok(SocketAddr::from((address, port))),
+        (IpAddr::V4(_), Some(_)) => Err(invalid()),
+        (IpAddr::V6(address), scope) => {
+            let scope_id = match scope {
+                Some(scope) => scope_id(scope).ok_or_else(invalid)?,
+                None => 0,
+            };
+            Ok(SocketAddrV6::new(address, port, 0, scope_id).into())
+        }
+    }
+}
+
+fn scope_id(scope: &str) -> Option<u32> {
+    scope.parse().ok().or_else(|| {
+        if_addrs::get_if_addrs()
+            .ok()?
+            .into_iter()
+            .find(|interface| interface.name == scope)?
+            .index
+    })
+}
+
+/// Serializes a message and sends it to a single peer.
+pub async fn send_to(
+    socket_manager: &SocketManager,
+    message: &DiscoveryMessage,
+    target_addr: SocketAddr,
+) -> Result<usize, MessageError> {
+    let message_bytes = serde_json::to_vec(message)
+        .map_err(MessageError::SerializationError)?;
+
+    socket_manager
+        .socket_for(&target_addr)
+        .send_to(&message_bytes, target_addr)
+        .await
+        .map_err(MessageError::NetworkError)
+}
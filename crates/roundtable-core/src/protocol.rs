@@ -0,0 +1,348 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::announcement::MAX_ANNOUNCEMENT_SIZE;
+use crate::chunk::CHUNK_SIZE;
+use crate::compression::{Compression, ZSTD_CAPABILITY};
+use crate::crypto::Envelope;
+use crate::error::MessageError;
+use crate::room::Room;
+use crate::transfer::HASH_PREFIX;
+
+// User data structure
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct User {
+    pub id: u64,
+    pub name: String,
+    pub username: String,
+    pub ip: String,
+    pub port: u16,
+    pub profile_picture: Option<String>,
+    pub hostname: Option<String>,
+    /// X25519 key for end-to-end encryption; absent from older peers.
+    #[serde(default)]
+    pub public_key: Option<String>,
+    /// Long-term Ed25519 key of the installation, pinned by peers on first contact.
+    #[serde(default)]
+    pub identity_key: Option<String>,
+    /// Signature by `identity_key` over every other field, including the advertised
+    /// `ip`. Receivers check it before replacing `ip` with the one they reach the
+    /// peer at.
+    #[serde(default)]
+    pub signature: Option<String>,
+    /// Optional protocol features the peer understands; see [`local_capabilities`].
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    /// Unix milliseconds when the record was signed. Receivers drop signed records
+    /// more than ten minutes off their clock, and presence no newer than the last
+    /// they accepted from the same user.
+    #[serde(default)]
+    pub timestamp: u64,
+}
+
+/// What this version advertises in presence.
+pub fn local_capabilities() -> Vec<String> {
+    vec![ZSTD_CAPABILITY.to_string()]
+}
+
+/// Capabilities peers have advertised, by user ID.
+#[derive(Clone, Default)]
+pub struct PeerCapabilities {
+    capabilities: Arc<Mutex<HashMap<u64, Vec<String>>>>,
+}
+
+impl PeerCapabilities {
+    pub fn insert(&self, user_id: u64, capabilities: Vec<String>) {
+        self.capabilities.lock().unwrap().insert(user_id, capabilities);
+    }
+
+    /// Peers we have not heard from are assumed to support nothing optional.
+    pub fn supports(&self, user_id: u64, capability: &str) -> bool {
+        self.capabilities
+            .lock()
+            .unwrap()
+            .get(&user_id)
+            .is_some_and(|capabilities| capabilities.iter().any(|c| c == capability))
+    }
+}
+
+impl User {
+    pub fn validate(&self) -> Result<(), MessageError> {
+        if self.name.is_empty() {
+            return Err(MessageError::InvalidData("Username cannot be empty".to_string()));
+        }
+        if self.name.len() > 100 {
+            return Err(MessageError::InvalidData("Username too long".to_string()));
+        }
+        Ok(())
+    }
+}
+
+// Improved message types
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum DiscoveryMessage {
+    Online(User),
+    Offline(User),
+    Response(User),
+    Query,
+    Message {
+        content: String,
+        sender: String,
+        sender_id: u64,
+        target_id: u64,
+        sender_port: u16,
+        timestamp: u64,
+        // Older peers send no ID and are never acknowledged
+        #[serde(default)]
+        message_id: String,
+        /// Set when `content` is base64 ciphertext.
+        #[serde(default)]
+        encryption: Option<Envelope>,
+        #[serde(default)]
+        signature: Option<String>,
+        /// Set when the content was compressed before any encryption; unencrypted,
+        /// `content` is then the compressed bytes in base64.
+        #[serde(default)]
+        compression: Option<Compression>,
+        /// Set for a room message; each member is sent their own copy.
+        #[serde(default)]
+        room_id: Option<String>,
+    },
+    /// The chunk ID is the sender's message ID.
+    ChunkedMessage {
+        chunk_id: String,
+        chunk_index: u16,
+        total_chunks: u16,
+        content: String,
+        sender: String,
+        sender_id: u64,
+        target_id: u64,
+        sender_port: u16,
+        timestamp: u64,
+        /// The whole message is encrypted before it is split.
+        #[serde(default)]
+        encryption: Option<Envelope>,
+        #[serde(default)]
+        signature: Option<String>,
+        /// As for `Message`; the whole message is compressed before it is split.
+        #[serde(default)]
+        compression: Option<Compression>,
+        #[serde(default)]
+        room_id: Option<String>,
+    },
+    /// Delivery receipt; also serves as the transport-level ACK.
+    Delivered {
+        message_id: String,
+        receiver_id: u64,
+    },
+    Read {
+        message_id: String,
+        reader_id: u64,
+    },
+    Typing {
+        sender_id: u64,
+        target_id: u64,
+        is_typing: bool,
+    },
+    ChunkNack {
+        chunk_id: String,
+        missing: Vec<u16>,
+    },
+    FileOffer {
+        sender: User,
+        file_name: String,
+        file_size: u64,
+        transfer_id: String,
+        /// `blake3:<hex>` digest of the file, checked by the receiver once it is
+        /// saved. For a multi-file offer, the digest of the manifest.
+        #[serde(default)]
+        hash: Option<String>,
+        /// Set for a multi-file offer, whose manifest is sent on the transfer stream.
+        #[serde(default)]
+        file_count: Option<u64>,
+        /// Signature by the sender's identity key over the offer; see
+        /// `identity::offer_signing_bytes`.
+        #[serde(default)]
+        signature: Option<String>,
+    },
+    FileAccept {
+        receiver: User,
+        transfer_id: String,
+        /// Most connections the receiver will fetch the file over; 0 from peers
+        /// that predate ranged transfers.
+        #[serde(default)]
+        max_streams: u8,
+        #[serde(default)]
+        signature: Option<String>,
+    },
+    FileReject {
+        transfer_id: String,
+        /// Who declined, so a one-to-many offer can tell its recipients apart.
+        #[serde(default)]
+        receiver_id: Option<u64>,
+    },
+    /// Stops a transfer for good. From a recipient it withdraws only that recipient;
+    /// from the sender (`user_id` unset) it ends the transfer for everyone.
+    FileCancel {
+        transfer_id: String,
+        user_id: Option<u64>,
+    },
+    /// Holds the data stream until a `FileResume`; `user_id` as for `FileCancel`.
+    FilePause {
+        transfer_id: String,
+        user_id: Option<u64>,
+    },
+    FileResume {
+        transfer_id: String,
+        user_id: Option<u64>,
+    },
+    TransferReady {
+        transfer_id: String,
+        tcp_port: u16,
+        #[serde(default)]
+        encrypted: bool,
+        /// Connections the receiver may split the file over; 0 means one.
+        #[serde(default)]
+        streams: u8,
+        /// One-time token, base64, the receiver presents when it connects.
+        #[serde(default)]
+        token: String,
+        /// Set when the data is sent in compressed frames.
+        #[serde(default)]
+        compression: Option<Compression>,
+    },
+    /// Adds `target_id` to a room, with its current name and members.
+    RoomInvite {
+        room: Room,
+        sender_id: u64,
+        target_id: u64,
+        #[serde(default)]
+        signature: Option<String>,
+    },
+    /// A room's name or members changed; kept only if `room.version` is newer.
+    RoomUpdate {
+        room: Room,
+        sender_id: u64,
+        target_id: u64,
+        #[serde(default)]
+        signature: Option<String>,
+    },
+    /// `user_id` left the room; `version` is the room's version without them.
+    RoomLeave {
+        room_id: String,
+        user_id: u64,
+        target_id: u64,
+        version: u64,
+        #[serde(default)]
+        signature: Option<String>,
+    },
+    /// The owner (`sender_id`) removed `user_id`, who is sent one too.
+    RoomKick {
+        room_id: String,
+        user_id: u64,
+        sender_id: u64,
+        target_id: u64,
+        version: u64,
+        #[serde(default)]
+        signature: Option<String>,
+    },
+    /// A notice for everyone on the subnet. Sent by broadcast, so it is never
+    /// encrypted, but it must be signed; receivers check the key against a pinned
+    /// one but never pin it.
+    Announcement {
+        announcement_id: String,
+        sender: String,
+        sender_id: u64,
+        content: String,
+        timestamp: u64,
+        #[serde(default)]
+        urgent: bool,
+        identity_key: String,
+        signature: String,
+    },
+}
+
+impl DiscoveryMessage {
+    pub fn validate(&self) -> Result<(), MessageError> {
+        match self {
+            DiscoveryMessage::Online(user) |
+            DiscoveryMessage::Offline(user) |
+            DiscoveryMessage::Response(user) => user.validate(),
+
+            DiscoveryMessage::Message { content, sender, .. } => {
+                if content.len() > 100_000 {
+                    return Err(MessageError::InvalidData("Message too long".to_string()));
+                }
+                if sender.is_empty() {
+                    return Err(MessageError::InvalidData("Sender name required".to_string()));
+                }
+                Ok(())
+            },
+
+            DiscoveryMessage::ChunkedMessage { content, sender, total_chunks, .. } => {
+                if content.len() > CHUNK_SIZE {
+                    return Err(MessageError::InvalidData("Chunk too large".to_string()));
+                }
+                if sender.is_empty() {
+                    return Err(MessageError::InvalidData("Sender name required".to_string()));
+                }
+                if *total_chunks == 0 || *total_chunks > 1000 {
+                    return Err(MessageError::InvalidData("Invalid chunk count".to_string()));
+                }
+                Ok(())
+            },
+
+            DiscoveryMessage::Delivered { .. } => Ok(()),
+            DiscoveryMessage::Read { message_id, .. } => {
+                if message_id.is_empty() {
+                    return Err(MessageError::InvalidData("Message ID required".to_string()));
+                }
+                Ok(())
+            },
+            DiscoveryMessage::Typing { .. } => Ok(()),
+            DiscoveryMessage::ChunkNack { missing, .. } => {
+                if missing.is_empty() || missing.len() > 1000 {
+                    return Err(MessageError::InvalidData("Invalid chunk list".to_string()));
+                }
+                Ok(())
+            },
+
+            DiscoveryMessage::FileOffer { file_name, hash, .. } => {
+                if file_name.is_empty() {
+                    return Err(MessageError::InvalidData("File name cannot be empty".to_string()));
+                }
+                if hash.as_deref().is_some_and(|hash| !hash.starts_with(HASH_PREFIX)) {
+                    return Err(MessageError::InvalidData("Unsupported file hash".to_string()));
+                }
+                Ok(())
+            },
+            DiscoveryMessage::FileAccept { .. } => Ok(()),
+            DiscoveryMessage::FileReject { .. } => Ok(()),
+            DiscoveryMessage::FileCancel { .. } |
+            DiscoveryMessage::FilePause { .. } |
+            DiscoveryMessage::FileResume { .. } => Ok(()),
+            DiscoveryMessage::TransferReady { .. } => Ok(()),
+            DiscoveryMessage::RoomInvite { room, .. } |
+            DiscoveryMessage::RoomUpdate { room, .. } => room.validate(),
+            DiscoveryMessage::Announcement { announcement_id, sender, content, .. } => {
+                if announcement_id.is_empty() {
+                    return Err(MessageError::InvalidData("Announcement ID required".to_string()));
+                }
+                if sender.is_empty() || sender.len() > 100 {
+                    return Err(MessageError::InvalidData("Invalid sender name".to_string()));
+                }
+                if content.trim().is_empty() {
+                    return Err(MessageError::InvalidData("Announcement cannot be empty".to_string()));
+                }
+                if content.len() > MAX_ANNOUNCEMENT_SIZE {
+                    return Err(MessageError::InvalidData("Announcement too long".to_string()));
+                }
+                Ok(())
+            },
+            DiscoveryMessage::RoomLeave { .. } |
+            DiscoveryMessage::RoomKick { .. } => Ok(()),
+            DiscoveryMessage::Query => Ok(()),
+        }
+    }
+}
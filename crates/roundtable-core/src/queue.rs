@@ -0,0 +1,476 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+/// Transfers moving data at once in each direction unless configured otherwise.
+pub const DEFAULT_MAX_ACTIVE_TRANSFERS: usize = 3;
+/// Bursts a rate limit allows: a tenth of a second's worth, but at least a few chunks.
+const BURST_DIVISOR: u64 = 10;
+const MIN_BURST: u64 = 64 * 1024;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferDirection {
+    Outgoing,
+    Incoming,
+}
+
+/// Queued transfers start highest priority first, and in the order they were
+/// queued within a priority.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferPriority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum QueueState {
+    /// Waiting for a running transfer in the same direction to finish.
+    Queued,
+    Active,
+    /// Active, but held by either side.
+    Paused,
+}
+
+/// One transfer as listed by [`crate::RoundtableEngine::transfer_queue`].
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct QueuedTransfer {
+    pub transfer_id: String,
+    pub direction: TransferDirection,
+    pub state: QueueState,
+    pub priority: TransferPriority,
+    /// This transfer's own limit in bytes per second, on top of the global one.
+    pub rate_limit: Option<u64>,
+    /// For a file sent to several recipients, bytes to all of them.
+    pub transferred: u64,
+    pub size: u64,
+    /// Average since the transfer became active.
+    pub bytes_per_second: u64,
+    pub eta_seconds: Option<u64>,
+}
+
+// Refills at `rate` bytes per second up to `burst`. Taking more than is there leaves
+// a debt the caller waits off, so chunks larger than the burst still pass.
+struct TokenBucket {
+    rate: u64,
+    burst: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        let rate = rate.max(1);
+        let burst = (rate / BURST_DIVISOR).max(MIN_BURST) as f64;
+        Self { rate, burst, tokens: burst, updated: Instant::now() }
+    }
+
+    // How long to wait before sending `n` more bytes
+    fn take(&mut self, n: usize) -> Duration {
+        let now = Instant::now();
+        let rate = self.rate as f64;
+        self.tokens = (self.tokens + now.duration_since(self.updated).as_secs_f64() * rate).min(self.burst);
+        self.updated = now;
+        self.tokens -= n as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate)
+        }
+    }
+}
+
+type Key = (TransferDirection, String);
+
+struct Entry {
+    priority: TransferPriority,
+    /// Order of arrival, for equal priorities.
+    seq: u64,
+    /// Connections or tasks still waiting to start, and ones moving data.
+    waiting: usize,
+    slots: usize,
+    limit: Option<TokenBucket>,
+    size: u64,
+    baseline: Option<u64>,
+    moved: u64,
+    started: Option<Instant>,
+}
+
+impl Entry {
+    fn is_active(&self) -> bool {
+        self.slots > 0
+    }
+
+    fn listing(&self, (direction, transfer_id): &Key) -> QueuedTransfer {
+        let transferred = self.baseline.unwrap_or(0) + self.moved;
+        let elapsed = self.started.map_or(0.0, |started| started.elapsed().as_secs_f64());
+        let bytes_per_second = if elapsed > 0.0 { (self.moved as f64 / elapsed) as u64 } else { 0 };
+        QueuedTransfer {
+            transfer_id: transfer_id.clone(),
+            direction: *direction,
+            state: if self.is_active() { QueueState::Active } else { QueueState::Queued },
+            priority: self.priority,
+            rate_limit: self.limit.as_ref().map(|bucket| bucket.rate),
+            transferred,
+            size: self.size,
+            bytes_per_second,
+            eta_seconds: (bytes_per_second > 0 && self.size > transferred)
+                .then(|| (self.size - transferred).div_ceil(bytes_per_second)),
+        }
+    }
+}
+
+// Settings made before a transfer joined the queue
+#[derive(Default)]
+struct Preset {
+    priority: TransferPriority,
+    rate_limit: Option<u64>,
+}
+
+struct QueueInner {
+    /// Per direction; 0 means no limit.
+    max_active: usize,
+    global: Option<TokenBucket>,
+    entries: HashMap<Key, Entry>,
+    presets: HashMap<String, Preset>,
+    next_seq: u64,
+}
+
+impl QueueInner {
+    // A waiting transfer may start when it is already running, or when it heads its
+    // direction's queue and a slot is free
+    fn may_start(&self, key: &Key) -> bool {
+        let Some(entry) = self.entries.get(key) else {
+            return false;
+        };
+        if entry.is_active() {
+            return true;
+        }
+
+        let direction = key.0;
+        let in_direction = || self.entries.iter().filter(move |((dir, _), _)| *dir == direction);
+        let active = in_direction().filter(|(_, entry)| entry.is_active()).count();
+        if self.max_active != 0 && active >= self.max_active {
+            return false;
+        }
+        in_direction()
+            .filter(|(_, other)| !other.is_active() && other.waiting > 0)
+            .all(|(_, other)| (other.priority, std::cmp::Reverse(other.seq)) <= (entry.priority, std::cmp::Reverse(entry.seq)))
+    }
+
+    fn forget_if_idle(&mut self, key: &Key) {
+        if self.entries.get(key).is_some_and(|entry| entry.waiting == 0 && entry.slots == 0) {
+            self.entries.remove(key);
+        }
+    }
+}
+
+/// Decides which transfers move data and how fast. Each direction runs at most
+/// `max_active` transfers at once; the rest wait their turn by priority. Every byte
+/// is charged to a global token bucket and to the transfer's own, when set.
+#[derive(Clone)]
+pub struct TransferQueue {
+    inner: Arc<Mutex<QueueInner>>,
+    changed: Arc<Notify>,
+}
+
+impl TransferQueue {
+    pub fn new(max_active: usize, rate_limit: Option<u64>) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(QueueInner {
+                max_active,
+                global: rate_limit.map(TokenBucket::new),
+                entries: HashMap::new(),
+                presets: HashMap::new(),
+                next_seq: 0,
+            })),
+            changed: Arc::new(Notify::new()),
+        }
+    }
+
+    pub fn set_max_active(&self, max_active: usize) {
+        self.inner.lock().unwrap().max_active = max_active;
+        self.changed.notify_waiters();
+    }
+
+    /// Caps all transfers together, in bytes per second.
+    pub fn set_rate_limit(&self, rate_limit: Option<u64>) {
+        self.inner.lock().unwrap().global = rate_limit.map(TokenBucket::new);
+    }
+
+    /// Applies to the transfer now if it is queued or running, or else once it joins.
+    pub fn set_priority(&self, transfer_id: &str, priority: TransferPriority) {
+        self.update(transfer_id, |entry| entry.priority = priority, |preset| preset.priority = priority);
+        self.changed.notify_waiters();
+    }
+
+    /// Caps one transfer, in bytes per second; applied as `set_priority` is.
+    pub fn set_transfer_rate_limit(&self, transfer_id: &str, rate_limit: Option<u64>) {
+        self.update(
+            transfer_id,
+            |entry| entry.limit = rate_limit.map(TokenBucket::new),
+            |preset| preset.rate_limit = rate_limit,
+        );
+    }
+
+    fn update(&self, transfer_id: &str, mut apply: impl FnMut(&mut Entry), preset: impl FnOnce(&mut Preset)) {
+        let mut inner = self.inner.lock().unwrap();
+        let mut found = false;
+        for (_, entry) in inner.entries.iter_mut().filter(|((_, id), _)| id == transfer_id) {
+            apply(entry);
+            found = true;
+        }
+        if !found {
+            preset(inner.presets.entry(transfer_id.to_string()).or_default());
+        }
+    }
+
+    /// Drops settings kept for a transfer that will never join.
+    pub fn forget(&self, transfer_id: &str) {
+        self.inner.lock().unwrap().presets.remove(transfer_id);
+    }
+
+    /// Running transfers, then queued ones in the order they will start.
+    pub fn list(&self) -> Vec<QueuedTransfer> {
+        let inner = self.inner.lock().unwrap();
+        let mut entries: Vec<_> = inner.entries.iter().collect();
+        entries.sort_by_key(|(_, entry)| (!entry.is_active(), std::cmp::Reverse(entry.priority), entry.seq));
+        entries.into_iter().map(|(key, entry)| entry.listing(key)).collect()
+    }
+
+    /// Joins the queue for `transfer_id`; `size` is a hint until the transfer starts.
+    pub(crate) fn enqueue(&self, direction: TransferDirection, transfer_id: &str, size: u64) -> Ticket {
+        let key = (direction, transfer_id.to_string());
+        let mut inner = self.inner.lock().unwrap();
+        let seq = inner.next_seq;
+        let fresh = !inner.entries.contains_key(&key);
+        let preset = if fresh { inner.presets.remove(transfer_id).unwrap_or_default() } else { Preset::default() };
+        let entry = inner.entries.entry(key.clone()).or_insert_with(|| Entry {
+            priority: preset.priority,
+            seq,
+            waiting: 0,
+            slots: 0,
+            limit: preset.rate_limit.map(TokenBucket::new),
+            size,
+            baseline: None,
+            moved: 0,
+            started: None,
+        });
+        entry.waiting += 1;
+        if fresh {
+            inner.next_seq += 1;
+        }
+        Ticket { queue: self.clone(), key, fresh, admitted: false }
+    }
+}
+
+/// A place in the queue. Dropping it before it is admitted leaves the queue.
+pub(crate) struct Ticket {
+    queue: TransferQueue,
+    key: Key,
+    /// First in the queue for this transfer, rather than joining one already there.
+    pub fresh: bool,
+    admitted: bool,
+}
+
+impl Ticket {
+    pub fn direction(&self) -> TransferDirection {
+        self.key.0
+    }
+
+    pub fn try_admit(&mut self) -> Option<TransferSlot> {
+        let mut inner = self.queue.inner.lock().unwrap();
+        if !inner.may_start(&self.key) {
+            return None;
+        }
+
+        let entry = inner.entries.get_mut(&self.key)?;
+        entry.waiting -= 1;
+        entry.slots += 1;
+        if entry.started.is_none() {
+            entry.started = Some(Instant::now());
+        }
+        self.admitted = true;
+        Some(TransferSlot(Arc::new(Slot { queue: self.queue.clone(), key: self.key.clone() })))
+    }
+
+    pub async fn admit(mut self) -> TransferSlot {
+        loop {
+            // Registered before checking, so a release in between is not missed
+            let changed = self.queue.changed.clone();
+            let notified = changed.notified();
+            if let Some(slot) = self.try_admit() {
+                return slot;
+            }
+            notified.await;
+        }
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        if self.admitted {
+            return;
+        }
+        let mut inner = self.queue.inner.lock().unwrap();
+        if let Some(entry) = inner.entries.get_mut(&self.key) {
+            entry.waiting -= 1;
+        }
+        inner.forget_if_idle(&self.key);
+        drop(inner);
+        self.queue.changed.notify_waiters();
+    }
+}
+
+/// Permission to move a transfer's data, shared by all of its streams. The next
+/// queued transfer starts once every clone is dropped.
+#[derive(Clone)]
+pub(crate) struct TransferSlot(Arc<Slot>);
+
+struct Slot {
+    queue: TransferQueue,
+    key: Key,
+}
+
+impl TransferSlot {
+    /// Records the transfer's size and how much was already there; only the first
+    /// call counts.
+    pub fn start_from(&self, size: u64, baseline: u64) {
+        let mut inner = self.0.queue.inner.lock().unwrap();
+        if let Some(entry) = inner.entries.get_mut(&self.0.key) {
+            if entry.baseline.is_none() {
+                entry.size = size;
+                entry.baseline = Some(baseline);
+            }
+        }
+    }
+
+    /// Counts `n` bytes moved and waits as long as the rate limits ask.
+    pub async fn throttle(&self, n: usize) {
+        let wait = {
+            let mut inner = self.0.queue.inner.lock().unwrap();
+            let global = inner.global.as_mut().map_or(Duration::ZERO, |bucket| bucket.take(n));
+            let own = match inner.entries.get_mut(&self.0.key) {
+                Some(entry) => {
+                    entry.moved += n as u64;
+                    entry.limit.as_mut().map_or(Duration::ZERO, |bucket| bucket.take(n))
+                }
+                None => Duration::ZERO,
+            };
+            global.max(own)
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut inner = self.queue.inner.lock().unwrap();
+        if let Some(entry) = inner.entries.get_mut(&self.key) {
+            entry.slots -= 1;
+        }
+        inner.forget_if_idle(&self.key);
+        drop(inner);
+        self.queue.changed.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_allows_a_burst_then_charges_the_debt() {
+        let mut bucket = TokenBucket::new(1000);
+        assert_eq!(bucket.take(MIN_BURST as usize), Duration::ZERO);
+        let wait = bucket.take(1000);
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1), "{:?}", wait);
+        // The debt carries over to the next caller
+        assert!(bucket.take(1000) > Duration::from_millis(1900));
+    }
+
+    #[test]
+    fn bucket_burst_scales_with_rate() {
+        let mut bucket = TokenBucket::new(10 * 1024 * 1024);
+        assert_eq!(bucket.take(1024 * 1024), Duration::ZERO);
+        assert!(bucket.take(1024 * 1024) > Duration::ZERO);
+    }
+
+    #[test]
+    fn bucket_lets_large_chunks_through() {
+        let mut bucket = TokenBucket::new(MIN_BURST);
+        let wait = bucket.take(3 * MIN_BURST as usize);
+        assert!(wait > Duration::from_millis(1900) && wait <= Duration::from_secs(2), "{:?}", wait);
+    }
+
+    #[test]
+    fn limits_active_transfers_per_direction() {
+        let queue = TransferQueue::new(1, None);
+        let mut first = queue.enqueue(TransferDirection::Outgoing, "a", 0);
+        let mut second = queue.enqueue(TransferDirection::Outgoing, "b", 0);
+        let mut incoming = queue.enqueue(TransferDirection::Incoming, "c", 0);
+
+        let slot = first.try_admit().unwrap();
+        assert!(second.try_admit().is_none());
+        assert!(incoming.try_admit().is_some());
+
+        // More streams of a running transfer share its slot
+        assert!(queue.enqueue(TransferDirection::Outgoing, "a", 0).try_admit().is_some());
+
+        drop(slot);
+        assert!(second.try_admit().is_some());
+    }
+
+    #[test]
+    fn starts_by_priority_then_arrival() {
+        let queue = TransferQueue::new(1, None);
+        let running = queue.enqueue(TransferDirection::Outgoing, "running", 0).try_admit().unwrap();
+        let mut normal = queue.enqueue(TransferDirection::Outgoing, "normal", 0);
+        let mut later = queue.enqueue(TransferDirection::Outgoing, "later", 0);
+        let mut high = queue.enqueue(TransferDirection::Outgoing, "high", 0);
+        queue.set_priority("high", TransferPriority::High);
+
+        let order: Vec<_> = queue.list().into_iter().map(|t| t.transfer_id).collect();
+        assert_eq!(order, ["running", "high", "normal", "later"]);
+
+        drop(running);
+        assert!(normal.try_admit().is_none());
+        let high = high.try_admit().unwrap();
+        drop(high);
+        assert!(later.try_admit().is_none());
+        assert!(normal.try_admit().is_some());
+    }
+
+    #[test]
+    fn settings_made_before_joining_apply() {
+        let queue = TransferQueue::new(0, None);
+        queue.set_priority("a", TransferPriority::Low);
+        queue.set_transfer_rate_limit("a", Some(5000));
+        let _ticket = queue.enqueue(TransferDirection::Incoming, "a", 10);
+
+        let listed = queue.list();
+        assert_eq!(listed[0].priority, TransferPriority::Low);
+        assert_eq!(listed[0].rate_limit, Some(5000));
+        assert_eq!(listed[0].state, QueueState::Queued);
+        assert_eq!(listed[0].size, 10);
+    }
+
+    #[test]
+    fn dropped_tickets_leave_the_queue() {
+        let queue = TransferQueue::new(1, None);
+        let running = queue.enqueue(TransferDirection::Outgoing, "a", 0).try_admit().unwrap();
+        drop(queue.enqueue(TransferDirection::Outgoing, "b", 0));
+        assert_eq!(queue.list().len(), 1);
+        drop(running);
+        assert!(queue.list().is_empty());
+    }
+}
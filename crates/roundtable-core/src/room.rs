@@ -0,0 +1,229 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use log::error;
+
+use crate::delivery::DeliveryStatus;
+use crate::error::MessageError;
+use crate::protocol::User;
+
+pub const ROOMS_FILE: &str = "rooms.json";
+/// Keeps a room record, and so every invite and update, within one datagram.
+pub const MAX_ROOM_MEMBERS: usize = 50;
+const MAX_ROOM_NAME: usize = 100;
+
+/// A member of a room and where their copies of its messages are sent.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RoomMember {
+    pub id: u64,
+    pub name: String,
+    pub ip: String,
+    pub port: u16,
+}
+
+/// A named group conversation. Every member keeps a copy, and changes are sent to
+/// all of them with a higher `version`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Room {
+    pub room_id: String,
+    pub name: String,
+    /// Any member may rename the room or invite, but only the owner removes others.
+    /// Passes to the longest-standing member when the owner leaves.
+    pub owner_id: u64,
+    /// In the order they joined.
+    pub members: Vec<RoomMember>,
+    pub version: u64,
+    /// Unix milliseconds.
+    pub created_at: u64,
+}
+
+impl Room {
+    pub fn validate(&self) -> Result<(), MessageError> {
+        if self.room_id.is_empty() {
+            return Err(MessageError::InvalidData("Room ID required".to_string()));
+        }
+        if self.name.trim().is_empty() {
+            return Err(MessageError::InvalidData("Room name cannot be empty".to_string()));
+        }
+        if self.name.len() > MAX_ROOM_NAME {
+            return Err(MessageError::InvalidData("Room name too long".to_string()));
+        }
+        if self.members.len() > MAX_ROOM_MEMBERS {
+            return Err(MessageError::InvalidData(format!("Rooms have at most {} members", MAX_ROOM_MEMBERS)));
+        }
+        if !self.is_member(self.owner_id) {
+            return Err(MessageError::InvalidData("Room owner is not a member".to_string()));
+        }
+        Ok(())
+    }
+
+    pub fn is_member(&self, user_id: u64) -> bool {
+        self.members.iter().any(|member| member.id == user_id)
+    }
+
+    /// Everyone but `user_id`, i.e. whom a member sends to.
+    pub fn others(&self, user_id: u64) -> impl Iterator<Item = &RoomMember> {
+        self.members.iter().filter(move |member| member.id != user_id)
+    }
+
+    /// Adds members not in the room yet, returning those that were added.
+    pub(crate) fn add_members(&mut self, members: Vec<RoomMember>) -> Vec<RoomMember> {
+        let mut added = Vec::new();
+        for member in members {
+            if !self.is_member(member.id) && !added.iter().any(|a: &RoomMember| a.id == member.id) {
+                added.push(member);
+            }
+        }
+        self.members.extend(added.iter().cloned());
+        added
+    }
+
+    /// Sets where a member is reached, e.g. from the address their packet came from.
+    pub(crate) fn set_member_ip(&mut self, user_id: u64, ip: String) {
+        if let Some(member) = self.members.iter_mut().find(|member| member.id == user_id) {
+            member.ip = ip;
+        }
+    }
+
+    /// Removes a member, handing ownership on if it was the owner. Returns whether
+    /// they were a member.
+    pub(crate) fn remove_member(&mut self, user_id: u64) -> bool {
+        let before = self.members.len();
+        self.members.retain(|member| member.id != user_id);
+        if self.owner_id == user_id {
+            if let Some(next) = self.members.first() {
+                self.owner_id = next.id;
+            }
+        }
+        self.members.len() != before
+    }
+}
+
+/// The rooms we are in, persisted as JSON.
+#[derive(Clone)]
+pub struct RoomStore {
+    path: PathBuf,
+    rooms: Arc<Mutex<BTreeMap<String, Room>>>,
+}
+
+impl RoomStore {
+    pub fn load(path: PathBuf) -> Self {
+        let rooms = match std::fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+                error!("Ignoring unreadable rooms file {}: {}", path.display(), e);
+                BTreeMap::new()
+            }),
+            Err(_) => BTreeMap::new(),
+        };
+
+        Self { path, rooms: Arc::new(Mutex::new(rooms)) }
+    }
+
+    pub fn get(&self, room_id: &str) -> Option<Room> {
+        self.rooms.lock().unwrap().get(room_id).cloned()
+    }
+
+    /// Every room, most recently created first.
+    pub fn list(&self) -> Vec<Room> {
+        let mut rooms: Vec<Room> = self.rooms.lock().unwrap().values().cloned().collect();
+        rooms.sort_by_key(|room| std::cmp::Reverse(room.created_at));
+        rooms
+    }
+
+    pub fn is_member(&self, room_id: &str, user_id: u64) -> bool {
+        self.rooms.lock().unwrap().get(room_id).is_some_and(|room| room.is_member(user_id))
+    }
+
+    pub fn insert(&self, room: Room) {
+        let mut rooms = self.rooms.lock().unwrap();
+        rooms.insert(room.room_id.clone(), room);
+        self.save(&rooms);
+    }
+
+    pub fn remove(&self, room_id: &str) -> Option<Room> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let removed = rooms.remove(room_id);
+        if removed.is_some() {
+            self.save(&rooms);
+        }
+        removed
+    }
+
+    /// Changes a room and saves it, unless we are not in the room or `change` fails
+    /// or leaves it invalid.
+    pub(crate) fn modify<T>(
+        &self,
+        room_id: &str,
+        change: impl FnOnce(&mut Room) -> Result<T, MessageError>,
+    ) -> Result<(Room, T), MessageError> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let room = rooms
+            .get_mut(room_id)
+            .ok_or_else(|| MessageError::InvalidData(format!("Unknown room {}", room_id)))?;
+        let mut updated = room.clone();
+        let result = change(&mut updated)?;
+        updated.validate()?;
+        *room = updated.clone();
+        self.save(&rooms);
+        Ok((updated, result))
+    }
+
+    /// Keeps a member's name and address current in every room they are in.
+    pub fn update_member(&self, user: &User) {
+        let mut rooms = self.rooms.lock().unwrap();
+        let mut changed = false;
+        for member in rooms.values_mut().flat_map(|room| room.members.iter_mut()).filter(|member| member.id == user.id) {
+            if member.name != user.name || member.ip != user.ip || member.port != user.port {
+                member.name = user.name.clone();
+                member.ip = user.ip.clone();
+                member.port = user.port;
+                changed = true;
+            }
+        }
+        if changed {
+            self.save(&rooms);
+        }
+    }
+
+    fn save(&self, rooms: &BTreeMap<String, Room>) {
+        let result = serde_json::to_vec_pretty(rooms)
+            .map_err(io::Error::from)
+            .and_then(|bytes| {
+                if let Some(parent) = self.path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                let tmp = self.path.with_extension("json.tmp");
+                std::fs::write(&tmp, bytes)?;
+                std::fs::rename(&tmp, &self.path)
+            });
+
+        if let Err(e) = result {
+            error!("Failed to save rooms to {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Which room message each member's copy carries, so their delivery receipts can be
+/// reported against the room message.
+#[derive(Clone, Default)]
+pub(crate) struct RoomDeliveries {
+    copies: Arc<Mutex<HashMap<String, (String, String)>>>,
+}
+
+impl RoomDeliveries {
+    pub fn track(&self, copy_id: String, room_id: &str, message_id: &str) {
+        self.copies.lock().unwrap().insert(copy_id, (room_id.to_string(), message_id.to_string()));
+    }
+
+    /// The room and room message of a copy, forgotten once its status is final.
+    pub fn resolve(&self, copy_id: &str, status: DeliveryStatus) -> Option<(String, String)> {
+        let mut copies = self.copies.lock().unwrap();
+        if status == DeliveryStatus::Pending {
+            copies.get(copy_id).cloned()
+        } else {
+            copies.remove(copy_id)
+        }
+    }
+}
@@ -0,0 +1,2241 @@
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::io::SeekFrom;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, watch};
+use tokio::task::JoinSet;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Socket, Type};
+
+use crate::compression::{ChunkCompressor, ChunkDecompressor, Compression};
+use crate::crypto::{self, FileCipher, TransferToken, TAG_SIZE};
+use crate::error::MessageError;
+use crate::event::EngineEvent;
+use crate::manifest::{self, ManifestEntry, MAX_MANIFEST_BYTES};
+use crate::net;
+use crate::queue::{Ticket, TransferDirection, TransferQueue, TransferSlot};
+
+const TRANSFER_CHUNK_SIZE: usize = 16384;
+const CONNECT_TIMEOUT_SECS: u64 = 15;
+const ACCEPT_TIMEOUT_SECS: u64 = 60;
+// The receiver hashes the whole file before acknowledging it
+const ACK_TIMEOUT_SECS: u64 = 600;
+/// Sent by the receiver once the whole file is saved.
+const TRANSFER_ACK: u8 = 1;
+/// Sent by the receiver when the file did not match the offered hash.
+const TRANSFER_CORRUPT: u8 = 2;
+/// Sent on each extra stream of a ranged download once its range is written.
+const RANGE_ACK: u8 = 3;
+/// Files are only split into ranges at least this large.
+const MIN_RANGE_SIZE: u64 = 16 * 1024 * 1024;
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+/// Connections a file is fetched over unless either side asks for fewer.
+pub const DEFAULT_TRANSFER_STREAMS: u8 = 4;
+/// How long recipients of a one-to-many offer have to answer it.
+pub(crate) const OFFER_TIMEOUT_SECS: u64 = 600;
+pub(crate) const HASH_PREFIX: &str = "blake3:";
+
+/// A file this side has offered to a peer.
+#[derive(Debug, Clone)]
+pub struct OutgoingFile {
+    pub transfer_id: String,
+    pub file_name: String,
+    pub file_size: u64,
+    pub path: PathBuf,
+}
+
+/// A peer to offer a file to.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct OfferTarget {
+    pub id: u64,
+    pub ip: String,
+    pub port: u16,
+}
+
+/// Where one recipient of an offer stands.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RecipientState {
+    Offered,
+    Accepted,
+    Rejected,
+    Sending,
+    /// Its connection dropped; waiting for it to resume.
+    Interrupted,
+    Sent,
+    Failed,
+    /// Did not answer, connect or resume in time.
+    Expired,
+    /// Withdrew after accepting.
+    Cancelled,
+}
+
+impl RecipientState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RecipientState::Offered => "offered",
+            RecipientState::Accepted => "accepted",
+            RecipientState::Rejected => "rejected",
+            RecipientState::Sending => "sending",
+            RecipientState::Interrupted => "interrupted",
+            RecipientState::Sent => "sent",
+            RecipientState::Failed => "failed",
+            RecipientState::Expired => "expired",
+            RecipientState::Cancelled => "cancelled",
+        }
+    }
+
+    /// Nothing more will happen for this recipient in the current session.
+    pub fn is_final(&self) -> bool {
+        matches!(
+            self,
+            RecipientState::Rejected
+                | RecipientState::Sent
+                | RecipientState::Failed
+                | RecipientState::Expired
+                | RecipientState::Cancelled
+        )
+    }
+}
+
+/// One recipient's part in an outgoing transfer.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RecipientStatus {
+    pub user_id: u64,
+    pub state: RecipientState,
+    /// Bytes sent to this recipient, including any it already had.
+    pub sent: u64,
+    pub error: Option<String>,
+}
+
+/// What a recipient is told in `TransferReady` so it can connect.
+#[derive(Debug, Clone)]
+pub(crate) struct Admission {
+    pub user_id: u64,
+    /// Its IP and message port.
+    pub addr: (String, u16),
+    pub token: TransferToken,
+    pub encrypted: bool,
+    pub streams: u8,
+    pub compression: Option<Compression>,
+}
+
+#[derive(Clone)]
+struct Recipient {
+    status: RecipientStatus,
+    /// Where its control messages go: the IP and message port it answered from.
+    addr: Option<(String, u16)>,
+    cipher: Option<FileCipher>,
+    /// What it presents when connecting; issued on each acceptance and dropped once
+    /// it is settled.
+    token: Option<TransferToken>,
+    /// Connections it was allowed in `TransferReady`.
+    streams: u8,
+    /// How its stream is compressed, if at all.
+    compression: Option<Compression>,
+    /// When an accepted or interrupted recipient is given up on.
+    deadline: Option<Instant>,
+    /// Expired after an interruption, so accepting again resumes.
+    resumable: bool,
+}
+
+impl Recipient {
+    // Holding a token means it accepted and is not settled yet
+    fn admission(&self) -> Option<Admission> {
+        Some(Admission {
+            user_id: self.status.user_id,
+            addr: self.addr.clone()?,
+            token: self.token?,
+            encrypted: self.cipher.is_some(),
+            streams: self.streams,
+            compression: self.compression,
+        })
+    }
+
+    fn admits(&self, ip: IpAddr) -> bool {
+        self.token.is_some() && self.addr.as_ref().is_some_and(|(addr_ip, _)| same_ip(addr_ip, ip))
+    }
+}
+
+// Compares a recorded address with a socket's, treating IPv4-mapped IPv6 as IPv4
+fn same_ip(recorded: &str, ip: IpAddr) -> bool {
+    net::parse_ip(recorded).is_some_and(|recorded| recorded == ip.to_canonical())
+}
+
+/// What a registered transfer streams: one file, or the files of a manifest in
+/// manifest order, and who it was offered to.
+#[derive(Clone)]
+pub struct TransferSource {
+    pub files: Vec<PathBuf>,
+    pub manifest: Option<Vec<ManifestEntry>>,
+    recipients: HashMap<u64, Recipient>,
+    /// Port of the transfer server while one is running.
+    port: Option<u16>,
+    control: TransferControl,
+}
+
+impl TransferSource {
+    fn new(files: Vec<PathBuf>, manifest: Option<Vec<ManifestEntry>>, recipients: &[OfferTarget]) -> Self {
+        let recipients = recipients
+            .iter()
+            .map(|target| {
+                let status = RecipientStatus { user_id: target.id, state: RecipientState::Offered, sent: 0, error: None };
+                let addr = Some((target.ip.clone(), target.port));
+                let recipient = Recipient {
+                    status,
+                    addr,
+                    cipher: None,
+                    token: None,
+                    streams: 1,
+                    compression: None,
+                    deadline: None,
+                    resumable: false,
+                };
+                (target.id, recipient)
+            })
+            .collect();
+        Self { files, manifest, recipients, port: None, control: TransferControl::default() }
+    }
+
+    fn finished(&self) -> bool {
+        self.recipients.values().all(|recipient| recipient.status.state.is_final())
+    }
+}
+
+/// What we know about a file a peer has offered us. Kept until the download
+/// completes so an interrupted one can be resumed.
+#[derive(Debug, Clone, Default)]
+pub struct IncomingTransfer {
+    pub sender_key: Option<String>,
+    /// Digest advertised in the offer; of the manifest for multi-file offers.
+    pub hash: Option<String>,
+    /// Size from the offer; 0 for transfers that were never offered to us.
+    pub size: u64,
+    /// A multi-file offer, saved under a directory.
+    pub bundle: bool,
+    /// Set by the sender's `TransferReady`.
+    pub encrypted: bool,
+    pub sender_ip: Option<String>,
+    /// The sender's message port, for pausing and cancelling; 0 if it never offered.
+    pub sender_port: u16,
+    pub port: u16,
+    /// Our user ID, sent to the sender so it can tell its recipients apart.
+    pub receiver_id: u64,
+    /// Set by the sender's `TransferReady`; presented when connecting.
+    pub token: Option<TransferToken>,
+    /// Set by the sender's `TransferReady`.
+    pub compression: Option<Compression>,
+    /// Connections the sender agreed to in `TransferReady`.
+    pub streams: u8,
+    /// Ranges a multi-stream download still lacks after an interrupted attempt.
+    pub missing: Option<Vec<(u64, u64)>>,
+    /// Where the last download attempt was saved.
+    pub save_path: Option<PathBuf>,
+    /// A download task is currently running.
+    pub active: bool,
+    pub control: TransferControl,
+}
+
+/// Pause and cancel switches shared by the tasks moving one transfer's data. On
+/// the sending side they can also hold or stop a single recipient.
+#[derive(Clone, Default)]
+pub struct TransferControl(Arc<watch::Sender<ControlState>>);
+
+#[derive(Debug, Default)]
+struct ControlState {
+    paused: bool,
+    cancelled: bool,
+    paused_recipients: HashSet<u64>,
+    cancelled_recipients: HashSet<u64>,
+}
+
+impl ControlState {
+    fn is_paused(&self, recipient: u64) -> bool {
+        self.paused || self.paused_recipients.contains(&recipient)
+    }
+
+    fn is_cancelled(&self, recipient: u64) -> bool {
+        self.cancelled || self.cancelled_recipients.contains(&recipient)
+    }
+}
+
+impl std::fmt::Debug for TransferControl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.borrow().fmt(f)
+    }
+}
+
+impl TransferControl {
+    /// Holds the transfer, or one recipient of it. Returns whether that changed anything.
+    pub fn pause(&self, recipient: Option<u64>) -> bool {
+        self.0.send_if_modified(|state| match recipient {
+            Some(user_id) => state.paused_recipients.insert(user_id),
+            None => !std::mem::replace(&mut state.paused, true),
+        })
+    }
+
+    /// Lets a held recipient continue, or without one the whole transfer and
+    /// everyone in it.
+    pub fn resume(&self, recipient: Option<u64>) -> bool {
+        self.0.send_if_modified(|state| match recipient {
+            Some(user_id) => state.paused_recipients.remove(&user_id),
+            None => {
+                let changed = state.paused || !state.paused_recipients.is_empty();
+                state.paused = false;
+                state.paused_recipients.clear();
+                changed
+            }
+        })
+    }
+
+    pub fn cancel(&self, recipient: Option<u64>) {
+        self.0.send_modify(|state| match recipient {
+            Some(user_id) => {
+                state.cancelled_recipients.insert(user_id);
+            }
+            None => state.cancelled = true,
+        });
+    }
+
+    /// Whether the transfer, or any recipient of it, is held.
+    pub fn is_paused(&self) -> bool {
+        let state = self.0.borrow();
+        state.paused || !state.paused_recipients.is_empty()
+    }
+
+    fn is_cancelled(&self, recipient: u64) -> bool {
+        self.0.borrow().is_cancelled(recipient)
+    }
+
+    // Waits out a pause before the next chunk; fails once cancelled
+    async fn proceed(&self, recipient: u64) -> std::io::Result<()> {
+        let mut switches = self.0.subscribe();
+        let state = switches
+            .wait_for(|state| state.is_cancelled(recipient) || !state.is_paused(recipient))
+            .await
+            .map_err(std::io::Error::other)?;
+        if state.is_cancelled(recipient) {
+            return Err(cancelled_error());
+        }
+        Ok(())
+    }
+
+    // Runs `operation` unless cancelled first, for reads that may wait on a paused peer
+    async fn guard<T>(&self, recipient: u64, operation: impl Future<Output = std::io::Result<T>>) -> std::io::Result<T> {
+        let mut switches = self.0.subscribe();
+        tokio::select! {
+            result = operation => result,
+            _ = switches.wait_for(|state| state.is_cancelled(recipient)) => Err(cancelled_error()),
+        }
+    }
+
+    // Resolves once the whole transfer is cancelled
+    async fn cancelled(&self) {
+        let mut switches = self.0.subscribe();
+        let _ = switches.wait_for(|state| state.cancelled).await;
+    }
+}
+
+fn cancelled_error() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Interrupted, "Transfer cancelled")
+}
+
+/// Maps transfer IDs to the local files we have offered, and tracks offers we
+/// have received.
+#[derive(Clone, Default)]
+pub struct TransferRegistry {
+    transfers: Arc<Mutex<HashMap<String, TransferSource>>>,
+    incoming: Arc<Mutex<HashMap<String, IncomingTransfer>>>,
+}
+
+impl TransferRegistry {
+    /// Registers a file offered to the users in `recipients`.
+    pub fn register(&self, transfer_id: String, path: PathBuf, recipients: &[OfferTarget]) {
+        let source = TransferSource::new(vec![path], None, recipients);
+        self.transfers.lock().unwrap().insert(transfer_id, source);
+    }
+
+    pub fn register_bundle(&self, transfer_id: String, files: Vec<PathBuf>, manifest: Vec<ManifestEntry>, recipients: &[OfferTarget]) {
+        let source = TransferSource::new(files, Some(manifest), recipients);
+        self.transfers.lock().unwrap().insert(transfer_id, source);
+    }
+
+    pub fn contains(&self, transfer_id: &str) -> bool {
+        self.transfers.lock().unwrap().contains_key(transfer_id)
+    }
+
+    pub fn get(&self, transfer_id: &str) -> Option<TransferSource> {
+        self.transfers.lock().unwrap().get(transfer_id).cloned()
+    }
+
+    pub fn remove(&self, transfer_id: &str) -> Option<TransferSource> {
+        self.transfers.lock().unwrap().remove(transfer_id)
+    }
+
+    /// Every recipient of an outgoing transfer, by user ID.
+    pub fn recipients(&self, transfer_id: &str) -> Option<Vec<RecipientStatus>> {
+        let transfers = self.transfers.lock().unwrap();
+        let mut recipients: Vec<_> = transfers
+            .get(transfer_id)?
+            .recipients
+            .values()
+            .map(|recipient| recipient.status.clone())
+            .collect();
+        recipients.sort_by_key(|status| status.user_id);
+        Some(recipients)
+    }
+
+    /// Records that a recipient accepted from `addr` and issues it a new token.
+    /// Returns the port the transfer is served on, whether the caller has to start
+    /// the server there and what to tell the recipient, or `None` if the peer was
+    /// never offered the transfer.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn accept_recipient(
+        &self,
+        transfer_id: &str,
+        user_id: u64,
+        addr: (String, u16),
+        cipher: Option<FileCipher>,
+        streams: u8,
+        compression: Option<Compression>,
+        port: u16,
+    ) -> Option<(u16, bool, RecipientStatus, Admission)> {
+        let mut transfers = self.transfers.lock().unwrap();
+        let source = transfers.get_mut(transfer_id)?;
+        let recipient = source.recipients.get_mut(&user_id)?;
+        recipient.addr = Some(addr);
+        recipient.cipher = cipher;
+        recipient.token = Some(crypto::new_transfer_token());
+        recipient.streams = streams;
+        recipient.compression = compression;
+        recipient.status.state = RecipientState::Accepted;
+        recipient.status.error = None;
+        recipient.deadline = Some(Instant::now() + Duration::from_secs(ACCEPT_TIMEOUT_SECS));
+        recipient.resumable = false;
+        let status = recipient.status.clone();
+        let admission = recipient.admission()?;
+
+        let start = source.port.is_none();
+        Some((*source.port.get_or_insert(port), start, status, admission))
+    }
+
+    /// The port a running transfer server listens on and what each recipient still
+    /// expected there has to be told to connect.
+    pub(crate) fn admissions(&self, transfer_id: &str) -> Option<(u16, Vec<Admission>)> {
+        let transfers = self.transfers.lock().unwrap();
+        let source = transfers.get(transfer_id)?;
+        let admissions = source.recipients.values().filter_map(Recipient::admission).collect();
+        Some((source.port?, admissions))
+    }
+
+    /// Whether a connection from `ip` may belong to a recipient of the transfer.
+    fn admits(&self, transfer_id: &str, ip: IpAddr) -> bool {
+        let transfers = self.transfers.lock().unwrap();
+        transfers
+            .get(transfer_id)
+            .is_some_and(|source| source.recipients.values().any(|recipient| recipient.admits(ip)))
+    }
+
+    /// Moves a recipient to `state`, returning its new status if that changed anything.
+    pub(crate) fn set_recipient_state(
+        &self,
+        transfer_id: &str,
+        user_id: u64,
+        state: RecipientState,
+        error: Option<String>,
+    ) -> Option<RecipientStatus> {
+        let mut transfers = self.transfers.lock().unwrap();
+        let recipient = transfers.get_mut(transfer_id)?.recipients.get_mut(&user_id)?;
+        if recipient.status.state == state && error.is_none() {
+            return None;
+        }
+
+        recipient.status.state = state;
+        recipient.status.error = error;
+        if state.is_final() {
+            recipient.token = None;
+        }
+        recipient.deadline = match state {
+            RecipientState::Accepted | RecipientState::Interrupted => {
+                Some(Instant::now() + Duration::from_secs(ACCEPT_TIMEOUT_SECS))
+            }
+            _ => None,
+        };
+        recipient.resumable = state == RecipientState::Interrupted;
+        Some(recipient.status.clone())
+    }
+
+    // Rejects arriving without a user ID come from peers that predate one-to-many
+    // offers, which only ever get single-recipient offers. Either way the reject
+    // must come from where the recipient was offered the transfer or accepted it.
+    pub(crate) fn reject_recipient(&self, transfer_id: &str, user_id: Option<u64>, from: IpAddr) -> Option<RecipientStatus> {
+        let user_id = {
+            let transfers = self.transfers.lock().unwrap();
+            let recipients = &transfers.get(transfer_id)?.recipients;
+            let user_id = match user_id {
+                Some(user_id) => user_id,
+                None if recipients.len() == 1 => *recipients.keys().next()?,
+                None => return None,
+            };
+            let (ip, _) = recipients.get(&user_id)?.addr.as_ref()?;
+            if !same_ip(ip, from) {
+                return None;
+            }
+            user_id
+        };
+        self.set_recipient_state(transfer_id, user_id, RecipientState::Rejected, None)
+    }
+
+    /// Gives up on recipients that have not answered a one-to-many offer.
+    pub(crate) fn expire_offers(&self, transfer_id: &str) -> Vec<RecipientStatus> {
+        self.expire(transfer_id, |recipient| recipient.status.state == RecipientState::Offered)
+    }
+
+    // Recipients past their deadline to connect or resume
+    fn expire_overdue(&self, transfer_id: &str) -> Vec<RecipientStatus> {
+        let now = Instant::now();
+        self.expire(transfer_id, |recipient| recipient.deadline.is_some_and(|deadline| deadline <= now))
+    }
+
+    fn expire(&self, transfer_id: &str, overdue: impl Fn(&Recipient) -> bool) -> Vec<RecipientStatus> {
+        let mut transfers = self.transfers.lock().unwrap();
+        let Some(source) = transfers.get_mut(transfer_id) else {
+            return Vec::new();
+        };
+        source
+            .recipients
+            .values_mut()
+            .filter(|recipient| overdue(recipient))
+            .map(|recipient| {
+                recipient.status.state = RecipientState::Expired;
+                recipient.token = None;
+                recipient.deadline = None;
+                recipient.status.clone()
+            })
+            .collect()
+    }
+
+    /// Forgets a transfer nobody is left to take while no server runs for it.
+    /// Returns whether it was removed.
+    pub(crate) fn settle(&self, transfer_id: &str) -> bool {
+        let mut transfers = self.transfers.lock().unwrap();
+        match transfers.get(transfer_id) {
+            Some(source) if source.port.is_none() && source.finished() => {
+                transfers.remove(transfer_id);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Pause and cancel switches of an outgoing transfer.
+    pub(crate) fn control(&self, transfer_id: &str) -> Option<TransferControl> {
+        Some(self.transfers.lock().unwrap().get(transfer_id)?.control.clone())
+    }
+
+    /// The recipient a control message claims to come from, if `ip` is where it
+    /// answered the offer from.
+    pub(crate) fn is_recipient_at(&self, transfer_id: &str, user_id: u64, ip: &str) -> bool {
+        let transfers = self.transfers.lock().unwrap();
+        transfers
+            .get(transfer_id)
+            .and_then(|source| source.recipients.get(&user_id))
+            .and_then(|recipient| recipient.addr.as_ref())
+            .is_some_and(|(recipient_ip, _)| recipient_ip == ip)
+    }
+
+    /// Recipients still taking part and their message addresses, optionally only
+    /// those that accepted.
+    pub(crate) fn recipient_addrs(&self, transfer_id: &str, accepted_only: bool) -> Vec<(u64, (String, u16))> {
+        let transfers = self.transfers.lock().unwrap();
+        let Some(source) = transfers.get(transfer_id) else {
+            return Vec::new();
+        };
+        source
+            .recipients
+            .values()
+            .filter(|recipient| !recipient.status.state.is_final())
+            .filter(|recipient| !accepted_only || recipient.status.state != RecipientState::Offered)
+            .filter_map(|recipient| Some((recipient.status.user_id, recipient.addr.clone()?)))
+            .collect()
+    }
+
+    /// Stops serving one recipient, which may end the server's session.
+    pub(crate) fn cancel_recipient(&self, transfer_id: &str, user_id: u64) -> Option<RecipientStatus> {
+        let status = self.set_recipient_state(transfer_id, user_id, RecipientState::Cancelled, None)?;
+        if let Some(control) = self.control(transfer_id) {
+            control.cancel(Some(user_id));
+        }
+        Some(status)
+    }
+
+    /// Forgets an outgoing transfer and stops its server and connections.
+    pub(crate) fn cancel(&self, transfer_id: &str) -> Option<TransferSource> {
+        let source = self.remove(transfer_id)?;
+        source.control.cancel(None);
+        Some(source)
+    }
+
+    // The server's view of a connecting recipient: its cipher and compression, or
+    // `None` unless it is waiting for this transfer, connects from where it accepted
+    // and has its token
+    fn connect_recipient(
+        &self,
+        transfer_id: &str,
+        user_id: u64,
+        ip: IpAddr,
+        token: &TransferToken,
+    ) -> Option<(Option<FileCipher>, Option<Compression>, Option<RecipientStatus>)> {
+        let mut transfers = self.transfers.lock().unwrap();
+        let recipient = transfers.get_mut(transfer_id)?.recipients.get_mut(&user_id)?;
+        let expected = recipient.token.as_ref()?;
+        if !recipient.admits(ip) || !crypto::tokens_match(expected, token) {
+            return None;
+        }
+
+        let changed = recipient.status.state != RecipientState::Sending;
+        recipient.status.state = RecipientState::Sending;
+        recipient.deadline = None;
+        Some((recipient.cipher.clone(), recipient.compression, changed.then(|| recipient.status.clone())))
+    }
+
+    fn record_sent(&self, transfer_id: &str, user_id: u64, sent: u64) {
+        if let Some(recipient) = self
+            .transfers
+            .lock()
+            .unwrap()
+            .get_mut(transfer_id)
+            .and_then(|source| source.recipients.get_mut(&user_id))
+        {
+            recipient.status.sent = sent;
+        }
+    }
+
+    // Ends the server's session once every recipient is settled. The transfer stays
+    // registered if someone expired part way, so accepting again can resume.
+    // Returns the recipients and whether the transfer was kept.
+    fn end_session_if_finished(&self, transfer_id: &str) -> Option<(Vec<RecipientStatus>, bool)> {
+        let mut transfers = self.transfers.lock().unwrap();
+        let source = transfers.get_mut(transfer_id)?;
+        if !source.finished() {
+            return None;
+        }
+
+        source.port = None;
+        let statuses = source.recipients.values().map(|recipient| recipient.status.clone()).collect();
+        let keep = source.recipients.values().any(|recipient| recipient.resumable);
+        if !keep {
+            transfers.remove(transfer_id);
+        }
+        Some((statuses, keep))
+    }
+
+    pub fn register_incoming(&self, transfer_id: String, transfer: IncomingTransfer) {
+        self.incoming.lock().unwrap().insert(transfer_id, transfer);
+    }
+
+    /// Records where the sender is serving the transfer, if `from` is the address
+    /// the offer came from, and returns that address. Anything else is ignored, so
+    /// no other host can point the download at itself.
+    #[allow(clippy::too_many_arguments)]
+    pub fn set_incoming_ready(
+        &self,
+        transfer_id: &str,
+        from: IpAddr,
+        port: u16,
+        encrypted: bool,
+        streams: u8,
+        token: Option<TransferToken>,
+        compression: Option<Compression>,
+    ) -> Option<String> {
+        let mut incoming = self.incoming.lock().unwrap();
+        let transfer = incoming.get_mut(transfer_id)?;
+        let sender_ip = transfer.sender_ip.clone().filter(|sender_ip| same_ip(sender_ip, from))?;
+        transfer.port = port;
+        transfer.encrypted = encrypted;
+        transfer.streams = streams;
+        transfer.token = token;
+        transfer.compression = compression;
+        Some(sender_ip)
+    }
+
+    pub fn set_incoming_receiver(&self, transfer_id: &str, receiver_id: u64) {
+        if let Some(transfer) = self.incoming.lock().unwrap().get_mut(transfer_id) {
+            transfer.receiver_id = receiver_id;
+        }
+    }
+
+    pub fn incoming(&self, transfer_id: &str) -> Option<IncomingTransfer> {
+        self.incoming.lock().unwrap().get(transfer_id).cloned()
+    }
+
+    /// Forgets an offer we received and stops its download, if one is running.
+    pub(crate) fn cancel_incoming(&self, transfer_id: &str) -> Option<IncomingTransfer> {
+        let transfer = self.incoming.lock().unwrap().remove(transfer_id)?;
+        transfer.control.cancel(None);
+        Some(transfer)
+    }
+
+    /// Marks a download as running, registering the transfer if it was never offered
+    /// to us. Returns the transfer as it was before, or `None` if a download is
+    /// already running.
+    pub fn start_download(&self, transfer_id: &str, sender_ip: String, port: u16, save_path: PathBuf) -> Option<IncomingTransfer> {
+        let mut incoming = self.incoming.lock().unwrap();
+        let transfer = incoming.entry(transfer_id.to_string()).or_default();
+        if transfer.active {
+            return None;
+        }
+
+        let previous = transfer.clone();
+        transfer.sender_ip = Some(sender_ip);
+        transfer.port = port;
+        transfer.save_path = Some(save_path);
+        transfer.active = true;
+        Some(previous)
+    }
+
+    /// Forgets a completed download, or keeps a failed one around for resuming.
+    pub fn finish_download(&self, transfer_id: &str, outcome: DownloadOutcome) {
+        let mut incoming = self.incoming.lock().unwrap();
+        match outcome {
+            DownloadOutcome::Completed => {
+                incoming.remove(transfer_id);
+            }
+            DownloadOutcome::Stopped(missing) => {
+                if let Some(transfer) = incoming.get_mut(transfer_id) {
+                    transfer.active = false;
+                    transfer.missing = missing;
+                }
+            }
+        }
+    }
+}
+
+/// Where a download is kept until it is complete: `<save_path>.part`.
+pub fn part_path(save_path: &Path) -> PathBuf {
+    let mut name = save_path.file_name().unwrap_or_default().to_os_string();
+    name.push(".part");
+    save_path.with_file_name(name)
+}
+
+/// The `blake3:<hex>` digest carried in file offers.
+pub async fn hash_file(path: PathBuf) -> std::io::Result<String> {
+    tokio::task::spawn_blocking(move || hash_path(&path))
+        .await
+        .map_err(std::io::Error::other)?
+}
+
+pub(crate) fn hash_path(path: &Path) -> std::io::Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(std::fs::File::open(path)?)?;
+    Ok(format!("{}{}", HASH_PREFIX, hasher.finalize().to_hex()))
+}
+
+pub fn get_available_tcp_port() -> Result<u16, std::io::Error> {
+    let socket = bind_listener(0)?;
+    let port = socket.local_addr()?.port();
+    Ok(port)
+}
+
+/// Listens for transfers on every address, over both IPv6 and IPv4 where the host
+/// supports it; IPv4 peers then connect from IPv4-mapped addresses.
+fn bind_listener(port: u16) -> std::io::Result<std::net::TcpListener> {
+    let dual_stack = || -> std::io::Result<std::net::TcpListener> {
+        let socket = Socket::new(Domain::IPV6, Type::STREAM, None)?;
+        socket.set_only_v6(false)?;
+        #[cfg(unix)]
+        socket.set_reuse_address(true)?;
+        socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+        socket.listen(128)?;
+        Ok(socket.into())
+    };
+    dual_stack().or_else(|e| {
+        debug!("No dual-stack listener ({}), listening on IPv4 only", e);
+        std::net::TcpListener::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)))
+    })
+}
+
+/// How many streams to serve a transfer over: at most `requested`, and no more than
+/// gives each a range of `MIN_RANGE_SIZE`. Multi-file transfers always use one.
+pub(crate) fn stream_count(source: &TransferSource, requested: u8) -> u8 {
+    if source.manifest.is_some() {
+        return 1;
+    }
+    let size = source
+        .files
+        .first()
+        .and_then(|path| std::fs::metadata(path).ok())
+        .map_or(0, |metadata| metadata.len());
+    let by_size = (size / MIN_RANGE_SIZE).clamp(1, u8::MAX as u64) as u8;
+    requested.clamp(1, by_size)
+}
+
+/// Progress of a transfer summed over all of its streams, with the throughput and
+/// time remaining of the current attempt.
+pub(crate) struct ProgressMeter {
+    size: u64,
+    state: Mutex<MeterState>,
+}
+
+struct MeterState {
+    /// Bytes already transferred when this attempt started.
+    baseline: Option<u64>,
+    moved: u64,
+    started: Instant,
+    last_percent: u8,
+    last_report: Instant,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Progress {
+    pub percent: u8,
+    pub transferred: u64,
+    pub bytes_per_second: u64,
+    pub eta_seconds: Option<u64>,
+}
+
+impl ProgressMeter {
+    pub fn new(size: u64) -> Self {
+        let now = Instant::now();
+        Self {
+            size,
+            state: Mutex::new(MeterState { baseline: None, moved: 0, started: now, last_percent: 0, last_report: now }),
+        }
+    }
+
+    /// Records how much was already there when the attempt began; only the first call counts.
+    pub fn start_from(&self, baseline: u64) {
+        let mut state = self.state.lock().unwrap();
+        if state.baseline.is_none() {
+            state.baseline = Some(baseline.min(self.size));
+            state.started = Instant::now();
+        }
+    }
+
+    pub fn transferred(&self) -> u64 {
+        let state = self.state.lock().unwrap();
+        (state.baseline.unwrap_or(0) + state.moved).min(self.size)
+    }
+
+    /// Counts `n` more bytes. Returns a snapshot when the percentage changes, or at
+    /// least once per `PROGRESS_INTERVAL` so slow transfers still update their ETA.
+    pub fn add(&self, n: usize) -> Option<Progress> {
+        let mut state = self.state.lock().unwrap();
+        state.moved += n as u64;
+        let transferred = (state.baseline.unwrap_or(0) + state.moved).min(self.size);
+        let percent = if self.size == 0 { 100 } else { (transferred as f64 / self.size as f64 * 100.0) as u8 };
+
+        let now = Instant::now();
+        if percent == state.last_percent && now.duration_since(state.last_report) < PROGRESS_INTERVAL {
+            return None;
+        }
+        state.last_percent = percent;
+        state.last_report = now;
+
+        let elapsed = now.duration_since(state.started).as_secs_f64();
+        let bytes_per_second = if elapsed > 0.0 { (state.moved as f64 / elapsed) as u64 } else { 0 };
+        let eta_seconds = (bytes_per_second > 0).then(|| (self.size - transferred).div_ceil(bytes_per_second));
+        Some(Progress { percent, transferred, bytes_per_second, eta_seconds })
+    }
+}
+
+/// Serves a registered transfer to every recipient that accepts it, on one port.
+/// Only recipients that accepted are served: connections from any other address are
+/// dropped, and each connection runs a short handshake:
+///
+/// 1. receiver: cipher session (4 bytes), its user ID (8 bytes) and the token it
+///    was given in `TransferReady` (16 bytes)
+/// 2. sender: total size (8 bytes), then the manifest as a length-prefixed blob
+///    (empty for a single file, sealed when encrypted)
+/// 3. receiver: the byte range it wants, start and end (8 bytes each), then how
+///    much of the transfer it already has in total (8 bytes, for progress)
+/// 4. sender: the offset it will stream from (8 bytes), then the data up to the end
+/// 5. receiver: one byte confirming the saved files or reporting them corrupt; or,
+///    on the extra streams of a ranged download, that its range is written
+///
+/// The data of a multi-file transfer is its files back to back. A single file may
+/// be fetched as several ranges over concurrent connections, as many as agreed in
+/// `TransferReady`. If a recipient's connections drop the server keeps listening so
+/// it can resume. With a cipher, data is a sequence of length-prefixed sealed
+/// records, under the recipient's key and a separate session per connection.
+///
+/// The server stops once every recipient has received the file, rejected it, failed
+/// or run out of time.
+pub(crate) async fn setup_file_transfer_server(
+    transfer_id: String,
+    port: u16,
+    registry: TransferRegistry,
+    queue: TransferQueue,
+    events: broadcast::Sender<EngineEvent>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    info!("Setting FT server for ID : {} , PORT : {}", transfer_id, port);
+
+    let source = match registry.get(&transfer_id) {
+        Some(source) => source,
+        None => {
+            let error_msg = format!("File path for transfer ID {} not found in registry.", transfer_id);
+            error!("{}", error_msg);
+            let _ = events.send(EngineEvent::transfer_error(&transfer_id, error_msg.clone()));
+            return Err(Box::new(std::io::Error::new(std::io::ErrorKind::NotFound, error_msg)));
+        }
+    };
+
+    let fail = |error_msg: String, e: std::io::Error| {
+        error!("{}", error_msg);
+        let _ = events.send(EngineEvent::transfer_error(&transfer_id, error_msg));
+        registry.remove(&transfer_id);
+        Err(Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
+    };
+
+    // A single file is sized when served; a manifest fixed the sizes when it was offered
+    let files: Vec<(PathBuf, u64)> = match &source.manifest {
+        Some(entries) => source.files.iter().cloned().zip(entries.iter().map(|entry| entry.size)).collect(),
+        None => {
+            let path = source.files.first().cloned().unwrap_or_default();
+            match tokio::fs::metadata(&path).await {
+                Ok(metadata) => vec![(path, metadata.len())],
+                Err(e) => return fail(format!("Error sending file: {}", e), e),
+            }
+        }
+    };
+    let size = files.iter().map(|(_, size)| size).sum();
+    info!("Sending {} file(s), {} bytes: {:?}", files.len(), size, files.first().map(|(path, _)| path));
+
+    let listener = match bind_listener(port).and_then(|listener| {
+        listener.set_nonblocking(true)?;
+        TcpListener::from_std(listener)
+    }) {
+        Ok(listener) => listener,
+        Err(e) => return fail(format!("Failed to listen for the transfer: {}", e), e),
+    };
+    info!("FT Server PORT : {} (waiting for connection)", port);
+
+    let context = Arc::new(ServeContext {
+        transfer_id: transfer_id.clone(),
+        files,
+        size,
+        manifest: source.manifest.as_deref().map(manifest::to_bytes).unwrap_or_default(),
+        registry: registry.clone(),
+        control: source.control.clone(),
+        queue,
+        connections: Mutex::new(HashMap::new()),
+        events: events.clone(),
+    });
+
+    // Dropping the set when the server stops also stops every connection
+    let mut served = JoinSet::new();
+    let mut tick = tokio::time::interval(Duration::from_secs(1));
+    let mut last_error = None;
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((mut socket, addr)) => {
+                    if !registry.admits(&transfer_id, addr.ip()) {
+                        warn!("Rejected connection to transfer {} from {}: not an accepted recipient", transfer_id, addr);
+                        continue;
+                    }
+                    info!("File transfer connection accepted from: {}", addr);
+                    let context = context.clone();
+                    served.spawn(async move {
+                        let mut recipient = None;
+                        let result = serve_connection(&mut socket, &context, &mut recipient).await;
+                        (recipient, result)
+                    });
+                }
+                Err(e) => {
+                    error!("Failed to accept connection: {}", e);
+                    let _ = events.send(EngineEvent::transfer_error(&transfer_id, format!("Failed to accept connection: {}", e)));
+                    // Clean up registry
+                    registry.remove(&transfer_id);
+                    return Err(Box::new(e));
+                }
+            },
+            Some(Ok((recipient, result))) = served.join_next() => {
+                let Some(user_id) = recipient else {
+                    if let Err(e) = result {
+                        warn!("Refused connection to transfer {}: {}", transfer_id, e);
+                    }
+                    continue;
+                };
+
+                let (active, meter) = context.finish_connection(user_id);
+                let update = match result {
+                    // Already marked by whoever cancelled
+                    Err(e) if context.control.is_cancelled(user_id) => {
+                        debug!("Stopped sending transfer {} to {}: {}", transfer_id, user_id, e);
+                        None
+                    }
+                    Ok(Served::Transfer) => {
+                        info!("File sent successfully to {}: {} bytes", user_id, size);
+                        registry.record_sent(&transfer_id, user_id, size);
+                        registry.set_recipient_state(&transfer_id, user_id, RecipientState::Sent, None)
+                    }
+                    Ok(Served::Range) => {
+                        debug!("Range of transfer {} delivered to {}", transfer_id, user_id);
+                        None
+                    }
+                    // Reported once the last stream of the attempt is gone
+                    Err(e) if is_interruption(&e) && active > 0 => None,
+                    Err(e) if is_interruption(&e) => {
+                        let transferred = meter.transferred();
+                        warn!("Transfer {} to {} interrupted at {}/{} bytes: {}", transfer_id, user_id, transferred, size, e);
+                        let _ = events.send(EngineEvent::FileTransferInterrupted {
+                            transfer_id: transfer_id.clone(),
+                            transferred,
+                            size,
+                        });
+                        registry.record_sent(&transfer_id, user_id, transferred);
+                        registry.set_recipient_state(&transfer_id, user_id, RecipientState::Interrupted, None)
+                    }
+                    Err(e) => {
+                        let error_msg = format!("Error sending file: {}", e);
+                        error!("{} (recipient {})", error_msg, user_id);
+                        last_error = Some(error_msg.clone());
+                        registry.set_recipient_state(&transfer_id, user_id, RecipientState::Failed, Some(error_msg))
+                    }
+                };
+                if let Some(recipient) = update {
+                    let _ = events.send(EngineEvent::FileTransferRecipient { transfer_id: transfer_id.clone(), recipient });
+                }
+            },
+            _ = context.control.cancelled() => {
+                info!("Transfer {} cancelled; closing its server", transfer_id);
+                return Ok(());
+            },
+            _ = tick.tick() => {
+                for recipient in registry.expire_overdue(&transfer_id) {
+                    warn!("Recipient {} of transfer {} timed out", recipient.user_id, transfer_id);
+                    let _ = events.send(EngineEvent::FileTransferRecipient { transfer_id: transfer_id.clone(), recipient });
+                }
+            }
+        }
+
+        if let Some((recipients, kept)) = registry.end_session_if_finished(&transfer_id) {
+            return end_session(&transfer_id, size, &recipients, kept, last_error, &events);
+        }
+    }
+}
+
+// Reports how a server's session went as a whole
+fn end_session(
+    transfer_id: &str,
+    size: u64,
+    recipients: &[RecipientStatus],
+    kept: bool,
+    last_error: Option<String>,
+    events: &broadcast::Sender<EngineEvent>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let sent = recipients.iter().filter(|recipient| recipient.state == RecipientState::Sent).count();
+    info!("Transfer {} finished: sent to {} of {} recipient(s)", transfer_id, sent, recipients.len());
+
+    if sent > 0 {
+        let _ = events.send(EngineEvent::FileTransferSent {
+            transfer_id: transfer_id.to_string(),
+            size,
+        });
+        return Ok(());
+    }
+
+    let error_msg = match last_error {
+        Some(error_msg) => error_msg,
+        // Stay registered so the receiver can accept again and get a new server
+        None if kept => "Timeout waiting for the receiver to resume".to_string(),
+        None if recipients.iter().any(|recipient| recipient.state == RecipientState::Expired) => {
+            "Timeout waiting for connection".to_string()
+        }
+        None if recipients.iter().any(|recipient| recipient.state == RecipientState::Cancelled) => {
+            let _ = events.send(EngineEvent::FileTransferCancelled { transfer_id: transfer_id.to_string(), by_peer: true });
+            return Ok(());
+        }
+        // Everyone declined
+        None => return Ok(()),
+    };
+    error!("Transfer {}: {}", transfer_id, error_msg);
+    let _ = events.send(EngineEvent::transfer_error(transfer_id, error_msg.clone()));
+    Err(Box::new(std::io::Error::other(error_msg)))
+}
+
+// What every connection to one transfer server shares
+struct ServeContext {
+    transfer_id: String,
+    files: Vec<(PathBuf, u64)>,
+    size: u64,
+    manifest: Vec<u8>,
+    registry: TransferRegistry,
+    control: TransferControl,
+    queue: TransferQueue,
+    /// Open connections and the progress of the current attempt, per recipient.
+    connections: Mutex<HashMap<u64, (usize, Arc<ProgressMeter>)>>,
+    events: broadcast::Sender<EngineEvent>,
+}
+
+impl ServeContext {
+    // A recipient's first connection of an attempt starts a fresh meter
+    fn start_connection(&self, user_id: u64) -> Arc<ProgressMeter> {
+        let mut connections = self.connections.lock().unwrap();
+        let (active, meter) = connections
+            .entry(user_id)
+            .or_insert_with(|| (0, Arc::new(ProgressMeter::new(self.size))));
+        if *active == 0 {
+            *meter = Arc::new(ProgressMeter::new(self.size));
+        }
+        *active += 1;
+        meter.clone()
+    }
+
+    fn finish_connection(&self, user_id: u64) -> (usize, Arc<ProgressMeter>) {
+        let mut connections = self.connections.lock().unwrap();
+        let (active, meter) = connections
+            .entry(user_id)
+            .or_insert_with(|| (1, Arc::new(ProgressMeter::new(self.size))));
+        *active = active.saturating_sub(1);
+        (*active, meter.clone())
+    }
+}
+
+// How a connection ended well: with the whole transfer confirmed, or just its range
+enum Served {
+    Transfer,
+    Range,
+}
+
+// Streams the requested range from disk so only one chunk is held in memory.
+// `recipient` is set once the connection is known to belong to one.
+async fn serve_connection(socket: &mut TcpStream, context: &ServeContext, recipient: &mut Option<u64>) -> std::io::Result<Served> {
+    let peer = socket.peer_addr()?;
+    let mut hello = [0u8; 28];
+    tokio::time::timeout(Duration::from_secs(CONNECT_TIMEOUT_SECS), socket.read_exact(&mut hello))
+        .await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, format!("No handshake from {}", peer)))??;
+    let session = u32::from_be_bytes(hello[..4].try_into().unwrap());
+    let user_id = u64::from_be_bytes(hello[4..12].try_into().unwrap());
+    let token: TransferToken = hello[12..].try_into().unwrap();
+
+    let (cipher, compression, update) = context
+        .registry
+        .connect_recipient(&context.transfer_id, user_id, peer.ip(), &token)
+        .ok_or_else(|| {
+            let message = format!("{} claiming to be user {} is not an accepted recipient or gave a wrong token", peer, user_id);
+            std::io::Error::new(std::io::ErrorKind::PermissionDenied, message)
+        })?;
+    if let Some(status) = update {
+        let _ = context.events.send(EngineEvent::FileTransferRecipient {
+            transfer_id: context.transfer_id.clone(),
+            recipient: status,
+        });
+    }
+    let meter = context.start_connection(user_id);
+    *recipient = Some(user_id);
+    let mut cipher = cipher.map(|cipher| cipher.for_session(session));
+
+    socket.write_all(&context.size.to_be_bytes()).await?;
+    write_blob(socket, cipher.as_mut(), &context.manifest).await?;
+
+    let mut request = [0u8; 24];
+    socket.read_exact(&mut request).await?;
+    let field = |i: usize| u64::from_be_bytes(request[i * 8..(i + 1) * 8].try_into().unwrap());
+    let (start, end, have) = (field(0), field(1), field(2));
+    // A receiver holding more than the whole transfer starts over
+    let (start, end) = if start > context.size { (0, context.size) } else { (start, end) };
+    if end > context.size || start > end {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid range requested"));
+    }
+    meter.start_from(have);
+    let ticket = context.queue.enqueue(TransferDirection::Outgoing, &context.transfer_id, context.size);
+    let slot = wait_for_slot(ticket, &context.transfer_id, &context.control, user_id, &context.events).await?;
+    slot.start_from(context.size, have);
+
+    info!("Sending bytes {}..{} of {} to {}", start, end, context.size, user_id);
+    socket.write_all(&start.to_be_bytes()).await?;
+
+    let mut reader = SourceReader::open_at(context.files.clone(), start).await?;
+    let mut buffer = vec![0u8; TRANSFER_CHUNK_SIZE];
+    let mut compressor = compression.map(|_| ChunkCompressor::new(TRANSFER_CHUNK_SIZE)).transpose()?;
+    let mut position = start;
+    while position < end {
+        context.control.proceed(user_id).await?;
+        let wanted = buffer.len().min((end - position) as usize);
+        let n = reader.read(&mut buffer[..wanted]).await?;
+        let chunk = &buffer[..n];
+        match (compressor.as_mut(), cipher.as_mut()) {
+            (Some(compressor), cipher) => write_blob(socket, cipher, compressor.frame(chunk)).await?,
+            (None, Some(cipher)) => write_record(socket, cipher, chunk).await?,
+            (None, None) => socket.write_all(chunk).await?,
+        }
+        position += n as u64;
+        slot.throttle(n).await;
+
+        if let Some(progress) = meter.add(n) {
+            debug!("Upload progress: {}% ({}/{})", progress.percent, progress.transferred, context.size);
+            context.registry.record_sent(&context.transfer_id, user_id, progress.transferred);
+            let _ = context.events.send(EngineEvent::FileTransferSendProgress {
+                transfer_id: context.transfer_id.clone(),
+                recipient_id: Some(user_id),
+                progress: progress.percent,
+                sent: progress.transferred,
+                size: context.size,
+                bytes_per_second: progress.bytes_per_second,
+                eta_seconds: progress.eta_seconds,
+            });
+        }
+    }
+    socket.flush().await?;
+
+    // Everything may still be in flight; only the receiver knows it arrived
+    let mut ack = [0u8; 1];
+    let acked = context.control.guard(user_id, async {
+        tokio::time::timeout(Duration::from_secs(ACK_TIMEOUT_SECS), socket.read_exact(&mut ack))
+            .await
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "No acknowledgement from receiver"))?
+    });
+    acked.await?;
+    match ack[0] {
+        TRANSFER_ACK => Ok(Served::Transfer),
+        RANGE_ACK => Ok(Served::Range),
+        TRANSFER_CORRUPT => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Receiver found the file corrupt")),
+        _ => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Unexpected transfer acknowledgement")),
+    }
+}
+
+/// Waits for the queue to let a transfer move data, giving up if it is cancelled
+/// meanwhile. Every stream of a transfer shares the one slot.
+pub(crate) async fn wait_for_slot(
+    mut ticket: Ticket,
+    transfer_id: &str,
+    control: &TransferControl,
+    recipient: u64,
+    events: &broadcast::Sender<EngineEvent>,
+) -> std::io::Result<TransferSlot> {
+    if let Some(slot) = ticket.try_admit() {
+        return Ok(slot);
+    }
+
+    if ticket.fresh {
+        info!("Transfer {} queued behind other transfers", transfer_id);
+        let _ = events.send(EngineEvent::FileTransferQueued {
+            transfer_id: transfer_id.to_string(),
+            direction: ticket.direction(),
+        });
+    }
+    control.guard(recipient, async { Ok(ticket.admit().await) }).await
+}
+
+// Reads a list of files as one stream
+struct SourceReader {
+    files: std::vec::IntoIter<(PathBuf, u64)>,
+    file: Option<tokio::fs::File>,
+    remaining: u64,
+}
+
+impl SourceReader {
+    async fn open_at(files: Vec<(PathBuf, u64)>, mut offset: u64) -> std::io::Result<Self> {
+        let mut reader = Self { files: files.into_iter(), file: None, remaining: 0 };
+        while reader.next_file().await? {
+            if offset < reader.remaining {
+                if let Some(file) = reader.file.as_mut() {
+                    file.seek(SeekFrom::Start(offset)).await?;
+                }
+                reader.remaining -= offset;
+                break;
+            }
+            offset -= reader.remaining;
+        }
+        Ok(reader)
+    }
+
+    async fn next_file(&mut self) -> std::io::Result<bool> {
+        let Some((path, size)) = self.files.next() else {
+            self.file = None;
+            return Ok(false);
+        };
+        let file = tokio::fs::File::open(&path).await?;
+        if file.metadata().await?.len() != size {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{} changed since it was offered", path.display()),
+            ));
+        }
+        self.file = Some(file);
+        self.remaining = size;
+        Ok(true)
+    }
+
+    async fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        while self.remaining == 0 {
+            if !self.next_file().await? {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Read past the end of the transfer"));
+            }
+        }
+
+        let wanted = buffer.len().min(self.remaining as usize);
+        let n = match self.file.as_mut() {
+            Some(file) => file.read(&mut buffer[..wanted]).await?,
+            None => 0,
+        };
+        if n == 0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "File shrank while it was being sent"));
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+// A dropped connection, as opposed to a local failure that resuming cannot fix
+fn is_interruption(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::ConnectionAborted
+            | std::io::ErrorKind::BrokenPipe
+            | std::io::ErrorKind::UnexpectedEof
+            | std::io::ErrorKind::TimedOut
+    )
+}
+
+async fn write_record(socket: &mut TcpStream, cipher: &mut FileCipher, chunk: &[u8]) -> std::io::Result<()> {
+    let record = cipher
+        .seal(chunk)
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+    socket.write_all(&(record.len() as u32).to_be_bytes()).await?;
+    socket.write_all(&record).await
+}
+
+// Opens the next sealed record into `buffer`, returning the plaintext length
+async fn read_record(stream: &mut TcpStream, cipher: &mut FileCipher, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut len_buf = [0u8; 4];
+    stream.read_exact(&mut len_buf).await?;
+
+    let len = u32::from_be_bytes(len_buf) as usize;
+    if !(TAG_SIZE..=buffer.len() + TAG_SIZE).contains(&len) {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid encrypted record length"));
+    }
+
+    let mut record = vec![0u8; len];
+    stream.read_exact(&mut record).await?;
+
+    let chunk = cipher
+        .open(&record)
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "File stream failed to decrypt"))?;
+    buffer[..chunk.len()].copy_from_slice(&chunk);
+    Ok(chunk.len())
+}
+
+// Reads the next piece of the data stream into `buffer`, at most `remaining` bytes
+// of it unless encrypted records say otherwise. A compressed stream arrives as
+// length-prefixed frames, sealed when encrypted.
+async fn read_chunk(
+    stream: &mut TcpStream,
+    cipher: Option<&mut FileCipher>,
+    decompressor: Option<&mut ChunkDecompressor>,
+    buffer: &mut [u8],
+    remaining: usize,
+) -> std::io::Result<usize> {
+    let Some(decompressor) = decompressor else {
+        return match cipher {
+            Some(cipher) => read_record(stream, cipher, buffer).await,
+            None => stream.read(&mut buffer[..remaining]).await,
+        };
+    };
+
+    let frame = decompressor.frame_buffer();
+    let len = match cipher {
+        Some(cipher) => read_record(stream, cipher, frame).await?,
+        None => {
+            let mut len_buf = [0u8; 4];
+            stream.read_exact(&mut len_buf).await?;
+            let len = u32::from_be_bytes(len_buf) as usize;
+            if len == 0 || len > frame.len() {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid compressed frame length"));
+            }
+            stream.read_exact(&mut frame[..len]).await?;
+            len
+        }
+    };
+    decompressor.unframe(len, &mut buffer[..remaining])
+}
+
+// A length-prefixed blob such as the manifest; sealed as one record when encrypted
+async fn write_blob(socket: &mut TcpStream, cipher: Option<&mut FileCipher>, bytes: &[u8]) -> std::io::Result<()> {
+    if bytes.is_empty() {
+        return socket.write_all(&0u32.to_be_bytes()).await;
+    }
+    match cipher {
+        Some(cipher) => write_record(socket, cipher, bytes).await,
+        None => {
+            socket.write_all(&(bytes.len() as u32).to_be_bytes()).await?;
+            socket.write_all(bytes).await
+        }
+    }
+}
+
+async fn read_blob(stream: &mut TcpStream, cipher: Option<&mut FileCipher>) -> std::io::Result<Vec<u8>> {
+    let mut len_buf = [0u8; 4];
+    stream.read_exact(&mut len_buf).await?;
+
+    let len = u32::from_be_bytes(len_buf) as usize;
+    if len == 0 {
+        return Ok(Vec::new());
+    }
+    if len > MAX_MANIFEST_BYTES + TAG_SIZE {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Manifest too large"));
+    }
+
+    let mut blob = vec![0u8; len];
+    stream.read_exact(&mut blob).await?;
+    match cipher {
+        Some(cipher) => cipher
+            .open(&blob)
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "Manifest failed to decrypt")),
+        None => Ok(blob),
+    }
+}
+
+/// What `receive_file` needs to know about a download.
+pub(crate) struct Download {
+    pub transfer_id: String,
+    pub receiver_id: u64,
+    pub token: TransferToken,
+    pub sender_ip: String,
+    pub port: u16,
+    /// The file, or for a multi-file transfer the directory the tree is recreated under.
+    pub save_path: PathBuf,
+    /// Digest from the offer: of the file, or of the manifest.
+    pub expected_hash: Option<String>,
+    pub bundle: bool,
+    /// Continue from part files left by an earlier attempt rather than starting over.
+    pub resume: bool,
+    /// Connections a single file may be fetched over.
+    pub streams: u8,
+    pub compression: Option<Compression>,
+    /// Ranges an earlier multi-stream attempt left unfetched.
+    pub missing: Option<Vec<(u64, u64)>>,
+    pub control: TransferControl,
+}
+
+/// How a download task ended.
+#[derive(Debug, Clone)]
+pub enum DownloadOutcome {
+    Completed,
+    /// Stopped early. For a file fetched in ranges, the ranges still missing.
+    Stopped(Option<Vec<(u64, u64)>>),
+}
+
+// One file being written by `receive_file`
+struct Target {
+    path: PathBuf,
+    /// Manifest path, for per-file events; `None` for a single-file transfer.
+    name: Option<String>,
+    size: u64,
+    hash: Option<String>,
+    mode: Option<u32>,
+}
+
+enum ReceiveError {
+    /// The connection dropped; part files are kept for resuming.
+    Interrupted { transferred: u64, size: u64 },
+    Corrupt { file: Option<String>, expected: String, actual: String },
+    Failed(String),
+    /// Stopped by either side; the part file is deleted.
+    Cancelled,
+}
+
+/// Connects to a peer's transfer server and streams the transfer to disk,
+/// reporting progress and the outcome as engine events. Each file is written to
+/// `<path>.part` and renamed once it is complete and matches its hash.
+pub(crate) async fn receive_file(
+    download: Download,
+    slot: TransferSlot,
+    cipher: Option<FileCipher>,
+    events: broadcast::Sender<EngineEvent>,
+) -> DownloadOutcome {
+    let transfer_id = download.transfer_id.clone();
+    let mut missing = download.missing.clone();
+
+    let mut stream = match connect(&download.sender_ip, download.port).await {
+        Ok(stream) => {
+            info!("Connected to file server at {}:{}", download.sender_ip, download.port);
+            stream
+        },
+        Err(_) if download.control.is_cancelled(0) => return DownloadOutcome::Stopped(None),
+        Err(error_msg) => {
+            error!("{}", error_msg);
+            let _ = events.send(EngineEvent::transfer_error(&transfer_id, error_msg));
+            return DownloadOutcome::Stopped(missing);
+        }
+    };
+
+    match receive_into(&mut stream, &download, &slot, cipher, &events, &mut missing).await {
+        Ok(total_bytes) => {
+            let _ = stream.write_all(&[TRANSFER_ACK]).await;
+            info!("File download complete: {} bytes saved to {}", total_bytes, download.save_path.display());
+            let _ = events.send(EngineEvent::FileTransferComplete {
+                transfer_id,
+                file_path: download.save_path.to_string_lossy().into_owned(),
+                size: total_bytes,
+            });
+            return DownloadOutcome::Completed;
+        }
+        Err(ReceiveError::Interrupted { transferred, size }) => {
+            warn!("Transfer {} interrupted at {}/{} bytes", transfer_id, transferred, size);
+            let _ = events.send(EngineEvent::FileTransferInterrupted { transfer_id, transferred, size });
+        }
+        Err(ReceiveError::Corrupt { file, expected, actual }) => {
+            error!("Transfer {} is corrupt: expected {}, got {}", transfer_id, expected, actual);
+            let _ = stream.write_all(&[TRANSFER_CORRUPT]).await;
+            let _ = events.send(EngineEvent::FileTransferCorrupt { transfer_id, file, expected, actual });
+        }
+        Err(ReceiveError::Failed(error_msg)) => {
+            error!("{}", error_msg);
+            let _ = events.send(EngineEvent::transfer_error(&transfer_id, error_msg));
+        }
+        Err(ReceiveError::Cancelled) => {
+            info!("Download of transfer {} cancelled", transfer_id);
+            return DownloadOutcome::Stopped(None);
+        }
+    }
+    DownloadOutcome::Stopped(missing)
+}
+
+async fn connect(ip: &str, port: u16) -> Result<TcpStream, String> {
+    let addr = net::peer_addr(ip, port).map_err(|e| e.to_string())?;
+    let connect_future = TcpStream::connect(addr);
+    match tokio::time::timeout(Duration::from_secs(CONNECT_TIMEOUT_SECS), connect_future).await {
+        Ok(Ok(stream)) => Ok(stream),
+        Ok(Err(e)) => Err(format!("Failed to connect to file server: {}", e)),
+        Err(_) => Err("Connection attempt timed out".to_string()),
+    }
+}
+
+// Steps 1 and 2 of the handshake: returns the transfer size and manifest
+async fn open_session(
+    stream: &mut TcpStream,
+    cipher: Option<&mut FileCipher>,
+    session: u32,
+    receiver_id: u64,
+    token: &TransferToken,
+) -> std::io::Result<(u64, Vec<u8>)> {
+    let mut hello = [0u8; 28];
+    hello[..4].copy_from_slice(&session.to_be_bytes());
+    hello[4..12].copy_from_slice(&receiver_id.to_be_bytes());
+    hello[12..].copy_from_slice(token);
+    stream.write_all(&hello).await?;
+    let mut size_buf = [0u8; 8];
+    stream.read_exact(&mut size_buf).await?;
+    let manifest_bytes = read_blob(stream, cipher).await?;
+    Ok((u64::from_be_bytes(size_buf), manifest_bytes))
+}
+
+// Steps 3 and 4: returns the offset the sender will stream from
+async fn request_range(stream: &mut TcpStream, (start, end): (u64, u64), have: u64) -> std::io::Result<u64> {
+    let mut request = [0u8; 24];
+    request[..8].copy_from_slice(&start.to_be_bytes());
+    request[8..16].copy_from_slice(&end.to_be_bytes());
+    request[16..].copy_from_slice(&have.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut offset_buf = [0u8; 8];
+    stream.read_exact(&mut offset_buf).await?;
+    Ok(u64::from_be_bytes(offset_buf))
+}
+
+// `missing` is kept up to date with what a ranged download still lacks
+async fn receive_into(
+    stream: &mut TcpStream,
+    download: &Download,
+    slot: &TransferSlot,
+    cipher: Option<FileCipher>,
+    events: &broadcast::Sender<EngineEvent>,
+    missing: &mut Option<Vec<(u64, u64)>>,
+) -> Result<u64, ReceiveError> {
+    let failed = |context: &str, e: std::io::Error| ReceiveError::Failed(format!("{}: {}", context, e));
+
+    let session = crypto::new_session();
+    let mut session_cipher = cipher.as_ref().map(|cipher| cipher.for_session(session));
+    let (file_size, manifest_bytes) = open_session(stream, session_cipher.as_mut(), session, download.receiver_id, &download.token)
+        .await
+        .map_err(|e| failed("Failed to request file", e))?;
+
+    if download.bundle {
+        return receive_bundle(stream, download, slot, session_cipher, file_size, &manifest_bytes, events).await;
+    }
+    if !manifest_bytes.is_empty() {
+        return Err(ReceiveError::Failed("Unexpected manifest for a single file".to_string()));
+    }
+    receive_ranges(stream, download, slot, cipher, session_cipher, file_size, events, missing).await
+}
+
+// What every stream of a ranged download shares
+struct RangeContext {
+    transfer_id: String,
+    receiver_id: u64,
+    token: TransferToken,
+    compression: Option<Compression>,
+    part: PathBuf,
+    size: u64,
+    have: u64,
+    meter: ProgressMeter,
+    control: TransferControl,
+    slot: TransferSlot,
+    events: broadcast::Sender<EngineEvent>,
+}
+
+// Fetches a single file as one or more byte ranges, the first on `stream` and the
+// rest on connections of their own, each written in place in the part file
+#[allow(clippy::too_many_arguments)]
+async fn receive_ranges(
+    stream: &mut TcpStream,
+    download: &Download,
+    slot: &TransferSlot,
+    cipher: Option<FileCipher>,
+    mut session_cipher: Option<FileCipher>,
+    file_size: u64,
+    events: &broadcast::Sender<EngineEvent>,
+    missing: &mut Option<Vec<(u64, u64)>>,
+) -> Result<u64, ReceiveError> {
+    let failed = |context: &str, e: std::io::Error| ReceiveError::Failed(format!("{}: {}", context, e));
+    let part = part_path(&download.save_path);
+
+    let mut ranges = match (download.resume, missing.take()) {
+        (true, Some(ranges)) => ranges,
+        (true, None) => {
+            let have = tokio::fs::metadata(&part).await.map_or(0, |metadata| metadata.len());
+            vec![(have.min(file_size), file_size)]
+        }
+        (false, _) => {
+            let _ = tokio::fs::remove_file(&part).await;
+            vec![(0, file_size)]
+        }
+    };
+    if ranges.iter().any(|&(start, end)| start > end || end > file_size) {
+        warn!("Saved ranges for {} do not fit the file; starting over", download.transfer_id);
+        ranges = vec![(0, file_size)];
+    }
+    ranges.retain(|(start, end)| start < end);
+    let ranges = split_ranges(ranges, download.streams);
+    let have = file_size - ranges.iter().map(|(start, end)| end - start).sum::<u64>();
+
+    if let Some(parent) = part.parent() {
+        tokio::fs::create_dir_all(parent).await.map_err(|e| failed("Failed to create output file", e))?;
+    }
+    let file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(&part)
+        .await
+        .map_err(|e| failed("Failed to create output file", e))?;
+    file.set_len(file_size).await.map_err(|e| failed("Failed to create output file", e))?;
+    // From here the part file is sparse, so only `missing` says what it holds
+    *missing = Some(ranges.clone());
+
+    info!("File size to download: {} bytes, {} already saved, {} range(s)", file_size, have, ranges.len().max(1));
+    let context = Arc::new(RangeContext {
+        transfer_id: download.transfer_id.clone(),
+        receiver_id: download.receiver_id,
+        token: download.token,
+        compression: download.compression,
+        part: part.clone(),
+        size: file_size,
+        have,
+        meter: ProgressMeter::new(file_size),
+        control: download.control.clone(),
+        slot: slot.clone(),
+        events: events.clone(),
+    });
+    context.meter.start_from(have);
+    slot.start_from(file_size, have);
+
+    let mut pending = ranges.into_iter();
+    let first = pending.next().unwrap_or((file_size, file_size));
+    let offset = request_range(stream, first, have).await.map_err(|e| failed("Failed to request file", e))?;
+    if offset != first.0 {
+        return Err(ReceiveError::Failed("Sender did not resume at the requested offset".to_string()));
+    }
+
+    let others: Vec<_> = pending
+        .map(|range| {
+            let (ip, port, cipher, context) = (download.sender_ip.clone(), download.port, cipher.clone(), context.clone());
+            (range, tokio::spawn(async move { fetch_extra_range(ip, port, cipher, range, &context).await }))
+        })
+        .collect();
+
+    let mut results = vec![(first, fetch_range(stream, session_cipher.as_mut(), first, &context).await)];
+    for (range, handle) in others {
+        let result = handle.await.unwrap_or_else(|e| (range.0, Err(std::io::Error::other(e))));
+        results.push((range, result));
+    }
+
+    *missing = Some(
+        results
+            .iter()
+            .filter(|((_, end), (reached, _))| reached < end)
+            .map(|((_, end), (reached, _))| (*reached, *end))
+            .collect(),
+    );
+    if let Some(e) = results.into_iter().find_map(|(_, (_, result))| result.err()) {
+        if download.control.is_cancelled(0) {
+            drop(file);
+            let _ = tokio::fs::remove_file(&part).await;
+            *missing = None;
+            return Err(ReceiveError::Cancelled);
+        }
+        if is_interruption(&e) {
+            let transferred = file_size - missing.iter().flatten().map(|(start, end)| end - start).sum::<u64>();
+            return Err(ReceiveError::Interrupted { transferred, size: file_size });
+        }
+        return Err(failed("Failed to download file", e));
+    }
+
+    let target = Target {
+        path: download.save_path.clone(),
+        name: None,
+        size: file_size,
+        hash: download.expected_hash.clone(),
+        mode: None,
+    };
+    let result = finish_target(target, file, &download.transfer_id, 0, events).await;
+    if matches!(result, Err(ReceiveError::Corrupt { .. })) {
+        *missing = None;
+    }
+    result.map(|_| file_size)
+}
+
+// Spreads what is left over up to `streams` ranges by splitting the largest, never
+// into pieces below `MIN_RANGE_SIZE`
+fn split_ranges(mut ranges: Vec<(u64, u64)>, streams: u8) -> Vec<(u64, u64)> {
+    while ranges.len() < streams as usize {
+        let Some((index, &(start, end))) = ranges.iter().enumerate().max_by_key(|(_, (start, end))| end - start) else {
+            break;
+        };
+        let slots = (streams as usize - ranges.len()) as u64;
+        let piece = (end - start) / (slots + 1);
+        if piece < MIN_RANGE_SIZE {
+            break;
+        }
+        ranges[index] = (start, end - piece);
+        ranges.insert(index + 1, (end - piece, end));
+    }
+    ranges
+}
+
+// One extra stream of a ranged download, from connecting to confirming its range
+async fn fetch_extra_range(
+    ip: String,
+    port: u16,
+    cipher: Option<FileCipher>,
+    range: (u64, u64),
+    context: &RangeContext,
+) -> (u64, std::io::Result<()>) {
+    let mut stream = match connect(&ip, port).await {
+        Ok(stream) => stream,
+        Err(e) => return (range.0, Err(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, e))),
+    };
+
+    let session = crypto::new_session();
+    let mut cipher = cipher.map(|cipher| cipher.for_session(session));
+    let opened = async {
+        let (size, manifest_bytes) = open_session(&mut stream, cipher.as_mut(), session, context.receiver_id, &context.token).await?;
+        if size != context.size || !manifest_bytes.is_empty() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Sender changed the transfer between streams"));
+        }
+        if request_range(&mut stream, range, context.have).await? != range.0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Sender did not serve the requested range"));
+        }
+        Ok(())
+    };
+    if let Err(e) = opened.await {
+        return (range.0, Err(e));
+    }
+
+    let (reached, result) = fetch_range(&mut stream, cipher.as_mut(), range, context).await;
+    match result {
+        Ok(()) => (reached, stream.write_all(&[RANGE_ACK]).await),
+        Err(e) => (reached, Err(e)),
+    }
+}
+
+// Writes `start..end` of the stream at its place in the part file. Returns how far
+// it got, which is all on disk even when the stream failed part way.
+async fn fetch_range(
+    stream: &mut TcpStream,
+    mut cipher: Option<&mut FileCipher>,
+    (start, end): (u64, u64),
+    context: &RangeContext,
+) -> (u64, std::io::Result<()>) {
+    let mut file = match tokio::fs::OpenOptions::new().write(true).open(&context.part).await {
+        Ok(file) => file,
+        Err(e) => return (start, Err(e)),
+    };
+    let mut position = start;
+    let mut buffer = vec![0u8; TRANSFER_CHUNK_SIZE];
+
+    let copied = async {
+        let mut decompressor = context.compression.map(|_| ChunkDecompressor::new(TRANSFER_CHUNK_SIZE)).transpose()?;
+        file.seek(SeekFrom::Start(start)).await?;
+        while position < end {
+            context.control.proceed(0).await?;
+            let remaining = buffer.len().min((end - position) as usize);
+            let read = read_chunk(stream, cipher.as_deref_mut(), decompressor.as_mut(), &mut buffer, remaining);
+            let n = context.control.guard(0, read).await?;
+            if n > remaining {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Sender sent more than the range"));
+            }
+            if n == 0 {
+                // The sender closed early; what we have is kept for resuming
+                return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Sender closed the connection early"));
+            }
+
+            file.write_all(&buffer[..n]).await?;
+            position += n as u64;
+            context.slot.throttle(n).await;
+            if let Some(progress) = context.meter.add(n) {
+                debug!("Download progress: {}% ({}/{})", progress.percent, progress.transferred, context.size);
+                let _ = context.events.send(EngineEvent::FileTransferProgress {
+                    transfer_id: context.transfer_id.clone(),
+                    progress: progress.percent,
+                    transferred: progress.transferred,
+                    size: context.size,
+                    bytes_per_second: progress.bytes_per_second,
+                    eta_seconds: progress.eta_seconds,
+                });
+            }
+        }
+        Ok(())
+    }
+    .await;
+
+    let flushed = file.flush().await;
+    (position, copied.and(flushed))
+}
+
+// A multi-file transfer, streamed in one piece across its files
+async fn receive_bundle(
+    stream: &mut TcpStream,
+    download: &Download,
+    slot: &TransferSlot,
+    mut cipher: Option<FileCipher>,
+    file_size: u64,
+    manifest_bytes: &[u8],
+    events: &broadcast::Sender<EngineEvent>,
+) -> Result<u64, ReceiveError> {
+    let failed = |context: &str, e: std::io::Error| ReceiveError::Failed(format!("{}: {}", context, e));
+
+    let targets = targets(download, file_size, manifest_bytes).map_err(|e| ReceiveError::Failed(e.to_string()))?;
+    if !download.resume {
+        for target in &targets {
+            let _ = tokio::fs::remove_file(part_path(&target.path)).await;
+        }
+    }
+
+    let have = resume_offset(&targets, download.resume).await;
+    let offset = request_range(stream, (have, file_size), have)
+        .await
+        .map_err(|e| failed("Failed to request file", e))?;
+    if offset > have {
+        return Err(ReceiveError::Failed("Sender resumed past the end of the partial file".to_string()));
+    }
+    info!("File size to download: {} bytes, resuming at {}", file_size, offset);
+
+    let mut writer = TargetWriter::open_at(targets, offset, &download.transfer_id, events)
+        .await
+        .map_err(|e| failed("Failed to create output file", e))?;
+
+    let meter = ProgressMeter::new(file_size);
+    meter.start_from(offset);
+    slot.start_from(file_size, offset);
+    let mut buffer = vec![0u8; TRANSFER_CHUNK_SIZE];
+    let mut decompressor = download
+        .compression
+        .map(|_| ChunkDecompressor::new(TRANSFER_CHUNK_SIZE))
+        .transpose()
+        .map_err(|e| failed("Failed to set up decompression", e))?;
+    let mut total_bytes = offset;
+
+    info!("Starting file download...");
+    while total_bytes < file_size {
+        let remaining = buffer.len().min((file_size - total_bytes) as usize);
+        let read = async {
+            download.control.proceed(0).await?;
+            read_chunk(stream, cipher.as_mut(), decompressor.as_mut(), &mut buffer, remaining).await.and_then(|n| {
+                if n > remaining {
+                    Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Sender sent more than the file size"))
+                } else {
+                    Ok(n)
+                }
+            })
+        };
+
+        match download.control.guard(0, read).await {
+            Ok(0) => {
+                // The sender closed early; what we have is kept for resuming
+                writer.flush().await;
+                return Err(ReceiveError::Interrupted { transferred: total_bytes, size: file_size });
+            },
+            Ok(n) => {
+                writer.write(&buffer[..n]).await?;
+                total_bytes += n as u64;
+                slot.throttle(n).await;
+
+                if let Some(progress) = meter.add(n) {
+                    debug!("Download progress: {}% ({}/{})", progress.percent, total_bytes, file_size);
+                    let _ = events.send(EngineEvent::FileTransferProgress {
+                        transfer_id: download.transfer_id.clone(),
+                        progress: progress.percent,
+                        transferred: progress.transferred,
+                        size: file_size,
+                        bytes_per_second: progress.bytes_per_second,
+                        eta_seconds: progress.eta_seconds,
+                    });
+                }
+            },
+            Err(_) if download.control.is_cancelled(0) => {
+                writer.discard().await;
+                return Err(ReceiveError::Cancelled);
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                // Whatever was written to the current file cannot be trusted
+                writer.discard().await;
+                return Err(ReceiveError::Failed(format!("Failed to read from stream: {}", e)));
+            },
+            Err(_) => {
+                writer.flush().await;
+                return Err(ReceiveError::Interrupted { transferred: total_bytes, size: file_size });
+            }
+        }
+    }
+
+    // Empty files at the very end never see a write
+    writer.finish_full().await?;
+    Ok(total_bytes)
+}
+
+// The files a multi-file download writes, from the offer and the manifest the sender sent
+fn targets(download: &Download, file_size: u64, manifest_bytes: &[u8]) -> Result<Vec<Target>, MessageError> {
+    match &download.expected_hash {
+        Some(expected) if *expected != manifest::digest(manifest_bytes) => {
+            return Err(MessageError::InvalidData("Manifest does not match the offer".to_string()));
+        }
+        Some(_) => {}
+        None => warn!("Offer for transfer {} did not include a manifest digest", download.transfer_id),
+    }
+
+    let entries: Vec<ManifestEntry> = serde_json::from_slice(manifest_bytes).map_err(MessageError::SerializationError)?;
+    manifest::validate(&entries, file_size)?;
+
+    entries
+        .into_iter()
+        .map(|entry| {
+            Ok(Target {
+                path: manifest::resolve(&download.save_path, &entry.path)?,
+                size: entry.size,
+                hash: Some(entry.hash),
+                mode: entry.mode,
+                name: Some(entry.path),
+            })
+        })
+        .collect()
+}
+
+// How much of the transfer is already on disk: completed files in order, then
+// whatever part file follows them
+async fn resume_offset(targets: &[Target], resume: bool) -> u64 {
+    if !resume {
+        return 0;
+    }
+
+    let mut offset = 0;
+    for target in targets {
+        if let Ok(metadata) = tokio::fs::metadata(part_path(&target.path)).await {
+            return offset + metadata.len().min(target.size);
+        }
+        match tokio::fs::metadata(&target.path).await {
+            Ok(metadata) if metadata.len() == target.size => offset += target.size,
+            _ => break,
+        }
+    }
+    offset
+}
+
+// Writes the stream across the target files, finishing each as it fills up
+struct TargetWriter<'a> {
+    targets: std::vec::IntoIter<Target>,
+    current: Option<(Target, tokio::fs::File, u64)>,
+    index: usize,
+    last_progress: u8,
+    transfer_id: &'a str,
+    events: &'a broadcast::Sender<EngineEvent>,
+}
+
+impl<'a> TargetWriter<'a> {
+    async fn open_at(
+        targets: Vec<Target>,
+        mut offset: u64,
+        transfer_id: &'a str,
+        events: &'a broadcast::Sender<EngineEvent>,
+    ) -> std::io::Result<TargetWriter<'a>> {
+        let mut writer = Self { targets: targets.into_iter(), current: None, index: 0, last_progress: 0, transfer_id, events };
+
+        // Files wholly before the offset are already complete
+        for target in writer.targets.by_ref() {
+            if offset < target.size || (offset == 0 && target.size == 0) {
+                let file = open_part(&target, offset).await?;
+                writer.current = Some((target, file, offset));
+                break;
+            }
+            offset -= target.size;
+            writer.index += 1;
+        }
+        Ok(writer)
+    }
+
+    async fn write(&mut self, mut data: &[u8]) -> Result<(), ReceiveError> {
+        while !data.is_empty() {
+            self.finish_full().await?;
+            let Some((target, file, written)) = self.current.as_mut() else {
+                return Err(ReceiveError::Failed("Sender sent more than the manifest lists".to_string()));
+            };
+
+            let take = data.len().min((target.size - *written) as usize);
+            file.write_all(&data[..take])
+                .await
+                .map_err(|e| ReceiveError::Failed(format!("Failed to write to file: {}", e)))?;
+            *written += take as u64;
+            data = &data[take..];
+
+            if let Some(name) = target.name.clone() {
+                let progress = (*written as f64 / target.size as f64 * 100.0) as u8;
+                // 100% is reported once the file is verified
+                if progress != self.last_progress && progress < 100 {
+                    self.last_progress = progress;
+                    let _ = self.events.send(EngineEvent::FileTransferEntryProgress {
+                        transfer_id: self.transfer_id.to_string(),
+                        path: name,
+                        index: self.index,
+                        progress,
+                    });
+                }
+            }
+        }
+        self.finish_full().await
+    }
+
+    // Completes the current file once it has all its bytes, and any empty files after it
+    async fn finish_full(&mut self) -> Result<(), ReceiveError> {
+        while let Some((target, _, written)) = &self.current {
+            if *written < target.size {
+                return Ok(());
+            }
+
+            let (target, file, _) = self.current.take().unwrap();
+            finish_target(target, file, self.transfer_id, self.index, self.events).await?;
+            self.index += 1;
+            self.last_progress = 0;
+
+            if let Some(next) = self.targets.next() {
+                let file = open_part(&next, 0)
+                    .await
+                    .map_err(|e| ReceiveError::Failed(format!("Failed to create output file: {}", e)))?;
+                self.current = Some((next, file, 0));
+            }
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) {
+        if let Some((_, file, _)) = self.current.as_mut() {
+            let _ = file.flush().await;
+        }
+    }
+
+    async fn discard(&mut self) {
+        if let Some((target, file, _)) = self.current.take() {
+            drop(file);
+            let _ = tokio::fs::remove_file(part_path(&target.path)).await;
+        }
+    }
+}
+
+async fn open_part(target: &Target, offset: u64) -> std::io::Result<tokio::fs::File> {
+    if let Some(parent) = target.path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(part_path(&target.path))
+        .await?;
+    file.set_len(offset).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    Ok(file)
+}
+
+// Verifies a complete part file against its hash and moves it into place
+async fn finish_target(
+    target: Target,
+    file: tokio::fs::File,
+    transfer_id: &str,
+    index: usize,
+    events: &broadcast::Sender<EngineEvent>,
+) -> Result<(), ReceiveError> {
+    let part_path = part_path(&target.path);
+    file.sync_all()
+        .await
+        .map_err(|e| ReceiveError::Failed(format!("Failed to save file: {}", e)))?;
+    drop(file);
+
+    if let Some(expected) = target.hash {
+        // Saving it unchecked would let whoever chose the hash skip verification
+        if !expected.starts_with(HASH_PREFIX) {
+            let _ = tokio::fs::remove_file(&part_path).await;
+            return Err(ReceiveError::Failed(format!("Cannot verify {} with unsupported hash {}", target.path.display(), expected)));
+        }
+        let actual = hash_file(part_path.clone())
+            .await
+            .map_err(|e| ReceiveError::Failed(format!("Failed to verify file: {}", e)))?;
+
+        if actual != expected {
+            let _ = tokio::fs::remove_file(&part_path).await;
+            return Err(ReceiveError::Corrupt { file: target.name, expected, actual });
+        }
+    }
+
+    tokio::fs::rename(&part_path, &target.path)
+        .await
+        .map_err(|e| ReceiveError::Failed(format!("Failed to save file: {}", e)))?;
+    #[cfg(unix)]
+    if let Some(mode) = target.mode {
+        use std::os::unix::fs::PermissionsExt;
+        let permissions = std::fs::Permissions::from_mode(mode & 0o777);
+        if let Err(e) = tokio::fs::set_permissions(&target.path, permissions).await {
+            warn!("Failed to set permissions on {}: {}", target.path.display(), e);
+        }
+    }
+
+    if let Some(name) = target.name {
+        debug!("Saved {} ({} bytes)", name, target.size);
+        let _ = events.send(EngineEvent::FileTransferEntryProgress {
+            transfer_id: transfer_id.to_string(),
+            path: name,
+            index,
+            progress: 100,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALICE: &str = "192.0.2.1";
+    const BOB: &str = "192.0.2.2";
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn offered_to_bob() -> TransferRegistry {
+        let registry = TransferRegistry::default();
+        let target = OfferTarget { id: 2, ip: BOB.to_string(), port: 2425 };
+        registry.register("t".into(), PathBuf::from("file"), &[target]);
+        registry
+    }
+
+    fn accept(registry: &TransferRegistry, user_id: u64) -> TransferToken {
+        let (_, _, _, admission) = registry
+            .accept_recipient("t", user_id, (BOB.to_string(), 2425), None, 1, None, 9000)
+            .unwrap();
+        admission.token
+    }
+
+    #[test]
+    fn admits_accepted_recipient_with_its_token() {
+        let registry = offered_to_bob();
+        let token = accept(&registry, 2);
+        assert!(registry.connect_recipient("t", 2, ip(BOB), &token).is_some());
+        // Reconnecting, e.g. for another stream, keeps working until it is settled
+        assert!(registry.connect_recipient("t", 2, "::ffff:192.0.2.2".parse().unwrap(), &token).is_some());
+    }
+
+    #[test]
+    fn rejects_wrong_or_missing_token() {
+        let registry = offered_to_bob();
+        assert!(registry.connect_recipient("t", 2, ip(BOB), &TransferToken::default()).is_none());
+
+        let token = accept(&registry, 2);
+        let mut wrong = token;
+        wrong[0] ^= 1;
+        assert!(registry.connect_recipient("t", 2, ip(BOB), &wrong).is_none());
+        assert!(registry.connect_recipient("t", 2, ip(BOB), &crypto::new_transfer_token()).is_none());
+        assert!(crypto::decode_token("too short").is_none());
+    }
+
+    #[test]
+    fn rejects_token_from_elsewhere() {
+        let registry = offered_to_bob();
+        let token = accept(&registry, 2);
+        assert!(registry.connect_recipient("t", 2, ip(ALICE), &token).is_none());
+        assert!(registry.connect_recipient("t", 3, ip(BOB), &token).is_none());
+        assert!(registry.connect_recipient("u", 2, ip(BOB), &token).is_none());
+        assert!(!registry.admits("t", ip(ALICE)));
+        assert!(registry.admits("t", ip(BOB)));
+    }
+
+    #[test]
+    fn rejects_token_once_settled() {
+        for state in [RecipientState::Sent, RecipientState::Cancelled, RecipientState::Failed] {
+            let registry = offered_to_bob();
+            let token = accept(&registry, 2);
+            registry.set_recipient_state("t", 2, state, None).unwrap();
+            assert!(registry.connect_recipient("t", 2, ip(BOB), &token).is_none(), "{:?}", state);
+        }
+    }
+
+    #[test]
+    fn rejects_previous_token_after_accepting_again() {
+        let registry = offered_to_bob();
+        let first = accept(&registry, 2);
+        let second = accept(&registry, 2);
+        assert!(!crypto::tokens_match(&first, &second));
+        assert!(registry.connect_recipient("t", 2, ip(BOB), &first).is_none());
+        assert!(registry.connect_recipient("t", 2, ip(BOB), &second).is_some());
+    }
+
+    #[test]
+    fn reject_only_from_the_recipient() {
+        let registry = offered_to_bob();
+        assert!(registry.reject_recipient("t", Some(2), ip(ALICE)).is_none());
+        assert!(registry.reject_recipient("t", None, ip(ALICE)).is_none());
+        assert!(registry.reject_recipient("t", Some(3), ip(BOB)).is_none());
+        assert_eq!(registry.recipients("t").unwrap()[0].state, RecipientState::Offered);
+
+        let status = registry.reject_recipient("t", None, ip(BOB)).unwrap();
+        assert_eq!((status.user_id, status.state), (2, RecipientState::Rejected));
+    }
+
+    #[test]
+    fn ready_only_from_the_offering_address() {
+        let registry = TransferRegistry::default();
+        registry.register_incoming("t".into(), IncomingTransfer {
+            sender_ip: Some(ALICE.to_string()),
+            ..Default::default()
+        });
+        let token = Some(crypto::new_transfer_token());
+        assert!(registry.set_incoming_ready("t", ip(BOB), 9000, true, 1, token, None).is_none());
+        assert!(registry.incoming("t").unwrap().token.is_none());
+        assert_eq!(registry.set_incoming_ready("t", ip(ALICE), 9000, true, 1, token, None).as_deref(), Some(ALICE));
+        assert_eq!(registry.incoming("t").unwrap().token, token);
+        assert!(registry.set_incoming_ready("u", ip(ALICE), 9000, true, 1, token, None).is_none());
+    }
+}
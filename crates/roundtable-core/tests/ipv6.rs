@@ -0,0 +1,168 @@
+//! Two engines talking to each other over the IPv6 loopback address.
+
+use roundtable_core::net;
+use roundtable_core::{DeliveryStatus, EngineConfig, EngineEvent, OutgoingFile, OutgoingMessage, RoundtableEngine};
+use socket2::{Domain, Socket, Type};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::broadcast::Receiver;
+
+const WAIT: Duration = Duration::from_secs(20);
+
+fn ipv6_loopback_available() -> bool {
+    std::net::UdpSocket::bind("[::1]:0").is_ok()
+}
+
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("roundtable-ipv6-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+// UDP ports free on both IPv4 and IPv6, all different. The sockets are held until
+// every port is picked, so the same one is not handed out twice.
+fn free_ports<const N: usize>() -> [u16; N] {
+    let mut held = Vec::new();
+    let mut ports = [0; N];
+    for port in &mut ports {
+        *port = loop {
+            let v4 = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+            let candidate = v4.local_addr().unwrap().port();
+            let v6 = Socket::new(Domain::IPV6, Type::DGRAM, None).unwrap();
+            v6.set_only_v6(true).unwrap();
+            if v6.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, candidate)).into()).is_ok() {
+                held.push((v4, v6));
+                break candidate;
+            }
+        };
+    }
+    ports
+}
+
+async fn engine(name: &str, discovery_port: u16, message_port: u16) -> RoundtableEngine {
+    let config = EngineConfig { discovery_port, message_port, data_dir: scratch_dir(name), ..Default::default() };
+    RoundtableEngine::start(config).await.expect("engine should start")
+}
+
+// Waits for the first event `found` picks out
+async fn next_event<T>(events: &mut Receiver<EngineEvent>, mut found: impl FnMut(EngineEvent) -> Option<T>) -> T {
+    let wait = async {
+        loop {
+            if let Some(value) = events.recv().await.ok().and_then(&mut found) {
+                return value;
+            }
+        }
+    };
+    tokio::time::timeout(WAIT, wait).await.expect("timed out waiting for an event")
+}
+
+fn outgoing(content: String, target_port: u16, sender_port: u16) -> OutgoingMessage {
+    OutgoingMessage {
+        content,
+        target_ip: "::1".to_string(),
+        target_port,
+        sender_name: "alice".to_string(),
+        sender_id: 1,
+        target_id: 2,
+        sender_port,
+    }
+}
+
+#[test]
+fn peer_addresses_keep_ipv6_scope() {
+    let link_local = net::peer_addr("fe80::1%7", 2427).unwrap();
+    assert_eq!(link_local, "[fe80::1%7]:2427".parse::<SocketAddr>().unwrap());
+    assert_eq!(net::peer_ip(link_local), "fe80::1%7");
+
+    let mapped = "[::ffff:10.1.2.3]:2427".parse::<SocketAddr>().unwrap();
+    assert_eq!(net::peer_ip(mapped), "10.1.2.3");
+    assert_eq!(net::peer_addr("::1", 2427).unwrap(), "[::1]:2427".parse::<SocketAddr>().unwrap());
+    assert!(net::peer_addr("10.1.2.3%7", 2427).is_err());
+}
+
+#[tokio::test]
+async fn messages_over_ipv6_loopback() {
+    if !ipv6_loopback_available() {
+        eprintln!("No IPv6 loopback; skipping");
+        return;
+    }
+    let [discovery_port, alice_port, bob_port] = free_ports();
+    let alice = engine("messages-alice", discovery_port, alice_port).await;
+    let bob = engine("messages-bob", discovery_port, bob_port).await;
+    let mut alice_events = alice.subscribe();
+    let mut bob_events = bob.subscribe();
+
+    // One datagram, then one long enough to be chunked
+    for content in ["hello over IPv6".to_string(), "x".repeat(20_000)] {
+        let message_id = alice.send_message(outgoing(content.clone(), bob_port, alice_port)).await.unwrap();
+
+        let received = next_event(&mut bob_events, |event| match event {
+            EngineEvent::MessageReceived(message) if message.message_id == message_id => Some(message),
+            _ => None,
+        })
+        .await;
+        assert_eq!(received.content, content);
+        assert_eq!(received.ip, "::1");
+
+        next_event(&mut alice_events, |event| match event {
+            EngineEvent::MessageDeliveryStatus { message_id: id, status: DeliveryStatus::Delivered, .. } if id == message_id => Some(()),
+            _ => None,
+        })
+        .await;
+    }
+}
+
+#[tokio::test]
+async fn file_transfer_over_ipv6_loopback() {
+    if !ipv6_loopback_available() {
+        eprintln!("No IPv6 loopback; skipping");
+        return;
+    }
+    let [discovery_port, alice_port, bob_port] = free_ports();
+    let alice = engine("file-alice", discovery_port, alice_port).await;
+    let bob = engine("file-bob", discovery_port, bob_port).await;
+    let mut bob_events = bob.subscribe();
+
+    let dir = scratch_dir("file-data");
+    std::fs::create_dir_all(&dir).unwrap();
+    let data: Vec<u8> = (0..300_000u32).map(|i| (i * 7 % 251) as u8).collect();
+    let source = dir.join("data.bin");
+    std::fs::write(&source, &data).unwrap();
+
+    let file = OutgoingFile {
+        transfer_id: "ipv6-transfer".to_string(),
+        file_name: "data.bin".to_string(),
+        file_size: data.len() as u64,
+        path: source,
+    };
+    let alice_user = alice.local_user(1, "alice".to_string(), "alice".to_string(), None);
+    alice.offer_file(alice_user, 2, "::1", bob_port, file).await.unwrap();
+
+    let (transfer_id, sender_ip) = next_event(&mut bob_events, |event| match event {
+        EngineEvent::FileOfferReceived { transfer_id, sender, .. } => Some((transfer_id, sender.ip)),
+        _ => None,
+    })
+    .await;
+    assert_eq!(sender_ip, "::1");
+
+    let bob_user = bob.local_user(2, "bob".to_string(), "bob".to_string(), None);
+    // No target, so the answer goes to where the offer came from
+    bob.respond_to_file_offer(transfer_id, true, bob_user, None).await.unwrap();
+
+    let (transfer_id, port, sender_ip) = next_event(&mut bob_events, |event| match event {
+        EngineEvent::FileTransferReady { transfer_id, port, sender_ip } => Some((transfer_id, port, sender_ip)),
+        _ => None,
+    })
+    .await;
+    bob.download_file(transfer_id, sender_ip, port, dir.join("received.bin")).await.unwrap();
+
+    let saved = next_event(&mut bob_events, |event| match event {
+        EngineEvent::FileTransferComplete { file_path, .. } => Some(file_path),
+        EngineEvent::FileTransferError { error, .. } => panic!("transfer failed: {}", error),
+        _ => None,
+    })
+    .await;
+    assert_eq!(std::fs::read(saved).unwrap(), data);
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -320,6 +320,7 @@ export function useTauriIntegration() {
     }
 
     const newMessage = {
+      messageId: messageData.message_id,
       sender: sender.id,
       text: messageData.content,
       time: new Date().toLocaleTimeString([], { hour: '2-digit', minute: '2-digit' }),